@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use std::sync::Mutex;
+use noodles::core::{Position, Region};
+use noodles::fasta;
+
+/// Fetches subsequences from an indexed FASTA, keeping the file handle and index open between calls.
+pub struct SequenceFetcher {
+    reader: Mutex<fasta::IndexedReader<fasta::io::BufReader<File>>>,
+}
+
+impl SequenceFetcher {
+    /// Open `fasta_file`, using `{fasta_file}.fai` when present and otherwise indexing
+    /// uncompressed FASTA files on the fly.
+    pub fn new(fasta_file: &str) -> io::Result<Self> {
+        let fai_file = format!("{}.fai", fasta_file);
+        let reader = if Path::new(&fai_file).exists() {
+            fasta::indexed_reader::Builder::default().build_from_path(fasta_file)?
+        } else {
+            let index = fasta::index(fasta_file)?;
+            fasta::indexed_reader::Builder::default().set_index(index).build_from_path(fasta_file)?
+        };
+        Ok(Self { reader: Mutex::new(reader) })
+    }
+
+    /// Fetch `[start, end)` of `seq_name`, reverse-complemented if `revcomp`.
+    pub fn fetch(&self, seq_name: &str, start: i32, end: i32, revcomp: bool) -> io::Result<Vec<u8>> {
+        if start >= end {
+            return Ok(Vec::new());
+        }
+
+        let invalid_range = || io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid range {}:{}-{}", seq_name, start, end));
+        let first = Position::try_from(start as usize + 1).map_err(|_| invalid_range())?;
+        let last = Position::try_from(end as usize).map_err(|_| invalid_range())?;
+        let record = self.reader.lock().unwrap().query(&Region::new(seq_name, first..=last))?;
+
+        let sequence = record.sequence().as_ref();
+        Ok(if revcomp { reverse_complement(sequence) } else { sequence.to_vec() })
+    }
+}
+
+/// Read the sequence lengths of a samtools FASTA index (`.fai`), by sequence name.
+pub fn read_fai_lengths(fai_file: &str) -> io::Result<HashMap<String, usize>> {
+    let mut lengths = HashMap::new();
+    for (line_number, line) in BufReader::new(File::open(fai_file)?).lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let mut fields = line.split('\t');
+        let (Some(name), Some(Ok(length))) = (fields.next(), fields.next().map(str::parse::<usize>)) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid line {} of {}: {}", line_number + 1, fai_file, line)));
+        };
+        lengths.insert(name.to_string(), length);
+    }
+    Ok(lengths)
+}
+
+pub fn reverse_complement(seq: &[u8]) -> Vec<u8> {
+    seq.iter().rev().map(|&base| match base {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        b'a' => b't',
+        b'c' => b'g',
+        b'g' => b'c',
+        b't' => b'a',
+        other => other,
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestDir;
+
+    #[test]
+    fn test_sequence_fetcher() {
+        let dir = TestDir::new("fetch");
+        let fetcher = SequenceFetcher::new(&dir.write("fetch.fa", ">s1\nACGTACGTAA\nCCGGTT\n>s2\nNNNN\n")).unwrap();
+        assert_eq!(fetcher.fetch("s1", 0, 4, false).unwrap(), b"ACGT");
+        // Across a line break
+        assert_eq!(fetcher.fetch("s1", 8, 12, false).unwrap(), b"AACC");
+        assert_eq!(fetcher.fetch("s1", 8, 12, true).unwrap(), b"GGTT");
+        assert_eq!(fetcher.fetch("s1", 3, 3, false).unwrap(), b"");
+        assert_eq!(fetcher.fetch("s2", 0, 4, false).unwrap(), b"NNNN");
+        assert!(fetcher.fetch("missing", 0, 4, false).is_err());
+    }
+
+    #[test]
+    fn test_reverse_complement() {
+        assert_eq!(reverse_complement(b"AACGTn"), b"nACGTT");
+    }
+}
@@ -1,13 +1,19 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use coitrees::{BasicCOITree, Interval, IntervalTree};
 use crate::paf::{PafRecord, ParseErr, Strand};
 use crate::seqidx::SequenceIndex;
 use serde::{Serialize, Deserialize};
-use std::io::{Read, SeekFrom, Seek};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, SeekFrom, Seek, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::fs::File;
+use std::fmt;
+use std::time::Instant;
 use rayon::prelude::*;
 use noodles::bgzf;
 use regex::Regex;
+use clap::ValueEnum;
+use log::{debug, info};
 
 /// Parse a CIGAR string into a vector of CigarOp
 // Note that the query_delta is negative for reverse strand alignments
@@ -19,25 +25,38 @@ pub struct CigarOp {
 
 impl CigarOp {
     pub fn new(len: i32, op: char) -> Self {
+        Self::try_new(len, op).unwrap_or_else(|_| panic!("Invalid CIGAR operation: {}", op))
+    }
+
+    /// Like `new`, failing on operations other than `=`, `X`, `I`, `D`, `M`, `N` and `P`, and on
+    /// lengths that don't fit in 29 bits.
+    pub fn try_new(len: i32, op: char) -> Result<Self, ParseErr> {
+        if !(0..1 << 29).contains(&len) {
+            return Err(ParseErr::InvalidCigarFormat);
+        }
         let val = match op {
             '=' => 0,
             'X' => 1,
             'I' => 2,
             'D' => 3,
             'M' => 4,
-            _ => panic!("Invalid CIGAR operation: {}", op),
+            'N' => 5,
+            'P' => 6,
+            _ => return Err(ParseErr::UnsupportedCigarOperation),
         };
-        Self { val: (val << 29) | (len as u32) }
+        Ok(Self { val: (val << 29) | (len as u32) })
     }
 
     pub fn op(&self) -> char {
-        // two most significant bits in the val tell us the op
+        // three most significant bits in the val tell us the op
         match self.val >> 29 {
             0 => '=',
             1 => 'X',
             2 => 'I',
             3 => 'D',
             4 => 'M',
+            5 => 'N',
+            6 => 'P',
             _ => panic!("Invalid CIGAR operation: {}", self.val >> 29),
         }
     }
@@ -52,8 +71,9 @@ impl CigarOp {
 
     pub fn target_delta(&self) -> i32 {
         match self.op() {
-            '=' | 'X' | 'D' | 'M' => self.len(),
-            'I' => 0,
+            // Skipped regions consume the target like deletions, padding consumes neither sequence
+            '=' | 'X' | 'D' | 'N' | 'M' => self.len(),
+            'I' | 'P' => 0,
             _ => panic!("Invalid CIGAR operation: {}", self.op()),
         }
     }
@@ -61,49 +81,251 @@ impl CigarOp {
     pub fn query_delta(&self, strand: Strand) -> i32 {
         match self.op() {
             '=' | 'X' | 'I' | 'M' => if strand == Strand::Forward { self.len() } else { -self.len() },
-            'D' => 0,
+            'D' | 'N' | 'P' => 0,
             _ => panic!("Invalid CIGAR operation: {}", self.op()),
         }
     }
 }
 
+/// Operation totals of a CIGAR, shared by the PAF columns and identity filters.
+/// `M` operations are counted as matches, which overestimates identity.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CigarStats {
+    pub matches: i32,
+    pub mismatches: i32,
+    pub insertions: i32,
+    pub inserted_bp: i32,
+    pub deletions: i32,
+    pub deleted_bp: i32,
+}
+
+impl CigarStats {
+    pub fn from_cigar(cigar: &[CigarOp]) -> Self {
+        cigar.iter().fold(Self::default(), |mut stats, op| {
+            let len = op.len();
+            match op.op() {
+                '=' | 'M' => stats.matches += len,
+                'X' => stats.mismatches += len,
+                'I' => {
+                    stats.insertions += 1;
+                    stats.inserted_bp += len;
+                },
+                'D' => {
+                    stats.deletions += 1;
+                    stats.deleted_bp += len;
+                },
+                // Skipped regions are not edits, and padding is not part of the alignment
+                'N' | 'P' => {},
+                op => panic!("Invalid CIGAR operation: {}", op),
+            }
+            stats
+        })
+    }
+
+    /// Alignment block length, the PAF column 11.
+    pub fn block_len(&self) -> i32 {
+        self.matches + self.mismatches + self.inserted_bp + self.deleted_bp
+    }
+
+    /// Number of mismatched, inserted and deleted bases, the SAM `NM` tag.
+    pub fn edit_distance(&self) -> i32 {
+        self.mismatches + self.inserted_bp + self.deleted_bp
+    }
+
+    /// Identity counting each indel as a single difference.
+    pub fn gap_compressed_identity(&self) -> f64 {
+        let denominator = self.matches + self.mismatches + self.insertions + self.deletions;
+        if denominator == 0 { 0.0 } else { self.matches as f64 / denominator as f64 }
+    }
+
+    /// Identity over the whole alignment block.
+    pub fn block_identity(&self) -> f64 {
+        let block_len = self.block_len();
+        if block_len == 0 { 0.0 } else { self.matches as f64 / block_len as f64 }
+    }
+}
+
+/// Rewrite `cigar` to read from the other sequence's perspective: insertions become deletions and
+/// vice versa, and a reverse-strand alignment is walked from the other end. Applying it twice
+/// gives back `cigar`.
+pub fn invert_cigar(cigar: &[CigarOp], strand: Strand) -> Vec<CigarOp> {
+    let invert = |op: &CigarOp| match op.op() {
+        'I' => CigarOp::new(op.len(), 'D'),
+        'D' => CigarOp::new(op.len(), 'I'),
+        _ => op.clone(),
+    };
+    match strand {
+        Strand::Forward => cigar.iter().map(invert).collect(),
+        Strand::Reverse => cigar.iter().rev().map(invert).collect(),
+    }
+}
+
+/// An alignment record, as the position of its PAF file in `Impg::paf_files` and the byte offset
+/// of its line in that file.
+pub type RecordId = (u32, u64);
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct QueryMetadata {
     query_id: u32,
+    paf_file_index: u32,
     target_start: i32,
     target_end: i32,
     query_start: i32,
     query_end: i32,
     strand: Strand,
+    mapq: u8,
+    record_offset: u64,
     cigar_offset: u64,
     cigar_bytes: usize,
 }
 
 impl QueryMetadata {
-    fn get_cigar_ops(&self, paf_file: &String, paf_gzi_index: Option<&bgzf::gzi::Index>) -> Vec<CigarOp> {
+    pub fn query_id(&self) -> u32 {
+        self.query_id
+    }
+
+    pub fn query_start(&self) -> i32 {
+        self.query_start
+    }
+
+    pub fn query_end(&self) -> i32 {
+        self.query_end
+    }
+
+    pub fn strand(&self) -> Strand {
+        self.strand
+    }
+
+    pub fn mapq(&self) -> u8 {
+        self.mapq
+    }
+
+    /// Position of the record's PAF file in `Impg::paf_files`.
+    pub fn paf_file_index(&self) -> u32 {
+        self.paf_file_index
+    }
+
+    /// The alignment record the interval was built from.
+    pub fn record_id(&self) -> RecordId {
+        (self.paf_file_index, self.record_offset)
+    }
+
+    /// Byte offset of the record's CIGAR string in the PAF file.
+    pub fn cigar_offset(&self) -> u64 {
+        self.cigar_offset
+    }
+
+    pub fn cigar_bytes(&self) -> usize {
+        self.cigar_bytes
+    }
+
+    fn get_cigar_ops(&self, paf_file: &str, paf_gzi_index: Option<&bgzf::gzi::Index>) -> io::Result<Vec<CigarOp>> {
+        let context = |error: &dyn fmt::Display| format!("Failed to read the CIGAR at byte {} of {}: {}", self.cigar_offset, paf_file, error);
         // Allocate space for cigar
         let mut cigar_buffer = vec![0; self.cigar_bytes];
+        open_paf_at(paf_file, paf_gzi_index, self.cigar_offset)
+            .and_then(|mut reader| reader.read_exact(&mut cigar_buffer))
+            .map_err(|e| io::Error::new(e.kind(), context(&e)))?;
 
-        // Get reader and seek start of cigar str
-        if [".gz", ".bgz"].iter().any(|e| paf_file.ends_with(e)) {
-            let mut reader = bgzf::Reader::new(File::open(paf_file).unwrap());
-            reader.seek_by_uncompressed_position(&paf_gzi_index.unwrap(), self.cigar_offset).unwrap();
-            reader.read_exact(&mut cigar_buffer).unwrap();
-        } else {
-            let mut reader = File::open(paf_file).unwrap();
-            reader.seek(SeekFrom::Start(self.cigar_offset)).unwrap();
-            reader.read_exact(&mut cigar_buffer).unwrap();
-        };
+        let cigar_str = std::str::from_utf8(&cigar_buffer).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, context(&e)))?;
+        cigar_from_str(cigar_str).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, context(&e)))
+    }
 
-        let cigar_str: &str = std::str::from_utf8(&cigar_buffer).unwrap();
-        parse_cigar_to_delta(cigar_str).ok().unwrap_or_else(Vec::new)
+    /// Read the optional fields of the record's PAF line, other than its `cg:Z:` CIGAR.
+    fn get_optional_fields(&self, paf_file: &str, paf_gzi_index: Option<&bgzf::gzi::Index>) -> io::Result<Vec<String>> {
+        // The CIGAR offset lies within the line, or just past it for records without one
+        let line = read_line_before(paf_file, paf_gzi_index, self.cigar_offset)?;
+        Ok(line.trim_end().split('\t').skip(12).filter(|field| !field.starts_with("cg:Z:")).map(str::to_string).collect())
     }
 }
 
+/// Open `paf_file`, plain or compressed, at uncompressed byte `offset`.
+fn open_paf_at(paf_file: &str, paf_gzi_index: Option<&bgzf::gzi::Index>, offset: u64) -> io::Result<Box<dyn Read>> {
+    if [".gz", ".bgz"].iter().any(|e| paf_file.ends_with(e)) {
+        let mut reader = bgzf::Reader::new(File::open(paf_file)?);
+        let paf_gzi_index = paf_gzi_index.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No .gzi index for {}", paf_file)))?;
+        reader.seek_by_uncompressed_position(paf_gzi_index, offset)?;
+        Ok(Box::new(reader))
+    } else if paf_file.ends_with(".zst") {
+        // zstd frames can't be seeked into, and decompressing up to every offset is quadratic
+        Err(io::Error::new(io::ErrorKind::Unsupported, format!("Can't read zstd-compressed {} at an offset, recompress it with bgzip", paf_file)))
+    } else {
+        let mut reader = File::open(paf_file)?;
+        reader.seek(SeekFrom::Start(offset))?;
+        Ok(Box::new(reader))
+    }
+}
+
+/// Read the line of `paf_file` holding the byte before `offset`, without its newline.
+fn read_line_before(paf_file: &str, paf_gzi_index: Option<&bgzf::gzi::Index>, offset: u64) -> io::Result<String> {
+    // Look back over growing windows until the previous newline or the start of the file
+    let mut window = 4096;
+    let line_start = loop {
+        let start = offset.saturating_sub(window);
+        let mut before = Vec::new();
+        open_paf_at(paf_file, paf_gzi_index, start)?.take(offset - start).read_to_end(&mut before)?;
+        // A newline right before `offset` ends the line we want
+        let search = if before.last() == Some(&b'\n') { &before[..before.len() - 1] } else { &before[..] };
+        match search.iter().rposition(|&byte| byte == b'\n') {
+            Some(newline) => break start + newline as u64 + 1,
+            None if start == 0 => break 0,
+            None => window *= 2,
+        }
+    };
+    let mut line = String::new();
+    io::BufReader::new(open_paf_at(paf_file, paf_gzi_index, line_start)?).read_line(&mut line)?;
+    Ok(line.trim_end_matches(['\n', '\r']).to_string())
+}
+
 pub type AdjustedInterval = (Interval<u32>, Vec<CigarOp>, Interval<u32>);
+/// Where a CIGAR stops matching its interval: `(query_pos, target_pos, expected_len, actual_len)`.
+pub type Divergence = (i32, i32, i32, i32);
 type TreeMap = HashMap<u32, BasicCOITree<QueryMetadata, u32>>;
-pub type SerializableImpg = (HashMap<u32, Vec<SerializableInterval>>, SequenceIndex);
+
+/// Version of the on-disk index layout, bumped whenever `SerializableImpg` changes.
+pub const INDEX_FORMAT_VERSION: u32 = 8;
+
+#[derive(Serialize, Deserialize)]
+pub struct SerializableImpg {
+    // Must stay the first field, it is checked before deserializing the rest
+    pub format_version: u32,
+    /// The PAF files the index was built from, in `paf_file_index` order
+    pub paf_files: Vec<String>,
+    pub paf_fingerprints: Vec<PafFingerprint>,
+    pub min_mapq: Option<u8>,
+    pub trees: SerializableTrees,
+    pub seq_index: SequenceIndex,
+}
+
+/// The tree intervals of a serialized index, with or without the location of their CIGARs.
+#[derive(Serialize, Deserialize)]
+pub enum SerializableTrees {
+    WithCigars(BTreeMap<u32, Vec<SerializableInterval>>),
+    /// Intervals of an index built with `--no-cigar`
+    CoordinatesOnly(BTreeMap<u32, Vec<CoordinateInterval>>),
+}
+
+impl SerializableImpg {
+    pub fn serialize_into<W: Write>(&self, writer: W) -> io::Result<()> {
+        bincode::serialize_into(writer, self).map_err(|e| io::Error::other(format!("Failed to serialize index: {:?}", e)))
+    }
+
+    /// Deserialize an index, rejecting other format versions before touching the rest of the data.
+    pub fn deserialize_from<R: BufRead>(mut reader: R) -> io::Result<Self> {
+        let header = reader.fill_buf()?;
+        if header.len() < 4 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Index file is truncated, regenerate it with -I"));
+        }
+        let format_version = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        if format_version != INDEX_FORMAT_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "Index format version {} is not supported by this impg (expected version {}), regenerate the index with -I",
+                format_version, INDEX_FORMAT_VERSION
+            )));
+        }
+        bincode::deserialize_from(reader).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Failed to deserialize index: {:?}", e)))
+    }
+}
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct SerializableInterval {
@@ -112,128 +334,523 @@ pub struct SerializableInterval {
     metadata: QueryMetadata,
 }
 
+/// A tree interval without the location of its CIGAR in the PAF file.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CoordinateInterval {
+    query_id: u32,
+    paf_file_index: u32,
+    target_start: i32,
+    target_end: i32,
+    query_start: i32,
+    query_end: i32,
+    strand: Strand,
+    mapq: u8,
+    record_offset: u64,
+}
+
+impl From<&QueryMetadata> for CoordinateInterval {
+    fn from(metadata: &QueryMetadata) -> Self {
+        let QueryMetadata { query_id, paf_file_index, target_start, target_end, query_start, query_end, strand, mapq, record_offset, .. } = *metadata;
+        Self { query_id, paf_file_index, target_start, target_end, query_start, query_end, strand, mapq, record_offset }
+    }
+}
+
+impl From<&CoordinateInterval> for Interval<QueryMetadata> {
+    fn from(interval: &CoordinateInterval) -> Self {
+        let CoordinateInterval { query_id, paf_file_index, target_start, target_end, query_start, query_end, strand, mapq, record_offset } = *interval;
+        Interval {
+            first: target_start,
+            last: target_end,
+            metadata: QueryMetadata { query_id, paf_file_index, target_start, target_end, query_start, query_end, strand, mapq, record_offset, cigar_offset: 0, cigar_bytes: 0 },
+        }
+    }
+}
+
+/// The first disagreement found between an index and the PAF records it should hold.
+#[derive(Debug, PartialEq, Eq)]
+pub enum IndexDivergence {
+    /// A sequence of the PAF records that the index does not know
+    MissingSequence(String),
+    /// A target with a different number of intervals than the PAF records have alignments onto it
+    IntervalCount { seq_name: String, indexed: usize, expected: usize },
+}
+
+impl fmt::Display for IndexDivergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IndexDivergence::MissingSequence(seq_name) => write!(f, "sequence '{}' is not in the index", seq_name),
+            IndexDivergence::IntervalCount { seq_name, indexed, expected } => write!(f, "'{}' has {} intervals in the index but {} alignments in the PAF", seq_name, indexed, expected),
+        }
+    }
+}
+
+/// Self-alignments (records whose query and target names are equal) to skip when building an index.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SelfAlignmentFilter {
+    /// Only forward records on the identity diagonal, with the same query and target coordinates
+    Diagonal,
+    /// Every record aligning a sequence to itself
+    All,
+}
+
+impl SelfAlignmentFilter {
+    pub fn matches(&self, record: &PafRecord) -> bool {
+        record.query_name == record.target_name && match self {
+            SelfAlignmentFilter::Diagonal => record.strand == Strand::Forward
+                && record.query_start == record.target_start
+                && record.query_end == record.target_end,
+            SelfAlignmentFilter::All => true,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Impg {
     pub trees: TreeMap,
     pub seq_index: SequenceIndex,
-    pub paf_file: String,
-    pub paf_gzi_index: Option<bgzf::gzi::Index>,
+    pub paf_files: Vec<String>,
+    pub paf_gzi_indices: Vec<Option<bgzf::gzi::Index>>,
+    /// The indexed extent of each PAF file, if known, used to append records added later
+    pub paf_fingerprints: Vec<PafFingerprint>,
+    /// False for indexes built with `--no-cigar`, whose results are projected linearly through
+    /// the alignments and come without CIGARs
+    pub has_cigars: bool,
+    /// Mapping quality below which records were left out of the index, if any
+    pub min_mapq: Option<u8>,
 }
 
-impl Impg {
-    pub fn from_paf_records(records: &[PafRecord], paf_file: &str) -> Result<Self, ParseErr> {
+/// Add the sequences of `records` to `seq_index`, failing if a sequence is reported with a
+/// length other than the one already known or is too long for the `i32` tree coordinates.
+fn register_sequences<'r>(seq_index: &mut SequenceIndex, records: impl Iterator<Item = &'r PafRecord>) -> Result<(), ParseErr> {
+    for record in records {
+        let coordinates = [record.query_length, record.query_start, record.query_end, record.target_length, record.target_start, record.target_end];
+        if coordinates.iter().any(|&coordinate| coordinate > i32::MAX as usize) {
+            return Err(ParseErr::InvalidFormat(format!(
+                "Alignment of '{}' onto '{}' has coordinates beyond the supported maximum of {}",
+                record.query_name, record.target_name, i32::MAX
+            )));
+        }
+        for (name, length) in [(&record.query_name, record.query_length), (&record.target_name, record.target_length)] {
+            let id = seq_index.get_or_insert_id(name, Some(length));
+            let known_length = seq_index.get_len_from_id(id).unwrap();
+            if known_length != length {
+                return Err(ParseErr::InvalidFormat(format!("Sequence '{}' has conflicting lengths {} and {}", name, known_length, length)));
+            }
+        }
+    }
+    Ok(())
+}
 
-        let paf_gzi_index: Option<bgzf::gzi::Index> = if [".gz", ".bgz"].iter().any(|e| paf_file.ends_with(e)) {
-            let paf_gzi_file = paf_file.to_owned() + ".gzi";
-            Some(bgzf::gzi::read(paf_gzi_file.clone()).expect(format!("Could not open {}", paf_gzi_file).as_str()))
-        } else {
-            None
-        };
+/// Log how many of `records` the `drop_self_alignments` filter leaves out of the index.
+fn log_dropped_self_alignments(records: &[(u32, &PafRecord)], drop_self_alignments: Option<SelfAlignmentFilter>) {
+    if let Some(filter) = drop_self_alignments {
+        let dropped = records.par_iter().filter(|(_, record)| filter.matches(record)).count();
+        info!("Dropped {} self-alignments", dropped);
+    }
+}
 
-        let mut seq_index = SequenceIndex::new();
-        for record in records {
-            seq_index.get_or_insert_id(&record.query_name, Some(record.target_length));
-            seq_index.get_or_insert_id(&record.target_name, Some(record.target_length));
-        }
-        
-        let intervals: HashMap<u32, Vec<Interval<QueryMetadata>>> = records.par_iter()
-            .filter_map(|record| {
-                let query_id = seq_index.get_id(&record.query_name).expect("Query name not found in index");
-                let target_id = seq_index.get_id(&record.target_name).expect("Target name not found in index");
-
-                let query_metadata = QueryMetadata {
-                    query_id,
-                    target_start: record.target_start as i32,
-                    target_end: record.target_end as i32,
-                    query_start: record.query_start as i32,
-                    query_end: record.query_end as i32,
-                    strand: record.strand,
-                    cigar_offset: record.cigar_offset,
-                    cigar_bytes: record.cigar_bytes
-                };
+/// Turn `(paf_file_index, record)` pairs into tree intervals grouped by target id, leaving out
+/// the records matched by `drop_self_alignments`. Every sequence must be in `seq_index`.
+/// Each rayon job groups its share of the records into per-sequence lists that are then merged,
+/// in an order that depends on the scheduling, so lists must be sorted before building trees.
+fn group_intervals(records: &[(u32, &PafRecord)], seq_index: &SequenceIndex, drop_self_alignments: Option<SelfAlignmentFilter>) -> HashMap<u32, Vec<Interval<QueryMetadata>>> {
+    records.par_iter()
+        .filter(|(_, record)| !drop_self_alignments.is_some_and(|filter| filter.matches(record)))
+        .filter_map(|&(paf_file_index, record)| {
+            let query_id = seq_index.get_id(&record.query_name).expect("Query name not found in index");
+            let target_id = seq_index.get_id(&record.target_name).expect("Target name not found in index");
+
+            let query_metadata = QueryMetadata {
+                query_id,
+                paf_file_index,
+                target_start: record.target_start as i32,
+                target_end: record.target_end as i32,
+                query_start: record.query_start as i32,
+                query_end: record.query_end as i32,
+                strand: record.strand,
+                mapq: record.mapq,
+                record_offset: record.record_offset,
+                cigar_offset: record.cigar_offset,
+                cigar_bytes: record.cigar_bytes
+            };
+
+            Some((target_id, Interval {
+                first: record.target_start as i32,
+                last: record.target_end as i32,
+                metadata: query_metadata,
+            }))
+        })  // Use fold and reduce to achieve grouping
+        .fold(HashMap::new, |mut acc: HashMap<u32, Vec<Interval<QueryMetadata>>>, (target_id, interval)| {
+            acc.entry(target_id).or_default().push(interval);
+            acc
+        })
+        .reduce(HashMap::new, |mut acc, part| {
+            for (key, value) in part {
+                acc.entry(key).or_default().extend(value);
+            }
+            acc
+        })
+}
 
-                Some((target_id, Interval {
-                    first: record.target_start as i32,
-                    last: record.target_end as i32,
-                    metadata: query_metadata,
-                }))
-            })  // Use fold and reduce to achieve grouping
-            .fold(HashMap::new, |mut acc: HashMap<u32, Vec<Interval<QueryMetadata>>>, (target_id, interval)| {
-                acc.entry(target_id).or_default().push(interval);
-                acc
-            })
-            .reduce(HashMap::new, |mut acc, part| {
-                for (key, value) in part {
-                    acc.entry(key).or_default().extend(value);
+/// Intervals grouped by target sequence that are moved to one temporary file per sequence
+/// whenever those held in memory take more than `mem_limit` bytes.
+struct SpilledIntervals {
+    dir: PathBuf,
+    mem_limit: usize,
+    in_memory: HashMap<u32, Vec<Interval<QueryMetadata>>>,
+    in_memory_count: usize,
+    spilled: HashSet<u32>,
+}
+
+impl SpilledIntervals {
+    fn new(mem_limit: usize) -> io::Result<Self> {
+        static BUILDS: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!("impg_build_{}_{}", std::process::id(), BUILDS.fetch_add(1, Ordering::Relaxed)));
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir, mem_limit, in_memory: HashMap::new(), in_memory_count: 0, spilled: HashSet::new() })
+    }
+
+    fn extend(&mut self, intervals: HashMap<u32, Vec<Interval<QueryMetadata>>>) -> io::Result<()> {
+        for (target_id, interval_nodes) in intervals {
+            self.in_memory_count += interval_nodes.len();
+            self.in_memory.entry(target_id).or_default().extend(interval_nodes);
+        }
+        if self.in_memory_count * std::mem::size_of::<Interval<QueryMetadata>>() > self.mem_limit {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        debug!("Spilling {} intervals of {} sequences to {}", self.in_memory_count, self.in_memory.len(), self.dir.display());
+        for (target_id, interval_nodes) in self.in_memory.drain() {
+            let chunk: Vec<SerializableInterval> = interval_nodes.into_iter()
+                .map(|interval| SerializableInterval { first: interval.first, last: interval.last, metadata: interval.metadata })
+                .collect();
+            let file = File::options().create(true).append(true).open(self.dir.join(target_id.to_string()))?;
+            bincode::serialize_into(BufWriter::new(file), &chunk).map_err(io::Error::other)?;
+            self.spilled.insert(target_id);
+        }
+        self.in_memory_count = 0;
+        Ok(())
+    }
+
+    /// Build the tree of each sequence, reading back one sequence's spilled intervals at a time.
+    fn into_trees(mut self) -> io::Result<TreeMap> {
+        let mut target_ids: HashSet<u32> = self.in_memory.keys().copied().collect();
+        target_ids.extend(&self.spilled);
+        let mut trees = TreeMap::default();
+        for target_id in target_ids {
+            let mut interval_nodes = self.in_memory.remove(&target_id).unwrap_or_default();
+            if self.spilled.contains(&target_id) {
+                let mut reader = BufReader::new(File::open(self.dir.join(target_id.to_string()))?);
+                while !reader.fill_buf()?.is_empty() {
+                    let chunk: Vec<SerializableInterval> = bincode::deserialize_from(&mut reader)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    interval_nodes.extend(chunk.into_iter().map(|interval| Interval { first: interval.first, last: interval.last, metadata: interval.metadata }));
                 }
-                acc
-            });
+            }
+            trees.insert(target_id, build_tree(interval_nodes));
+        }
+        Ok(trees)
+    }
+}
 
-        let trees: TreeMap = intervals.into_iter().map(|(target_id, interval_nodes)| {
-            (target_id, BasicCOITree::new(interval_nodes.as_slice()))
-        }).collect();
+impl Drop for SpilledIntervals {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn build_tree(mut interval_nodes: Vec<Interval<QueryMetadata>>) -> BasicCOITree<QueryMetadata, u32> {
+    // Fix the order of intervals sharing a start so the tree layout is reproducible. No two
+    // records share a file and offset, so the order doesn't depend on how they were grouped
+    interval_nodes.par_sort_unstable_by_key(|interval| (interval.first, interval.last, interval.metadata.query_id, interval.metadata.query_start, interval.metadata.query_end, interval.metadata.paf_file_index, interval.metadata.record_offset));
+    BasicCOITree::new(interval_nodes.as_slice())
+}
 
-        Ok(Self { trees, seq_index, paf_file: paf_file.to_string(), paf_gzi_index })
+/// Identifies the indexed part of a PAF file, to tell whether later changes only appended to it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PafFingerprint {
+    /// Number of bytes that were indexed
+    pub size: u64,
+    /// FNV-1a hash of the last (up to) `PafFingerprint::TAIL_BYTES` indexed bytes
+    pub tail_hash: u64,
+}
+
+impl PafFingerprint {
+    pub const TAIL_BYTES: u64 = 64 * 1024;
+
+    /// Fingerprint the first `size` bytes of `paf_file`.
+    pub fn of_prefix(paf_file: &str, size: u64) -> io::Result<Self> {
+        let tail_len = size.min(Self::TAIL_BYTES);
+        let mut file = File::open(paf_file)?;
+        file.seek(SeekFrom::Start(size - tail_len))?;
+        let mut tail = vec![0; tail_len as usize];
+        file.read_exact(&mut tail)?;
+        let tail_hash = tail.iter().fold(0xcbf29ce484222325u64, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3));
+        Ok(Self { size, tail_hash })
     }
 
-    pub fn to_serializable(&self) -> SerializableImpg {
-        let serializable_trees = self.trees.iter().map(|(target_id, tree)| {
-            let intervals = tree.iter().map(|interval| SerializableInterval {
-                first: interval.first,
-                last: interval.last,
-                metadata: interval.metadata.clone(),
-            }).collect();
-            (*target_id, intervals)
-        }).collect();
-        (serializable_trees, self.seq_index.clone())
+    /// Fingerprint the whole of `paf_file`.
+    pub fn of_file(paf_file: &str) -> io::Result<Self> {
+        Self::of_prefix(paf_file, std::fs::metadata(paf_file)?.len())
+    }
+}
+
+/// Read the `.gzi` index of a compressed PAF file, or `None` for uncompressed ones.
+fn read_paf_gzi_index(paf_file: &str) -> Option<bgzf::gzi::Index> {
+    if [".gz", ".bgz"].iter().any(|e| paf_file.ends_with(e)) {
+        let paf_gzi_file = paf_file.to_owned() + ".gzi";
+        Some(bgzf::gzi::read(paf_gzi_file.clone()).unwrap_or_else(|_| panic!("Could not open {}", paf_gzi_file)))
+    } else {
+        None
+    }
+}
+
+/// Size the global rayon pool used for building indexes and expanding queries to `num_threads`.
+/// Returns false, leaving the pool as it is, if it was already set up, for example by an earlier
+/// call or by rayon itself on first use.
+pub fn initialize_thread_pool(num_threads: usize) -> bool {
+    match rayon::ThreadPoolBuilder::new().num_threads(num_threads).build_global() {
+        Ok(()) => true,
+        Err(_) => {
+            info!("Thread pool already initialized with {} threads, not resizing it to {}", rayon::current_num_threads(), num_threads);
+            false
+        },
+    }
+}
+
+impl Impg {
+    /// Build the index from parsed PAF records. With `canonical_seq_order`, sequence ids are
+    /// assigned in lexicographic name order instead of first-seen order, so the ids (and the
+    /// serialized trees) don't depend on the order of the PAF lines. Records matched by
+    /// `drop_self_alignments` are left out of the trees.
+    pub fn from_paf_records(records: &[PafRecord], paf_file: &str, canonical_seq_order: bool, drop_self_alignments: Option<SelfAlignmentFilter>) -> Result<Self, ParseErr> {
+        Self::from_multi_paf_records(&[(records, paf_file)], canonical_seq_order, drop_self_alignments)
     }
 
-    pub fn from_paf_and_serializable(paf_file: &str, serializable: SerializableImpg) -> Self {
-        let (serializable_trees, seq_index) = serializable;
-        let paf_gzi_index: Option<bgzf::gzi::Index> = if [".gz", ".bgz"].iter().any(|e| paf_file.ends_with(e)) {
-            let paf_gzi_file = paf_file.to_owned() + ".gzi";
-            Some(bgzf::gzi::read(paf_gzi_file.clone()).expect(format!("Could not open {}", paf_gzi_file).as_str()))
+    /// Build one index from the records of several PAF files, given with the file each was parsed
+    /// from. Sequence names are shared across files; a sequence reported with different lengths
+    /// is an error.
+    pub fn from_multi_paf_records(records_by_file: &[(&[PafRecord], &str)], canonical_seq_order: bool, drop_self_alignments: Option<SelfAlignmentFilter>) -> Result<Self, ParseErr> {
+        Self::from_multi_paf_records_with_mem_limit(records_by_file, canonical_seq_order, drop_self_alignments, None)
+    }
+
+    /// Same as `from_multi_paf_records`, but with `build_mem_limit` the intervals waiting to be
+    /// built into trees are moved to temporary files whenever they take more than that many
+    /// bytes. The index is the same either way. Only those intervals are bounded: `records_by_file`
+    /// and the finished trees are held in memory regardless.
+    pub fn from_multi_paf_records_with_mem_limit(records_by_file: &[(&[PafRecord], &str)], canonical_seq_order: bool, drop_self_alignments: Option<SelfAlignmentFilter>, build_mem_limit: Option<usize>) -> Result<Self, ParseErr> {
+        let paf_files: Vec<String> = records_by_file.iter().map(|(_, paf_file)| paf_file.to_string()).collect();
+        let paf_gzi_indices = paf_files.iter().map(|paf_file| read_paf_gzi_index(paf_file)).collect();
+
+        let mut seq_index = SequenceIndex::new();
+        let records = || records_by_file.iter().flat_map(|(records, _)| records.iter());
+        if canonical_seq_order {
+            let names: BTreeSet<&str> = records()
+                .flat_map(|record| [record.query_name.as_str(), record.target_name.as_str()])
+                .collect();
+            for name in names {
+                seq_index.get_or_insert_id(name, None);
+            }
+        }
+        register_sequences(&mut seq_index, records())?;
+
+        let records: Vec<(u32, &PafRecord)> = records_by_file.iter().enumerate()
+            .flat_map(|(file_index, (records, _))| records.iter().map(move |record| (file_index as u32, record)))
+            .collect();
+        log_dropped_self_alignments(&records, drop_self_alignments);
+        let trees: TreeMap = match build_mem_limit {
+            Some(mem_limit) => {
+                let mut spilled = SpilledIntervals::new(mem_limit).map_err(ParseErr::IoError)?;
+                let chunk_size = (mem_limit / std::mem::size_of::<Interval<QueryMetadata>>()).max(1);
+                for chunk in records.chunks(chunk_size) {
+                    spilled.extend(group_intervals(chunk, &seq_index, drop_self_alignments)).map_err(ParseErr::IoError)?;
+                }
+                spilled.into_trees().map_err(ParseErr::IoError)?
+            },
+            None => {
+                let intervals = group_intervals(&records, &seq_index, drop_self_alignments);
+                intervals.into_par_iter().map(|(target_id, interval_nodes)| (target_id, build_tree(interval_nodes))).collect()
+            },
+        };
+
+        Ok(Self { trees, seq_index, paf_files, paf_gzi_indices, paf_fingerprints: Vec::new(), has_cigars: true, min_mapq: None })
+    }
+
+    pub fn to_serializable(&self) -> SerializableImpg {
+        let serializable_trees = if self.has_cigars {
+            SerializableTrees::WithCigars(self.trees.iter().map(|(target_id, tree)| {
+                let intervals = tree.iter().map(|interval| SerializableInterval {
+                    first: interval.first,
+                    last: interval.last,
+                    metadata: interval.metadata.clone(),
+                }).collect();
+                (*target_id, intervals)
+            }).collect())
         } else {
-            None
+            SerializableTrees::CoordinatesOnly(self.trees.iter().map(|(target_id, tree)| {
+                (*target_id, tree.iter().map(|interval| CoordinateInterval::from(interval.metadata)).collect())
+            }).collect())
         };
-        let trees = serializable_trees.into_iter().map(|(target_id, intervals)| {
-            let tree = BasicCOITree::new(intervals.iter().map(|interval| Interval {
-                first: interval.first,
-                last: interval.last,
-                metadata: interval.metadata.clone(),
-            }).collect::<Vec<_>>().as_slice());
-            (target_id, tree)
-        }).collect();
-        Self { trees, seq_index, paf_file: paf_file.to_string(), paf_gzi_index }
+        SerializableImpg { format_version: INDEX_FORMAT_VERSION, paf_files: self.paf_files.clone(), paf_fingerprints: self.paf_fingerprints.clone(), min_mapq: self.min_mapq, trees: serializable_trees, seq_index: self.seq_index.clone() }
     }
 
-    pub fn query(&self, target_id: u32, range_start: i32, range_end: i32) -> Vec<AdjustedInterval> {
-        let mut results = Vec::new();
-        // add the input range to the results
-        results.push((
-            Interval {
-                first: range_start,
-                last: range_end,
-                metadata: target_id,
-            },
-            vec![CigarOp::new(range_end - range_start, '=')],
-            Interval {
-                first: range_start,
-                last: range_end,
-                metadata: 0
+    /// Rebuild an index from its serialized form. `paf_files` are the locations of the PAF files
+    /// it was built from, in the same order.
+    pub fn from_paf_and_serializable(paf_files: &[String], serializable: SerializableImpg) -> Self {
+        let SerializableImpg { trees: serializable_trees, seq_index, paf_fingerprints, min_mapq, .. } = serializable;
+        let paf_gzi_indices = paf_files.iter().map(|paf_file| read_paf_gzi_index(paf_file)).collect();
+        let has_cigars = matches!(serializable_trees, SerializableTrees::WithCigars(_));
+        let trees = match serializable_trees {
+            SerializableTrees::WithCigars(trees) => trees.into_iter().map(|(target_id, intervals)| {
+                let tree = BasicCOITree::new(intervals.iter().map(|interval| Interval {
+                    first: interval.first,
+                    last: interval.last,
+                    metadata: interval.metadata.clone(),
+                }).collect::<Vec<_>>().as_slice());
+                (target_id, tree)
+            }).collect(),
+            SerializableTrees::CoordinatesOnly(trees) => trees.into_iter().map(|(target_id, intervals)| {
+                (target_id, BasicCOITree::new(intervals.iter().map(Interval::from).collect::<Vec<_>>().as_slice()))
+            }).collect(),
+        };
+        Self { trees, seq_index, paf_files: paf_files.to_vec(), paf_gzi_indices, paf_fingerprints, has_cigars, min_mapq }
+    }
+
+    /// Add the records appended to the PAF file at `paf_file_index` since the index was built.
+    /// New sequences get the next free ids, so they break `canonical_seq_order`.
+    pub fn append_paf_records(&mut self, records: &[PafRecord], paf_file_index: u32, drop_self_alignments: Option<SelfAlignmentFilter>) -> Result<(), ParseErr> {
+        register_sequences(&mut self.seq_index, records.iter())?;
+        let records: Vec<(u32, &PafRecord)> = records.iter().map(|record| (paf_file_index, record)).collect();
+        log_dropped_self_alignments(&records, drop_self_alignments);
+        for (target_id, mut interval_nodes) in group_intervals(&records, &self.seq_index, drop_self_alignments) {
+            if let Some(tree) = self.trees.get(&target_id) {
+                interval_nodes.extend(tree.iter().map(|interval| Interval {
+                    first: interval.first,
+                    last: interval.last,
+                    metadata: interval.metadata.clone(),
+                }));
             }
-        ));
+            self.trees.insert(target_id, build_tree(interval_nodes));
+        }
+        Ok(())
+    }
+
+    /// Overlaps of `[range_start, range_end)` on the sequence with id `target_id`, starting with
+    /// the input range itself. An empty range is a point query of the base at `range_start`.
+    /// Fails if the CIGAR of an overlapping alignment can't be read from its PAF file.
+    pub fn query(&self, target_id: u32, range_start: i32, range_end: i32) -> io::Result<Vec<AdjustedInterval>> {
+        self.query_iter(target_id, range_start, range_end).collect_results()
+    }
+
+    /// Same as `query`. Sequence ids are stable for a loaded index, so callers running many
+    /// queries can resolve names once with `seq_index.get_id` or enumerate `seq_index.ids()`.
+    ///
+    /// ```
+    /// use std::io::BufReader;
+    /// use impg::impg::Impg;
+    /// use impg::paf::parse_paf;
+    ///
+    /// let paf = "b\t100\t0\t20\t+\ta\t100\t10\t30\t20\t20\t60\tcg:Z:20=\n";
+    /// let paf_file = std::env::temp_dir().join("impg_doctest_query_by_id.paf");
+    /// std::fs::write(&paf_file, paf).unwrap();
+    /// let records = parse_paf(BufReader::new(paf.as_bytes())).unwrap();
+    /// let impg = Impg::from_paf_records(&records, paf_file.to_str().unwrap(), false, None).unwrap();
+    ///
+    /// let a = impg.seq_index.get_id("a").unwrap();
+    /// let results = impg.query_by_id(a, 0, 100).unwrap();
+    /// // The queried range itself, then its projection onto b
+    /// assert_eq!(results.len(), 2);
+    /// let (query, _, target) = &results[1];
+    /// assert_eq!((impg.seq_index.get_name(query.metadata), query.first, query.last), (Some("b"), 0, 20));
+    /// assert_eq!((target.first, target.last), (10, 30));
+    ///
+    /// // Every sequence, including those that are only ever queries, has an id
+    /// let lengths: Vec<usize> = impg.seq_index.ids().map(|id| impg.query_by_id(id, 0, 100).unwrap().len()).collect();
+    /// assert_eq!(lengths, vec![1, 2]);
+    /// ```
+    pub fn query_by_id(&self, target_id: u32, range_start: i32, range_end: i32) -> io::Result<Vec<AdjustedInterval>> {
+        self.query(target_id, range_start, range_end)
+    }
+
+    /// Same as `query_transitive`, see `query_by_id`.
+    pub fn query_transitive_by_id(&self, target_id: u32, range_start: i32, range_end: i32, max_depth: Option<usize>, max_sequences: Option<usize>) -> io::Result<Vec<AdjustedInterval>> {
+        self.query_transitive(target_id, range_start, range_end, max_depth, max_sequences)
+    }
+
+    /// Like `query`, pairing each result with the mapping quality of its alignment record
+    /// (255 for the input range itself).
+    pub fn query_with_mapq(&self, target_id: u32, range_start: i32, range_end: i32) -> io::Result<Vec<(AdjustedInterval, u8)>> {
+        let mut iter = self.query_iter(target_id, range_start, range_end);
+        let results = std::iter::from_fn(|| iter.next_with_mapq()).collect();
+        iter.take_error().map_or(Ok(results), Err)
+    }
+
+    /// Transitively query overlaps, following projected ranges onto other sequences.
+    /// `max_depth` bounds the number of expansion rounds beyond the initial query;
+    /// `Some(0)` behaves like `query`, `None` expands until closure.
+    /// `max_sequences` bounds the number of distinct sequences (including the queried one)
+    /// that results may land on; projections onto further sequences are dropped.
+    pub fn query_transitive(&self, target_id: u32, range_start: i32, range_end: i32, max_depth: Option<usize>, max_sequences: Option<usize>) -> io::Result<Vec<AdjustedInterval>> {
+        self.query_transitive_iter(target_id, range_start, range_end, max_depth, max_sequences).collect_results()
+    }
+
+    /// Like `query_transitive`, pairing each result with the lowest mapping quality of the
+    /// alignment records along the chain of projections that produced it.
+    pub fn query_transitive_with_mapq(&self, target_id: u32, range_start: i32, range_end: i32, max_depth: Option<usize>, max_sequences: Option<usize>) -> io::Result<Vec<(AdjustedInterval, u8)>> {
+        let mut iter = self.query_transitive_iter(target_id, range_start, range_end, max_depth, max_sequences);
+        let results = std::iter::from_fn(|| iter.next_with_mapq()).collect();
+        iter.take_error().map_or(Ok(results), Err)
+    }
+
+    /// Like `query_transitive_with_mapq`, giving up on expansion rounds that would start after
+    /// `deadline`. Returns the results found so far and whether the deadline cut the query short;
+    /// the input range itself is always returned.
+    pub fn query_transitive_with_deadline(&self, target_id: u32, range_start: i32, range_end: i32, max_depth: Option<usize>, max_sequences: Option<usize>, deadline: Instant) -> io::Result<(Vec<(AdjustedInterval, u8)>, bool)> {
+        let mut iter = self.query_transitive_iter(target_id, range_start, range_end, max_depth, max_sequences).with_deadline(deadline);
+        let results = std::iter::from_fn(|| iter.next_with_mapq()).collect();
+        if let Some(e) = iter.take_error() {
+            return Err(e);
+        }
+        Ok((results, iter.timed_out()))
+    }
+
+    /// Project `[range_start, range_end)` on `target_id` through every overlapping alignment,
+    /// along with the mapping quality of each alignment.
+    /// Alignments onto other queries than `only_query`, if given, are skipped.
+    fn project_overlaps(&self, target_id: u32, range_start: i32, range_end: i32, only_query: Option<u32>, keep_cigar: bool) -> io::Result<Vec<(AdjustedInterval, u8, RecordId)>> {
+        let mut results = Vec::new();
+        let mut error = None;
         if let Some(tree) = self.trees.get(&target_id) {
             tree.query(range_start, range_end, |interval| {
                 let metadata = &interval.metadata;
-                let (adjusted_query_start, adjusted_query_end, adjusted_cigar, adjusted_target_start, adjusted_target_end) = 
-                project_target_range_through_alignment(
-                    (range_start, range_end),
-                    (metadata.target_start, metadata.target_end, metadata.query_start, metadata.query_end, metadata.strand),
-                    &metadata.get_cigar_ops(&self.paf_file, self.paf_gzi_index.as_ref())
-                );
-
-                let adjusted_interval = (
+                if error.is_some() {
+                    return;
+                }
+                // The tree query is end-inclusive, so skip alignments merely touching the range
+                if metadata.target_end <= range_start || metadata.target_start >= range_end {
+                    return;
+                }
+                if only_query.is_some_and(|query_id| metadata.query_id != query_id) {
+                    return;
+                }
+                let record = (metadata.target_start, metadata.target_end, metadata.query_start, metadata.query_end, metadata.strand);
+                let (adjusted_query_start, adjusted_query_end, adjusted_cigar, adjusted_target_start, adjusted_target_end) = if self.has_cigars {
+                    let cigar = match metadata.get_cigar_ops(&self.paf_files[metadata.paf_file_index as usize], self.paf_gzi_indices[metadata.paf_file_index as usize].as_ref()) {
+                        Ok(cigar) => cigar,
+                        Err(e) => {
+                            error = Some(e);
+                            return;
+                        },
+                    };
+                    project_through_alignment((range_start, range_end), record, &cigar, keep_cigar)
+                } else {
+                    project_target_range_linearly((range_start, range_end), record)
+                };
+
+                results.push(((
                     Interval {
                         first: adjusted_query_start,
                         last: adjusted_query_end,
@@ -243,19 +860,158 @@ impl Impg {
                     Interval {
                         first: adjusted_target_start,
                         last: adjusted_target_end,
-                        metadata: 0
+                        metadata: target_id
                     }
-                );
-                results.push(adjusted_interval);
+                ), metadata.mapq, metadata.record_id()));
             });
         }
-        results
+        error.map_or(Ok(results), Err)
     }
 
-    pub fn query_transitive(&self, target_id: u32, range_start: i32, range_end: i32) -> Vec<AdjustedInterval> {
-        let mut results = Vec::new();
+    /// The stored alignment a result was projected through: the first alignment, in target and
+    /// query coordinate order, between its query and target sequences on its strand whose spans
+    /// contain both sides of the result. For transitive results this is the last hop. `None` for
+    /// the input range itself and for results merged across alignments.
+    pub fn source_alignment(&self, (query, _, target): &AdjustedInterval) -> Option<QueryMetadata> {
+        let tree = self.trees.get(&target.metadata)?;
+        let strand = if query.first <= query.last { Strand::Forward } else { Strand::Reverse };
+        let (query_start, query_end) = (query.first.min(query.last), query.first.max(query.last));
+        let mut candidates = Vec::new();
+        tree.query(target.first, target.last, |interval| {
+            let metadata = &interval.metadata;
+            if metadata.query_id == query.metadata && metadata.strand == strand
+                && metadata.target_start <= target.first && metadata.target_end >= target.last
+                && metadata.query_start <= query_start && metadata.query_end >= query_end {
+                candidates.push(metadata.clone());
+            }
+        });
+        candidates.into_iter().min_by_key(|metadata| (metadata.target_start, metadata.target_end, metadata.query_start, metadata.query_end, metadata.paf_file_index))
+    }
+
+    /// The optional fields of the PAF line of `metadata`, other than its `cg:Z:` CIGAR.
+    pub fn optional_fields(&self, metadata: &QueryMetadata) -> io::Result<Vec<String>> {
+        let paf_file_index = metadata.paf_file_index as usize;
+        metadata.get_optional_fields(&self.paf_files[paf_file_index], self.paf_gzi_indices[paf_file_index].as_ref())
+    }
+
+    /// Check that the index holds one interval per alignment of `records` onto each target, after
+    /// dropping `drop_self_alignments`. Returns the number of indexed records and sequences, or the
+    /// first divergence in sequence id order.
+    pub fn validate_records<'r>(&self, records: impl Iterator<Item = &'r PafRecord>, drop_self_alignments: Option<SelfAlignmentFilter>) -> Result<(usize, usize), IndexDivergence> {
+        let mut expected: BTreeMap<u32, usize> = BTreeMap::new();
+        for record in records.filter(|record| !drop_self_alignments.is_some_and(|filter| filter.matches(record))) {
+            let id = |name: &str| self.seq_index.get_id(name).ok_or_else(|| IndexDivergence::MissingSequence(name.to_string()));
+            id(&record.query_name)?;
+            *expected.entry(id(&record.target_name)?).or_default() += 1;
+        }
+
+        let target_ids: BTreeSet<u32> = expected.keys().chain(self.trees.keys()).copied().collect();
+        for target_id in target_ids {
+            let indexed = self.trees.get(&target_id).map_or(0, |tree| tree.len());
+            let expected = expected.get(&target_id).copied().unwrap_or(0);
+            if indexed != expected {
+                let seq_name = self.seq_index.get_name(target_id).unwrap_or_default().to_string();
+                return Err(IndexDivergence::IntervalCount { seq_name, indexed, expected });
+            }
+        }
+        Ok((expected.values().sum(), self.seq_index.len()))
+    }
+
+    /// The tree of each target sequence, by increasing sequence id, for reports that must not
+    /// depend on the order of `trees`.
+    pub fn trees_by_id(&self) -> Vec<(u32, &BasicCOITree<QueryMetadata, u32>)> {
+        let mut trees: Vec<_> = self.trees.iter().map(|(&target_id, tree)| (target_id, tree)).collect();
+        trees.sort_unstable_by_key(|(target_id, _)| *target_id);
+        trees
+    }
+
+    /// The stored intervals on `target_id` as `(target_start, target_end, metadata)`, sorted by
+    /// target start, then end and query id. Empty if the sequence is not a target in the index.
+    pub fn tree_entries(&self, target_id: u32) -> Vec<(i32, i32, &QueryMetadata)> {
+        let mut entries: Vec<_> = match self.trees.get(&target_id) {
+            Some(tree) => tree.iter().map(|interval| (interval.first, interval.last, interval.metadata)).collect(),
+            None => Vec::new(),
+        };
+        entries.sort_by_key(|(first, last, metadata)| (*first, *last, metadata.query_id, metadata.query_start, metadata.query_end));
+        entries
+    }
+
+    /// The largest number of stored alignments covering any single base of `target_id`.
+    pub fn max_overlap_depth(&self, target_id: u32) -> usize {
+        let Some(tree) = self.trees.get(&target_id) else {
+            return 0;
+        };
+        // Sweep over interval ends and starts; ends sort first so touching intervals don't stack
+        let mut events: Vec<(i32, i32)> = tree.iter().flat_map(|interval| [(interval.first, 1), (interval.last, -1)]).collect();
+        events.sort_unstable();
+        let (mut depth, mut max_depth) = (0, 0);
+        for (_, change) in events {
+            depth += change;
+            max_depth = max_depth.max(depth);
+        }
+        max_depth as usize
+    }
+
+    /// Lazily yield the overlaps of `query`, starting with the input range itself.
+    pub fn query_iter(&self, target_id: u32, range_start: i32, range_end: i32) -> QueryIter<'_> {
+        QueryIter::new(self, target_id, range_start, range_end, Some(0), None)
+    }
+
+    /// Lazily yield the overlaps of `query_transitive`. Each expansion round is only
+    /// queried once the results of the previous one have been consumed.
+    pub fn query_transitive_iter(&self, target_id: u32, range_start: i32, range_end: i32, max_depth: Option<usize>, max_sequences: Option<usize>) -> QueryIter<'_> {
+        QueryIter::new(self, target_id, range_start, range_end, max_depth, max_sequences)
+    }
+}
+
+/// Counters of one expansion round of a `QueryIter`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ExpansionRound {
+    /// Ranges queried in the round
+    pub queried: usize,
+    /// Results the round produced
+    pub results: usize,
+    /// Results onto ranges already queried, which aren't expanded again
+    pub deduplicated: usize,
+    /// Projections dropped by `max_sequences`
+    pub dropped: usize,
+    /// Distinct sequences reached so far, including the queried one
+    pub sequences: usize,
+}
+
+/// Iterator over query results, expanding the transitive frontier one round at a time.
+/// The ranges of a round are projected in parallel on the rayon pool and merged in
+/// frontier order, so results don't depend on the number of threads. Iteration ends early if
+/// the CIGAR of an alignment can't be read, with the error left for `take_error`.
+pub struct QueryIter<'a> {
+    impg: &'a Impg,
+    max_depth: Option<usize>,
+    max_sequences: Option<usize>,
+    pending: VecDeque<(AdjustedInterval, u8, Vec<RecordId>)>,
+    // Ranges to query in the next round, with the lowest mapping quality and, if tracing, the
+    // records on the way to them
+    frontier: Vec<(u32, i32, i32, u8, Vec<RecordId>)>,
+    depth: usize,
+    visited: HashSet<(u32, i32, i32)>,
+    touched_sequences: HashSet<u32>,
+    deadline: Option<Instant>,
+    timed_out: bool,
+    against: Option<u32>,
+    rounds: Vec<ExpansionRound>,
+    trace: bool,
+    keep_cigars: bool,
+    // Whether the input range, always the first result, is still to be yielded
+    input_row_pending: bool,
+    error: Option<io::Error>,
+}
+
+impl<'a> QueryIter<'a> {
+    fn new(impg: &'a Impg, target_id: u32, range_start: i32, range_end: i32, max_depth: Option<usize>, max_sequences: Option<usize>) -> Self {
+        // Point queries cover the base at their position
+        let range_end = if range_end == range_start { range_start.saturating_add(1) } else { range_end };
+        let mut pending = VecDeque::new();
         // add the input range to the results
-        results.push((
+        pending.push_back(((
             Interval {
                 first: range_start,
                 last: range_end,
@@ -265,49 +1021,188 @@ impl Impg {
             Interval {
                 first: range_start,
                 last: range_end,
-                metadata: 0
+                metadata: target_id
             }
-        ));
-        let mut stack = vec![(target_id, range_start, range_end)];
+        ), 255, Vec::new()));
         let mut visited = HashSet::new();
+        visited.insert((target_id, range_start, range_end));
+        let mut touched_sequences = HashSet::new();
+        touched_sequences.insert(target_id);
+
+        Self {
+            impg,
+            max_depth,
+            max_sequences,
+            pending,
+            frontier: vec![(target_id, range_start, range_end, 255, Vec::new())],
+            depth: 0,
+            visited,
+            touched_sequences,
+            deadline: None,
+            timed_out: false,
+            against: None,
+            rounds: Vec::new(),
+            trace: false,
+            keep_cigars: true,
+            input_row_pending: true,
+            error: None,
+        }
+    }
 
-        while let Some((current_target, current_start, current_end)) = stack.pop() {
-            if let Some(tree) = self.trees.get(&current_target) {
-                tree.query(current_start, current_end, |interval| {
-                    let metadata = &interval.metadata;
-                    let (adjusted_query_start, adjusted_query_end, adjusted_cigar, adjusted_target_start, adjusted_target_end) = 
-                    project_target_range_through_alignment(
-                        (current_start, current_end),
-                        (metadata.target_start, metadata.target_end, metadata.query_start, metadata.query_end, metadata.strand),
-                        &metadata.get_cigar_ops(&self.paf_file, self.paf_gzi_index.as_ref())
-                    );
-
-                    let adjusted_interval = (
-                        Interval {
-                            first: adjusted_query_start,
-                            last: adjusted_query_end,
-                            metadata: metadata.query_id
-                        },
-                        adjusted_cigar,
-                        Interval {
-                            first: adjusted_target_start,
-                            last: adjusted_target_end,
-                            metadata: 0
-                        }
-                    );
-                    results.push(adjusted_interval);
-
-                    if metadata.query_id != current_target {
-                        let todo_range = (metadata.query_id, adjusted_query_start, adjusted_query_end);
-                        if !visited.insert(todo_range) {
-                            stack.push(todo_range);
-                        }
+    /// Record the alignment records each result was projected through, for `next_traced`.
+    pub fn with_trace(mut self) -> Self {
+        self.trace = true;
+        self
+    }
+
+    /// Yield results with empty CIGARs, skipping their reconstruction for callers that only need
+    /// coordinates. The coordinates are the same.
+    pub fn coordinates_only(mut self) -> Self {
+        self.keep_cigars = false;
+        for ((_, cigar, _), _, _) in &mut self.pending {
+            cigar.clear();
+        }
+        self
+    }
+
+    /// Counters of the expansion rounds run so far, also logged at debug level.
+    pub fn rounds(&self) -> &[ExpansionRound] {
+        &self.rounds
+    }
+
+    /// Only yield results whose query side lies on `query_id`. Alignments onto other sequences
+    /// are still followed by transitive queries, but skipped unprojected in the last round.
+    pub fn against(mut self, query_id: u32) -> Self {
+        self.against = Some(query_id);
+        self
+    }
+
+    /// Stop expanding once `deadline` has passed, checked before each expansion round.
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Whether expansion stopped early at the deadline, leaving results out.
+    pub fn timed_out(&self) -> bool {
+        self.timed_out
+    }
+
+    /// The error that ended iteration early, if any, leaving `None` in its place.
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    /// Collect the remaining results, or the error that ends them.
+    fn collect_results(mut self) -> io::Result<Vec<AdjustedInterval>> {
+        let results = self.by_ref().collect();
+        self.take_error().map_or(Ok(results), Err)
+    }
+
+    /// Query every range of the current frontier, buffering their overlaps and collecting the next frontier.
+    fn expand(&mut self) {
+        let frontier = std::mem::take(&mut self.frontier);
+        let impg = self.impg;
+        let expand_further = self.max_depth.is_none_or(|max| self.depth < max);
+        // Only the results of the last round can skip the alignments onto other sequences
+        let only_query = if expand_further { None } else { self.against };
+        let keep_cigars = self.keep_cigars;
+        let overlaps: io::Result<Vec<Vec<(AdjustedInterval, u8, RecordId)>>> = frontier.par_iter()
+            .map(|&(current_target, current_start, current_end, _, _)| impg.project_overlaps(current_target, current_start, current_end, only_query, keep_cigars))
+            .collect();
+        let overlaps = match overlaps {
+            Ok(overlaps) => overlaps,
+            Err(e) => {
+                self.error = Some(e);
+                return;
+            },
+        };
+
+        let mut round = ExpansionRound { queried: frontier.len(), ..ExpansionRound::default() };
+        for ((current_target, _, _, chain_mapq, chain), overlaps) in frontier.into_iter().zip(overlaps) {
+            for (adjusted_interval, mapq, record_id) in overlaps {
+                let mapq = mapq.min(chain_mapq);
+                let trace = match self.trace {
+                    true => chain.iter().copied().chain([record_id]).collect(),
+                    false => Vec::new(),
+                };
+                let (query_interval, _, _) = &adjusted_interval;
+                let query_id = query_interval.metadata;
+                if !self.touched_sequences.contains(&query_id) {
+                    if self.max_sequences.is_some_and(|max| self.touched_sequences.len() >= max) {
+                        round.dropped += 1;
+                        continue;
+                    }
+                    self.touched_sequences.insert(query_id);
+                }
+
+                if query_id != current_target && expand_further {
+                    // Reverse strand projections have start > end, normalize before querying again
+                    let todo_range = (query_id, query_interval.first.min(query_interval.last), query_interval.first.max(query_interval.last));
+                    if self.visited.insert(todo_range) {
+                        self.frontier.push((todo_range.0, todo_range.1, todo_range.2, mapq, trace.clone()));
+                    } else {
+                        round.deduplicated += 1;
                     }
-                });
+                }
+                round.results += 1;
+                self.pending.push_back((adjusted_interval, mapq, trace));
+            }
+        }
+        round.sequences = self.touched_sequences.len();
+        debug!("Expansion round {}: queried {} ranges, {} results, {} deduplicated, {} dropped, {} sequences reached",
+               self.depth, round.queried, round.results, round.deduplicated, round.dropped, round.sequences);
+        self.rounds.push(round);
+        self.depth += 1;
+    }
+}
+
+impl<'a> QueryIter<'a> {
+    /// Yield the results paired with their mapping quality, as in `Impg::query_transitive_with_mapq`.
+    /// An error reading a CIGAR ends the results without being reported, so callers that need it
+    /// should use `next_with_mapq` and `take_error`.
+    pub fn with_mapq(mut self) -> impl Iterator<Item = (AdjustedInterval, u8)> + 'a {
+        std::iter::from_fn(move || self.next_with_mapq())
+    }
+
+    /// The next result paired with its mapping quality.
+    pub fn next_with_mapq(&mut self) -> Option<(AdjustedInterval, u8)> {
+        self.next_traced().map(|(adjusted_interval, mapq, _)| (adjusted_interval, mapq))
+    }
+
+    /// The next result with its mapping quality and the records it was projected through, from
+    /// the queried sequence outwards. The trace is empty for the input range, and unless
+    /// `with_trace` was set.
+    pub fn next_traced(&mut self) -> Option<(AdjustedInterval, u8, Vec<RecordId>)> {
+        self.next_marked().map(|(adjusted_interval, mapq, trace, _)| (adjusted_interval, mapq, trace))
+    }
+
+    /// Like `next_traced`, also telling whether the result is the input range itself rather than
+    /// an alignment, which an identity self-alignment can't be told apart from by coordinates.
+    pub fn next_marked(&mut self) -> Option<(AdjustedInterval, u8, Vec<RecordId>, bool)> {
+        loop {
+            while self.pending.is_empty() && !self.frontier.is_empty() {
+                if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    self.timed_out = true;
+                    self.frontier.clear();
+                    break;
+                }
+                self.expand();
+            }
+            let (adjusted_interval, mapq, trace) = self.pending.pop_front()?;
+            let input_row = std::mem::replace(&mut self.input_row_pending, false);
+            if self.against.is_none_or(|query_id| adjusted_interval.0.metadata == query_id) {
+                return Some((adjusted_interval, mapq, trace, input_row));
             }
         }
+    }
+}
 
-        results
+impl Iterator for QueryIter<'_> {
+    type Item = AdjustedInterval;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_with_mapq().map(|(adjusted_interval, _)| adjusted_interval)
     }
 }
 
@@ -315,6 +1210,16 @@ fn project_target_range_through_alignment(
     target_range: (i32, i32),
     record: (i32, i32, i32, i32, Strand),
     cigar_ops: &[CigarOp]
+) -> (i32, i32, Vec<CigarOp>, i32, i32) {
+    project_through_alignment(target_range, record, cigar_ops, true)
+}
+
+/// `project_target_range_through_alignment`, leaving the returned CIGAR empty unless `keep_cigar`.
+fn project_through_alignment(
+    target_range: (i32, i32),
+    record: (i32, i32, i32, i32, Strand),
+    cigar_ops: &[CigarOp],
+    keep_cigar: bool
 ) -> (i32, i32, Vec<CigarOp>, i32, i32) {
     let (target_start, target_end, query_start, query_end, strand) = record;
 
@@ -334,11 +1239,14 @@ fn project_target_range_through_alignment(
             break;
         }
         match (cigar_op.target_delta(), cigar_op.query_delta(strand)) {
+            (0, 0) => {}, // Padding
             (0, query_delta) => { // Insertion in query (deletions in target)
                 if target_pos >= target_range.0 && target_pos <= target_range.1 {
                     projected_start.get_or_insert(query_pos);
                     projected_end = Some(query_pos + query_delta);
-                    projected_cigar.push(CigarOp::new(query_delta.abs(), 'I'));
+                    if keep_cigar {
+                        projected_cigar.push(CigarOp::new(query_delta.abs(), 'I'));
+                    }
 
                     new_target_start.get_or_insert(target_pos);
                     new_target_end = Some(target_pos);
@@ -353,49 +1261,429 @@ fn project_target_range_through_alignment(
                     projected_start.get_or_insert(query_pos);
                     projected_end = Some(query_pos); // Deletion does not advance query position
 
-                    projected_cigar.push(CigarOp::new(overlap_end - overlap_start, 'D'));
+                    if keep_cigar {
+                        projected_cigar.push(CigarOp::new(overlap_end - overlap_start, cigar_op.op()));
+                    }
+
+                    new_target_start.get_or_insert(overlap_start);
+                    new_target_end = Some(overlap_end);
+                }
+
+                target_pos += target_delta;
+            },
+            (target_delta, query_delta) => { // Match or mismatch
+                let overlap_start = target_pos.max(target_range.0);
+                let overlap_end = (target_pos + target_delta).min(target_range.1);
+                if overlap_start < overlap_end { // There's an overlap
+                    let overlap_length = overlap_end - overlap_start;
+                    let dir = if strand == Strand::Forward { 1 } else { -1 };
+                    let query_overlap_start = query_pos + (overlap_start - target_pos) * dir;
+                    let query_overlap_end = query_overlap_start + overlap_length * dir;
+
+                    projected_start.get_or_insert(query_overlap_start);
+                    projected_end = Some(query_overlap_end);
+
+                    if keep_cigar {
+                        projected_cigar.push(CigarOp::new(overlap_length, cigar_op.op()));
+                    }
+
+                    new_target_start.get_or_insert(overlap_start);
+                    new_target_end = Some(overlap_end);
+                }
+
+                target_pos += target_delta;
+                query_pos += query_delta;
+            },
+        }
+    }
+    
+    (
+        projected_start.unwrap_or(query_start),
+        (projected_end.unwrap_or(query_pos)).min(query_end),
+        projected_cigar,
+        new_target_start.unwrap_or(target_start),
+        (new_target_end.unwrap_or(target_pos)).min(target_end),
+    )
+}
+
+/// Like `project_target_range_through_alignment` for an alignment without a CIGAR, scaling target
+/// offsets by the ratio of query to target length. The returned CIGAR is empty.
+fn project_target_range_linearly(target_range: (i32, i32), record: (i32, i32, i32, i32, Strand)) -> (i32, i32, Vec<CigarOp>, i32, i32) {
+    let (target_start, target_end, query_start, query_end, strand) = record;
+    let overlap_start = target_range.0.clamp(target_start, target_end);
+    let overlap_end = target_range.1.clamp(overlap_start, target_end);
+    let scale = |target_pos: i32| {
+        let offset = (target_pos - target_start) as i64 * (query_end - query_start) as i64 / (target_end - target_start).max(1) as i64;
+        offset as i32
+    };
+    let (projected_start, projected_end) = match strand {
+        Strand::Forward => (query_start + scale(overlap_start), query_start + scale(overlap_end)),
+        Strand::Reverse => (query_end - scale(overlap_start), query_end - scale(overlap_end)),
+    };
+    (projected_start, projected_end, Vec::new(), overlap_start, overlap_end)
+}
+
+/// Merge results on the same query sequence and strand whose query ranges overlap or lie within
+/// `merge_distance` bp of each other. A merged interval spans all of its members on the query and
+/// on the target of its longest member. No CIGAR of a member matches those spans, so merged
+/// intervals have an empty CIGAR. Unmerged intervals are returned unchanged. The output is sorted
+/// by query sequence, strand and start.
+pub fn merge_adjusted_intervals(results: Vec<AdjustedInterval>, merge_distance: i32) -> Vec<AdjustedInterval> {
+    let span = |query: &Interval<u32>| (query.first.min(query.last), query.first.max(query.last));
+    let mut results = results;
+    results.sort_by_key(|(query, _, _)| {
+        let (start, end) = span(query);
+        (query.metadata, query.first > query.last, start, end)
+    });
+
+    let mut merged: Vec<AdjustedInterval> = Vec::with_capacity(results.len());
+    // Members of the current group, merged when the next interval starts a new group
+    let mut group: Vec<AdjustedInterval> = Vec::new();
+    let mut group_end = i32::MIN;
+    let flush = |group: &mut Vec<AdjustedInterval>, merged: &mut Vec<AdjustedInterval>| {
+        if group.len() == 1 {
+            merged.push(group.pop().unwrap());
+            return;
+        }
+        let (start, end) = group.iter().fold((i32::MAX, i32::MIN), |(start, end), (query, _, _)| {
+            let (first, last) = span(query);
+            (start.min(first), end.max(last))
+        });
+        let representative = group.iter().max_by_key(|(query, _, _)| span(query).1 - span(query).0).unwrap();
+        let (query, _, target) = representative;
+        let (target_first, target_last) = group.iter()
+            .filter(|(_, _, other)| other.metadata == target.metadata)
+            .fold((target.first, target.last), |(first, last), (_, _, other)| (first.min(other.first), last.max(other.last)));
+        let (query_first, query_last) = if query.first <= query.last { (start, end) } else { (end, start) };
+        merged.push((
+            Interval { first: query_first, last: query_last, metadata: query.metadata },
+            Vec::new(),
+            Interval { first: target_first, last: target_last, metadata: target.metadata },
+        ));
+        group.clear();
+    };
+
+    for result in results {
+        let (start, end) = span(&result.0);
+        let same_group = group.last().is_some_and(|(last, _, _)| {
+            last.metadata == result.0.metadata && (last.first > last.last) == (result.0.first > result.0.last)
+        }) && start <= group_end.saturating_add(merge_distance);
+        if !same_group && !group.is_empty() {
+            flush(&mut group, &mut merged);
+        }
+        group_end = if same_group { group_end.max(end) } else { end };
+        group.push(result);
+    }
+    if !group.is_empty() {
+        flush(&mut group, &mut merged);
+    }
+    merged
+}
+
+/// The union of `ranges` as sorted, disjoint ranges, joining those that overlap or touch. Ranges
+/// may be unsorted; empty ranges are dropped.
+pub fn merge_ranges(ranges: &[(i32, i32)]) -> Vec<(i32, i32)> {
+    let mut merged: Vec<(i32, i32)> = ranges.iter().copied().filter(|(range_start, range_end)| range_start < range_end).collect();
+    merged.sort_unstable();
+    merged.dedup_by(|next, last| {
+        let joined = next.0 <= last.1;
+        if joined {
+            last.1 = last.1.max(next.1);
+        }
+        joined
+    });
+    merged
+}
+
+/// The parts of `[start, end)` not covered by any of `ranges`, in ascending order. Ranges may be
+/// unsorted, overlapping, or extend beyond `[start, end)`; empty ranges cover nothing.
+pub fn complement_ranges(ranges: &[(i32, i32)], start: i32, end: i32) -> Vec<(i32, i32)> {
+    let mut ranges: Vec<(i32, i32)> = ranges.iter().copied().filter(|(range_start, range_end)| range_start < range_end).collect();
+    ranges.sort();
+    let mut gaps = Vec::new();
+    let mut covered_until = start;
+    for (range_start, range_end) in ranges {
+        if range_start > covered_until {
+            gaps.push((covered_until, range_start.min(end)));
+        }
+        covered_until = covered_until.max(range_end);
+        if covered_until >= end {
+            break;
+        }
+    }
+    if covered_until < end {
+        gaps.push((covered_until, end));
+    }
+    gaps.retain(|(gap_start, gap_end)| gap_start < gap_end);
+    gaps
+}
+
+/// Clip an `AdjustedInterval` so its target side lies within `[range_start, range_end)`, cutting
+/// the query side and the CIGAR at the matching alignment positions. Returns `None` if nothing
+/// of the interval falls within the range.
+pub fn clip_to_target_range(interval: &AdjustedInterval, range_start: i32, range_end: i32) -> Option<AdjustedInterval> {
+    let (query, cigar, target) = interval;
+    if target.first >= range_start && target.last <= range_end {
+        return Some(interval.clone());
+    }
+    if target.last <= range_start || target.first >= range_end {
+        return None;
+    }
+    let record = if query.first <= query.last {
+        (target.first, target.last, query.first, query.last, Strand::Forward)
+    } else {
+        (target.first, target.last, query.last, query.first, Strand::Reverse)
+    };
+    let (query_start, query_end, clipped_cigar, target_start, target_end) =
+        project_target_range_through_alignment((range_start, range_end), record, cigar);
+    if clipped_cigar.is_empty() {
+        return None;
+    }
+    Some((
+        Interval { first: query_start, last: query_end, metadata: query.metadata },
+        clipped_cigar,
+        Interval { first: target_start, last: target_end, metadata: target.metadata },
+    ))
+}
+
+/// Where a single target base lands on the query side of an alignment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LiftedPosition {
+    /// The base is aligned (as a match or mismatch) to this query base
+    Aligned(i32),
+    /// The base is deleted from the query, whose sequence continues from this position
+    Gap(i32),
+}
+
+/// Find the query position aligned to target base `pos` by walking the CIGAR of `interval`.
+/// Returns `None` if `pos` lies outside the interval's target range.
+pub fn lift_position(interval: &AdjustedInterval, pos: i32) -> Option<LiftedPosition> {
+    let (query, cigar, target) = interval;
+    let forward = query.first <= query.last;
+    let mut query_pos = query.first;
+    let mut target_pos = target.first;
+    for op in cigar {
+        let query_len = if op.query_delta(Strand::Forward) != 0 { op.len() } else { 0 };
+        let target_len = op.target_delta();
+        if target_len > 0 && pos >= target_pos && pos < target_pos + target_len {
+            if query_len == 0 {
+                return Some(LiftedPosition::Gap(query_pos));
+            }
+            let offset = pos - target_pos;
+            return Some(LiftedPosition::Aligned(if forward { query_pos + offset } else { query_pos - offset - 1 }));
+        }
+        target_pos += target_len;
+        query_pos += if forward { query_len } else { -query_len };
+    }
+    None
+}
+
+/// Clip an `AdjustedInterval` so its query side lies within `[range_start, range_end)` (ascending
+/// coordinates on either strand), walking the CIGAR to find the target coordinates of the cut
+/// points. The returned CIGAR only covers the kept portion. Returns `None` if the interval has no
+/// aligned or inserted query bases in the range.
+pub fn clip_to_query_range(interval: &AdjustedInterval, range_start: i32, range_end: i32) -> Option<AdjustedInterval> {
+    let (query, cigar, target) = interval;
+    let forward = query.first <= query.last;
+    let mut query_pos = query.first;
+    let mut target_pos = target.first;
+    let mut clipped_cigar: Vec<CigarOp> = Vec::new();
+    let mut query_bounds: Option<(i32, i32)> = None;
+    let mut target_bounds: Option<(i32, i32)> = None;
+
+    for op in cigar {
+        let len = op.len();
+        let query_len = if op.query_delta(Strand::Forward) == 0 { 0 } else { len };
+        let target_len = op.target_delta();
+        // Ascending query span of the op, and the op offsets [i0, i1) falling in the range
+        let (span_start, span_end) = if forward { (query_pos, query_pos + query_len) } else { (query_pos - query_len, query_pos) };
+        let (i0, i1) = if query_len == 0 {
+            // Deletions are kept when they fall strictly inside the range
+            if query_pos > range_start && query_pos < range_end { (0, len) } else { (0, 0) }
+        } else {
+            let overlap_start = span_start.max(range_start);
+            let overlap_end = span_end.min(range_end);
+            if overlap_start >= overlap_end {
+                (0, 0)
+            } else if forward {
+                (overlap_start - span_start, overlap_end - span_start)
+            } else {
+                (span_end - overlap_end, span_end - overlap_start)
+            }
+        };
+
+        if i0 < i1 {
+            let piece_query_first = if forward { query_pos + i0.min(query_len) } else { query_pos - i0.min(query_len) };
+            let piece_query_last = if forward { query_pos + i1.min(query_len) } else { query_pos - i1.min(query_len) };
+            let (piece_target_first, piece_target_last) = if target_len == 0 {
+                (target_pos, target_pos)
+            } else {
+                (target_pos + i0, target_pos + i1)
+            };
+            query_bounds = Some(match query_bounds {
+                Some((first, _)) => (first, piece_query_last),
+                None => (piece_query_first, piece_query_last),
+            });
+            target_bounds = Some(match target_bounds {
+                Some((first, _)) => (first, piece_target_last),
+                None => (piece_target_first, piece_target_last),
+            });
+            clipped_cigar.push(CigarOp::new(i1 - i0, op.op()));
+        }
 
-                    new_target_start.get_or_insert(overlap_start);
-                    new_target_end = Some(overlap_end);
-                }
+        query_pos += if forward { query_len } else { -query_len };
+        target_pos += target_len;
+    }
 
-                target_pos += target_delta;
-            },
-            (target_delta, query_delta) => { // Match or mismatch
-                let overlap_start = target_pos.max(target_range.0);
-                let overlap_end = (target_pos + target_delta).min(target_range.1);
-                if overlap_start < overlap_end { // There's an overlap
-                    let overlap_length = overlap_end - overlap_start;
-                    let dir = if strand == Strand::Forward { 1 } else { -1 };
-                    let query_overlap_start = query_pos + (overlap_start - target_pos) * dir;
-                    let query_overlap_end = query_overlap_start + overlap_length * dir;
+    let (query_first, query_last) = query_bounds?;
+    let (target_first, target_last) = target_bounds?;
+    if clipped_cigar.iter().all(|op| op.query_delta(Strand::Forward) == 0) {
+        return None;
+    }
+    Some((
+        Interval { first: query_first, last: query_last, metadata: query.metadata },
+        clipped_cigar,
+        Interval { first: target_first, last: target_last, metadata: target.metadata },
+    ))
+}
 
-                    projected_start.get_or_insert(query_overlap_start);
-                    projected_end = Some(query_overlap_end);
+/// Remove the `masked` query ranges from an `AdjustedInterval`, returning one precisely clipped
+/// interval per unmasked query segment, in ascending query order.
+pub fn subtract_query_regions(interval: &AdjustedInterval, masked: &[(i32, i32)]) -> Vec<AdjustedInterval> {
+    let (query, _, _) = interval;
+    let (start, end) = (query.first.min(query.last), query.first.max(query.last));
+    let mut masked: Vec<(i32, i32)> = masked.iter().copied().filter(|&(mask_start, mask_end)| mask_start < end && mask_end > start).collect();
+    masked.sort();
+
+    let mut segments = Vec::new();
+    let mut segment_start = start;
+    for (mask_start, mask_end) in masked {
+        if mask_start > segment_start {
+            segments.push((segment_start, mask_start));
+        }
+        segment_start = segment_start.max(mask_end);
+    }
+    if segment_start < end {
+        segments.push((segment_start, end));
+    }
 
-                    projected_cigar.push(CigarOp::new(overlap_length, cigar_op.op()));
+    segments.into_iter().filter_map(|(segment_start, segment_end)| clip_to_query_range(interval, segment_start, segment_end)).collect()
+}
 
-                    new_target_start.get_or_insert(overlap_start);
-                    new_target_end = Some(overlap_end);
+/// Replace each `M` run of `cigar` with `=`/`X` runs by comparing the aligned bases, ignoring case.
+/// `target_seq` and `query_seq` are the aligned sequences in alignment orientation (the query
+/// reverse-complemented on the reverse strand). Adjacent ops of the same kind are merged.
+pub fn refine_match_ops(cigar: &[CigarOp], target_seq: &[u8], query_seq: &[u8]) -> Vec<CigarOp> {
+    let mut refined: Vec<(i32, char)> = Vec::with_capacity(cigar.len());
+    let mut push = |len: i32, op: char| match refined.last_mut() {
+        Some((last_len, last_op)) if *last_op == op => *last_len += len,
+        _ => refined.push((len, op)),
+    };
+    let (mut target_pos, mut query_pos) = (0usize, 0usize);
+    for op in cigar {
+        let len = op.len() as usize;
+        match op.op() {
+            'M' => {
+                for i in 0..len {
+                    let same = match (target_seq.get(target_pos + i), query_seq.get(query_pos + i)) {
+                        (Some(t), Some(q)) => t.eq_ignore_ascii_case(q),
+                        _ => false,
+                    };
+                    push(1, if same { '=' } else { 'X' });
                 }
+                target_pos += len;
+                query_pos += len;
+            },
+            '=' | 'X' => {
+                push(len as i32, op.op());
+                target_pos += len;
+                query_pos += len;
+            },
+            'I' => {
+                push(len as i32, 'I');
+                query_pos += len;
+            },
+            'D' | 'N' => {
+                push(len as i32, op.op());
+                target_pos += len;
+            },
+            'P' => push(len as i32, 'P'),
+            op => panic!("Invalid CIGAR operation: {}", op),
+        }
+    }
+    refined.into_iter().map(|(len, op)| CigarOp::new(len, op)).collect()
+}
 
-                target_pos += target_delta;
-                query_pos += query_delta;
+/// Split the query side of an alignment into `[start, end)` blocks of aligned bases, in ascending
+/// order. Insertions leave gaps between blocks, deletions end a block without leaving a gap.
+/// `query_first` and `query_last` follow the `AdjustedInterval` convention of `first > last` on the reverse strand.
+pub fn cigar_to_query_blocks(cigar: &[CigarOp], query_first: i32, query_last: i32) -> Vec<(i32, i32)> {
+    let strand = if query_first <= query_last { Strand::Forward } else { Strand::Reverse };
+    let mut blocks = Vec::new();
+    let mut query_pos = query_first;
+    let mut block_start: Option<i32> = None;
+    for op in cigar {
+        match op.op() {
+            '=' | 'X' | 'M' => {
+                block_start.get_or_insert(query_pos);
+            },
+            'P' => {},
+            _ => if let Some(start) = block_start.take() {
+                blocks.push((start.min(query_pos), start.max(query_pos)));
             },
         }
+        query_pos += op.query_delta(strand);
     }
-    
-    (
-        projected_start.unwrap_or(query_start),
-        (projected_end.unwrap_or(query_pos)).min(query_end),
-        projected_cigar,
-        new_target_start.unwrap_or(target_start),
-        (new_target_end.unwrap_or(target_pos)).min(target_end),
-    )
+    if let Some(start) = block_start {
+        blocks.push((start.min(query_pos), start.max(query_pos)));
+    }
+    blocks.sort();
+    blocks
+}
+
+/// The `(query_pos, target_pos)` points where each run of aligned bases begins and ends, walking
+/// the CIGAR from `query_first` and `target_start`. An insertion or deletion between two runs
+/// shows up as a jump in one coordinate from the end of the first to the start of the second.
+/// `query_first` and `query_last` follow the `AdjustedInterval` convention of `first > last` on the reverse strand.
+pub fn cigar_anchor_points(cigar: &[CigarOp], query_first: i32, query_last: i32, target_start: i32) -> Vec<(i32, i32)> {
+    let strand = if query_first <= query_last { Strand::Forward } else { Strand::Reverse };
+    let mut points = Vec::new();
+    let (mut query_pos, mut target_pos) = (query_first, target_start);
+    let mut in_run = false;
+    for op in cigar {
+        match op.op() {
+            '=' | 'X' | 'M' => if !in_run {
+                points.push((query_pos, target_pos));
+                in_run = true;
+            },
+            'P' => {},
+            _ => if in_run {
+                points.push((query_pos, target_pos));
+                in_run = false;
+            },
+        }
+        query_pos += op.query_delta(strand);
+        target_pos += op.target_delta();
+    }
+    if in_run {
+        points.push((query_pos, target_pos));
+    }
+    points
 }
 
-fn parse_cigar_to_delta(cigar: &str) -> Result<Vec<CigarOp>, ParseErr> {
+/// Parse a CIGAR string such as `10=1X5I`, the inverse of `cigar_to_str`.
+pub fn cigar_from_str(cigar: &str) -> Result<Vec<CigarOp>, ParseErr> {
     let mut ops = Vec::new();
+    visit_cigar_ops(cigar, |op| ops.push(op))?;
+    Ok(ops)
+}
+
+/// Check that `cigar` parses with `cigar_from_str`, without collecting its operations.
+pub fn validate_cigar_str(cigar: &str) -> Result<(), ParseErr> {
+    visit_cigar_ops(cigar, |_| {})
+}
+
+fn visit_cigar_ops(cigar: &str, mut visit: impl FnMut(CigarOp)) -> Result<(), ParseErr> {
     let mut num_buf = String::new();
 
     for c in cigar.chars() {
@@ -405,18 +1693,25 @@ fn parse_cigar_to_delta(cigar: &str) -> Result<Vec<CigarOp>, ParseErr> {
             let len = num_buf.parse::<i32>().map_err(|_| ParseErr::InvalidCigarFormat)?;
             num_buf.clear(); // Reset the buffer for the next operation
             // raise any error from the cigar op parsing
-            let op = CigarOp::new(len, c);
-            ops.push(op);
+            visit(CigarOp::try_new(len, c)?);
         }
     }
+    if !num_buf.is_empty() {
+        return Err(ParseErr::InvalidCigarFormat);
+    }
 
-    Ok(ops)
+    Ok(())
+}
+
+/// Format CIGAR operations the way PAF `cg:Z:` tags write them.
+pub fn cigar_to_str(cigar: &[CigarOp]) -> String {
+    cigar.iter().map(|op| format!("{}{}", op.len(), op.op())).collect()
 }
 
 fn is_valid_cigar(cigar: &[CigarOp]) -> Result<(), String> {
-    let cigar_str: String = cigar.iter().map(|op| format!("{}{}", op.len(), op.op())).collect();
+    let cigar_str = cigar_to_str(cigar);
 
-    let re = Regex::new(r"^(\d+[MX=ID])+$").unwrap();
+    let re = Regex::new(r"^(\d+[MX=IDNP])+$").unwrap();
     if !re.is_match(&cigar_str) {
         return Err("Invalid format: non-standard or not-yet-supported operations, or formatting errors detected.".to_string());
     }
@@ -448,7 +1743,37 @@ fn parse_cigar(cigar: &[CigarOp]) -> (i32, i32) {
     (query_length, target_length)
 }
 
-pub fn check_intervals(impg: &Impg, results: &Vec<AdjustedInterval>) -> Vec<(String, String)> {
+/// Walk the CIGAR and find the first `(query_pos, target_pos, expected_len, actual_len)` where the
+/// consumed lengths diverge from the stated spans, preferring the query side when both diverge.
+/// Positions are in the coordinates of the respective interval; reverse query intervals count down.
+fn find_divergence(cigar: &[CigarOp], query_range: (i32, i32), target_range: (i32, i32)) -> Option<Divergence> {
+    let expected_query_len = (query_range.1 - query_range.0).abs();
+    let expected_target_len = (target_range.1 - target_range.0).abs();
+    let query_dir = if query_range.0 <= query_range.1 { 1 } else { -1 };
+    let (calc_query_len, calc_target_len) = parse_cigar(cigar);
+    if calc_query_len == expected_query_len && calc_target_len == expected_target_len {
+        return None;
+    }
+    let (expected_len, actual_len) = if calc_query_len != expected_query_len {
+        (expected_query_len, calc_query_len)
+    } else {
+        (expected_target_len, calc_target_len)
+    };
+
+    let (mut query_len, mut target_len) = (0, 0);
+    for op in cigar {
+        let (op_query_len, op_target_len) = parse_cigar(std::slice::from_ref(op));
+        if query_len + op_query_len > expected_query_len || target_len + op_target_len > expected_target_len {
+            break;
+        }
+        query_len += op_query_len;
+        target_len += op_target_len;
+    }
+
+    Some((query_range.0 + query_len * query_dir, target_range.0 + target_len, expected_len, actual_len))
+}
+
+pub fn check_intervals(impg: &Impg, results: &Vec<AdjustedInterval>) -> Vec<(String, String, Option<Divergence>)> {
     let mut invalid = Vec::new();
 
     for (overlap_query, cigar, overlap_target) in results {
@@ -460,7 +1785,7 @@ pub fn check_intervals(impg: &Impg, results: &Vec<AdjustedInterval>) -> Vec<(Str
         let (query_start, query_end) = (overlap_query.first, overlap_query.last);
         let (target_start, target_end) = (overlap_target.first, overlap_target.last);
 
-        let full_cigar: String = cigar.iter().map(|op| format!("{}{}", op.len(), op.op())).collect();
+        let full_cigar = cigar_to_str(cigar);
         let first_chunk_cigar = if full_cigar.len() > 20 {
             format!("{}...", &full_cigar[..20])
         } else {
@@ -477,11 +1802,12 @@ pub fn check_intervals(impg: &Impg, results: &Vec<AdjustedInterval>) -> Vec<(Str
             error_details.push(format!("Target length mismatch: expected {} from the target range [{}-{}), got {} from the CIGAR string", (target_end - target_start).abs(), target_start, target_end, calc_target_len));
         }
 
+        let divergence = find_divergence(cigar, (query_start, query_end), (target_start, target_end));
         match is_valid_cigar(cigar) {
             Ok(()) => {
                 if !error_details.is_empty() {
                     let error_reason = error_details.join("; ");
-                    invalid.push((format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}", query_name, query_len, query_start, query_end, if query_start <= query_end { '+' } else { '-' }, target_name, target_len, target_start, target_end, first_chunk_cigar), error_reason));
+                    invalid.push((format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}", query_name, query_len, query_start, query_end, if query_start <= query_end { '+' } else { '-' }, target_name, target_len, target_start, target_end, first_chunk_cigar), error_reason, divergence));
                 }
             }
             Err(error_msg) => {
@@ -490,7 +1816,7 @@ pub fn check_intervals(impg: &Impg, results: &Vec<AdjustedInterval>) -> Vec<(Str
                 } else {
                     format!("{}; {}", error_msg, error_details.join("; "))
                 };
-                invalid.push((format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}", query_name, query_len, query_start, query_end, if query_start <= query_end { '+' } else { '-' }, target_name, target_len, target_start, target_end, first_chunk_cigar), error_reason));
+                invalid.push((format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}", query_name, query_len, query_start, query_end, if query_start <= query_end { '+' } else { '-' }, target_name, target_len, target_start, target_end, first_chunk_cigar), error_reason, divergence));
             }
         }
     }
@@ -502,7 +1828,579 @@ pub fn check_intervals(impg: &Impg, results: &Vec<AdjustedInterval>) -> Vec<(Str
 mod tests {
     use super::*;
     use std::io::BufReader;
-    use crate::paf::parse_paf;
+    use crate::paf::{parse_paf, parse_paf_with_progress};
+    use crate::test_util::{build_test_impg, TestDir};
+
+
+    // a0 -> b0 -> c0 -> d0, each aligned onto the next one
+    const CHAIN_PAF: &str = "c0\t100\t0\t100\t+\td0\t100\t0\t100\t100\t100\t60\tcg:Z:100=\n\
+                             b0\t100\t0\t100\t+\tc0\t100\t0\t100\t100\t100\t60\tcg:Z:100=\n\
+                             a0\t100\t0\t100\t+\tb0\t100\t0\t100\t100\t100\t60\tcg:Z:100=\n";
+
+    #[test]
+    fn test_query_transitive_with_mapq() {
+        let paf = "c0\t100\t0\t100\t+\td0\t100\t0\t100\t100\t100\t60\tcg:Z:100=\n\
+                   b0\t100\t0\t100\t+\tc0\t100\t0\t100\t100\t100\t20\tcg:Z:100=\n\
+                   a0\t100\t0\t100\t+\tb0\t100\t0\t100\t100\t100\t40\tcg:Z:100=\n";
+        let impg = build_test_impg("mapq", paf);
+        let target_id = impg.seq_index.get_id("d0").unwrap();
+        let mapqs = |results: Vec<(AdjustedInterval, u8)>| -> Vec<(String, u8)> {
+            let mut mapqs: Vec<_> = results.iter().map(|((query, _, _), mapq)| (impg.seq_index.get_name(query.metadata).unwrap().to_string(), *mapq)).collect();
+            mapqs.sort();
+            mapqs
+        };
+
+        assert_eq!(mapqs(impg.query_with_mapq(target_id, 0, 100).unwrap()), vec![("c0".to_string(), 60), ("d0".to_string(), 255)]);
+        // a0 is reached through the MAPQ 20 record, so it keeps the lowest value along the chain
+        assert_eq!(mapqs(impg.query_transitive_with_mapq(target_id, 0, 100, None, None).unwrap()), vec![
+            ("a0".to_string(), 20), ("b0".to_string(), 20), ("c0".to_string(), 60), ("d0".to_string(), 255),
+        ]);
+    }
+
+    #[test]
+    fn test_query_unreadable_cigar() {
+        let impg = build_test_impg("unreadable_cigar", "b\t100\t0\t20\t+\ta\t100\t10\t30\t20\t20\t60\tcg:Z:20=\n");
+        let a = impg.seq_index.get_id("a").unwrap();
+        assert_eq!(impg.query(a, 0, 100).unwrap().len(), 2);
+
+        // The CIGAR bytes no longer hold a CIGAR once the PAF file changes
+        std::fs::write(&impg.paf_files[0], "b\t100\t0\t20\t+\ta\t100\t10\t30\t20\t20\t60\tcg:Z:2QQ\n").unwrap();
+        let error = impg.query(a, 0, 100).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("unsupported CIGAR operation"));
+
+        std::fs::remove_file(&impg.paf_files[0]).unwrap();
+        assert_eq!(impg.query_transitive(a, 0, 100, None, None).unwrap_err().kind(), io::ErrorKind::NotFound);
+        let mut iter = impg.query_iter(a, 0, 100);
+        // The input range comes before the first CIGAR is read
+        assert!(iter.next().is_some());
+        assert!(iter.next().is_none());
+        assert!(iter.take_error().is_some());
+    }
+
+    #[test]
+    fn test_input_range_is_first_result() {
+        let impg = build_test_impg("input_first", CHAIN_PAF);
+        let target_id = impg.seq_index.get_id("c0").unwrap();
+        for results in [impg.query(target_id, 10, 60).unwrap(), impg.query_transitive(target_id, 10, 60, None, None).unwrap()] {
+            let (query, cigar, target) = &results[0];
+            assert_eq!((query.metadata, query.first, query.last), (target_id, 10, 60));
+            assert_eq!((target.metadata, target.first, target.last), (target_id, 10, 60));
+            assert_eq!(cigar, &vec![CigarOp::new(50, '=')]);
+            assert!(results.len() > 1);
+        }
+    }
+
+    #[test]
+    fn test_query_transitive_max_depth() {
+        let impg = build_test_impg("max_depth", CHAIN_PAF);
+        let target_id = impg.seq_index.get_id("d0").unwrap();
+        let names = |results: Vec<AdjustedInterval>| -> Vec<String> {
+            let mut names: Vec<String> = results.iter().map(|(query, _, _)| impg.seq_index.get_name(query.metadata).unwrap().to_string()).collect();
+            names.sort();
+            names
+        };
+
+        assert_eq!(names(impg.query_transitive(target_id, 0, 100, Some(0), None).unwrap()), names(impg.query(target_id, 0, 100).unwrap()));
+        assert_eq!(names(impg.query_transitive(target_id, 0, 100, Some(1), None).unwrap()), vec!["b0", "c0", "d0"]);
+        assert_eq!(names(impg.query_transitive(target_id, 0, 100, None, None).unwrap()), vec!["a0", "b0", "c0", "d0"]);
+    }
+
+    #[test]
+    fn test_query_transitive_with_deadline() {
+        let impg = build_test_impg("deadline", CHAIN_PAF);
+        let target_id = impg.seq_index.get_id("d0").unwrap();
+
+        // Only the input range is returned once the deadline has passed
+        let (results, timed_out) = impg.query_transitive_with_deadline(target_id, 0, 100, None, None, Instant::now()).unwrap();
+        assert!(timed_out);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0.0.metadata, target_id);
+
+        let deadline = Instant::now() + std::time::Duration::from_secs(3600);
+        let (results, timed_out) = impg.query_transitive_with_deadline(target_id, 0, 100, None, None, deadline).unwrap();
+        assert!(!timed_out);
+        assert_eq!(results.len(), impg.query_transitive(target_id, 0, 100, None, None).unwrap().len());
+    }
+
+    #[test]
+    fn test_expansion_rounds() {
+        // a and b align to t, and a to b as well
+        let paf_data = "a\t100\t0\t100\t+\tt\t100\t0\t100\t100\t100\t60\n\
+                        b\t100\t0\t100\t+\tt\t100\t0\t100\t100\t100\t60\n\
+                        a\t100\t0\t100\t+\tb\t100\t0\t100\t100\t100\t60\n";
+        let records = parse_paf(BufReader::new(paf_data.as_bytes())).unwrap();
+        let mut impg = Impg::from_paf_records(&records, "unused.paf", false, None).unwrap();
+        impg.has_cigars = false;
+        let t = impg.seq_index.get_id("t").unwrap();
+        let mut iter = impg.query_transitive_iter(t, 0, 100, None, None);
+        let total = iter.by_ref().count();
+
+        // Reaching a again from b doesn't query it twice
+        assert_eq!(iter.rounds(), [
+            ExpansionRound { queried: 1, results: 2, deduplicated: 0, dropped: 0, sequences: 3 },
+            ExpansionRound { queried: 2, results: 1, deduplicated: 1, dropped: 0, sequences: 3 },
+        ]);
+        // Every result beyond the input range comes from a round
+        assert_eq!(total, 1 + iter.rounds().iter().map(|round| round.results).sum::<usize>());
+
+        let mut iter = impg.query_transitive_iter(t, 0, 100, None, Some(2));
+        iter.by_ref().count();
+        assert_eq!(iter.rounds()[0], ExpansionRound { queried: 1, results: 1, deduplicated: 0, dropped: 1, sequences: 2 });
+    }
+
+    #[test]
+    fn test_trace() {
+        let first_line = "a\t100\t0\t100\t+\tt\t100\t0\t100\t100\t100\t60\n";
+        let paf_data = format!("{}b\t100\t0\t100\t+\ta\t100\t0\t100\t100\t100\t60\n", first_line);
+        let mut impg = build_test_impg("trace", &paf_data);
+        impg.has_cigars = false;
+        let t = impg.seq_index.get_id("t").unwrap();
+        let traces = |iter: QueryIter| {
+            let mut iter = iter;
+            std::iter::from_fn(|| iter.next_traced())
+                .map(|((query, _, _), _, trace)| (impg.seq_index.get_name(query.metadata).unwrap().to_string(), trace))
+                .collect::<Vec<_>>()
+        };
+
+        // A one-hop result traces to its own record, a transitive one to the whole chain
+        let second_offset = first_line.len() as u64;
+        assert_eq!(traces(impg.query_iter(t, 0, 100).with_trace()), vec![("t".to_string(), vec![]), ("a".to_string(), vec![(0, 0)])]);
+        assert_eq!(traces(impg.query_transitive_iter(t, 0, 100, None, None).with_trace()).last().unwrap(), &("b".to_string(), vec![(0, 0), (0, second_offset)]));
+        assert!(traces(impg.query_transitive_iter(t, 0, 100, None, None)).iter().all(|(_, trace)| trace.is_empty()));
+    }
+
+    #[test]
+    fn test_coordinates_only() {
+        let paf_data = "a\t100\t0\t100\t+\tt\t100\t0\t98\t95\t100\t60\tcg:Z:10=2D30=5I40=3X10=\n\
+                        b\t100\t0\t100\t-\ta\t100\t0\t100\t100\t100\t60\tcg:Z:100=\n";
+        let impg = build_test_impg("coordinates_only", paf_data);
+        let t = impg.seq_index.get_id("t").unwrap();
+        let coordinates = |results: Vec<AdjustedInterval>| results.into_iter()
+            .map(|(query, _, target)| (query.metadata, query.first, query.last, target.metadata, target.first, target.last))
+            .collect::<Vec<_>>();
+
+        let with_cigars: Vec<_> = impg.query_transitive_iter(t, 5, 60, None, None).collect();
+        let without_cigars: Vec<_> = impg.query_transitive_iter(t, 5, 60, None, None).coordinates_only().collect();
+        assert_eq!(with_cigars.len(), 3);
+        assert!(without_cigars.iter().all(|(_, cigar, _)| cigar.is_empty()));
+        assert_eq!(coordinates(without_cigars), coordinates(with_cigars));
+    }
+
+    #[test]
+    fn test_point_query() {
+        let paf_data = "a\t100\t0\t50\t+\tt\t200\t50\t100\t50\t50\t60\tcg:Z:50=\n\
+                        b\t100\t0\t50\t-\tt\t200\t100\t150\t50\t50\t60\tcg:Z:50=\n";
+        let impg = build_test_impg("point_query", paf_data);
+        let t = impg.seq_index.get_id("t").unwrap();
+        let spans = |results: Vec<AdjustedInterval>| results.iter()
+            .map(|(query, _, target)| (impg.seq_index.get_name(query.metadata).unwrap(), query.first, query.last, target.first, target.last))
+            .collect::<Vec<_>>();
+
+        // Position 100 is the first base of b's alignment, just past the end of a's
+        assert_eq!(spans(impg.query(t, 100, 100).unwrap()), vec![("t", 100, 101, 100, 101), ("b", 50, 49, 100, 101)]);
+        assert_eq!(spans(impg.query_transitive(t, 99, 99, None, None).unwrap()), vec![("t", 99, 100, 99, 100), ("a", 49, 50, 99, 100)]);
+    }
+
+    #[test]
+    fn test_max_overlap_depth() {
+        // b nested in a, c nested in b, d only touching a
+        let paf_data = "a\t100\t0\t100\t+\tt\t200\t0\t100\t100\t100\t60\n\
+                        b\t100\t0\t50\t+\tt\t200\t20\t70\t50\t50\t60\n\
+                        c\t100\t0\t10\t+\tt\t200\t30\t40\t10\t10\t60\n\
+                        d\t100\t0\t100\t+\tt\t200\t100\t200\t100\t100\t60\n";
+        let records = parse_paf(BufReader::new(paf_data.as_bytes())).unwrap();
+        let impg = Impg::from_paf_records(&records, "unused.paf", false, None).unwrap();
+        assert_eq!(impg.max_overlap_depth(impg.seq_index.get_id("t").unwrap()), 3);
+        assert_eq!(impg.max_overlap_depth(impg.seq_index.get_id("a").unwrap()), 0);
+    }
+
+    #[test]
+    fn test_canonical_seq_order() {
+        let forward = "b\t100\t0\t100\t+\tc\t100\t0\t100\t100\t100\t60\n\
+                       a\t100\t0\t100\t+\tb\t100\t0\t100\t100\t100\t60\n";
+        let reversed = "a\t100\t0\t100\t+\tb\t100\t0\t100\t100\t100\t60\n\
+                        b\t100\t0\t100\t+\tc\t100\t0\t100\t100\t100\t60\n";
+        let build = |paf_data: &str, canonical: bool| {
+            let records = parse_paf(BufReader::new(paf_data.as_bytes())).unwrap();
+            Impg::from_paf_records(&records, "unused.paf", canonical, None).unwrap()
+        };
+
+        let (impg1, impg2) = (build(forward, true), build(reversed, true));
+        for (id, name) in ["a", "b", "c"].iter().enumerate() {
+            assert_eq!(impg1.seq_index.get_id(name), Some(id as u32));
+        }
+        assert_eq!(bincode::serialize(&impg1.seq_index).unwrap(), bincode::serialize(&impg2.seq_index).unwrap());
+
+        // First-seen order otherwise
+        assert_eq!(build(forward, false).seq_index.get_id("b"), Some(0));
+    }
+
+    #[test]
+    fn test_multi_paf_index() {
+        let paf_data = [
+            "b\t100\t0\t20\t+\ta\t100\t10\t30\t20\t20\t60\tcg:Z:20=\n",
+            "c\t50\t5\t25\t-\ta\t100\t20\t40\t20\t20\t60\tcg:Z:10=1X9=\n",
+        ];
+        let dir = TestDir::new("multi");
+        let files: Vec<(String, Vec<PafRecord>)> = paf_data.iter().enumerate().map(|(i, data)| {
+            (dir.write(&format!("{}.paf", i), data), parse_paf(BufReader::new(data.as_bytes())).unwrap())
+        }).collect();
+        let records_by_file: Vec<(&[PafRecord], &str)> = files.iter().map(|(paf_file, records)| (records.as_slice(), paf_file.as_str())).collect();
+        let impg = Impg::from_multi_paf_records(&records_by_file, false, None).unwrap();
+
+        let a = impg.seq_index.get_id("a").unwrap();
+        let results: Vec<_> = impg.query(a, 0, 100).unwrap().into_iter()
+            .map(|(query, cigar, _)| (impg.seq_index.get_name(query.metadata).unwrap(), query.first, query.last, cigar.len()))
+            .collect();
+        assert_eq!(results, vec![("a", 0, 100, 1), ("b", 0, 20, 1), ("c", 25, 5, 3)]);
+
+        // The same sequence with another length in the second file
+        let conflicting = parse_paf(BufReader::new("b\t90\t0\t20\t+\ta\t100\t10\t30\t20\t20\t60\tcg:Z:20=\n".as_bytes())).unwrap();
+        let result = Impg::from_multi_paf_records(&[(records_by_file[0].0, "x.paf"), (&conflicting, "y.paf")], false, None);
+        assert!(matches!(result, Err(ParseErr::InvalidFormat(message)) if message.contains("'b'")));
+    }
+
+    #[test]
+    fn test_index_independent_of_thread_count() {
+        // Every line twice, as records that only differ in their file offsets
+        let paf_data = (0..200)
+            .map(|i| format!("q{}\t1000\t{}\t{}\t{}\tt{}\t1000\t{}\t{}\t50\t50\t60\tcg:Z:50=\n", i % 7, i, i + 50, if i % 3 == 0 { '-' } else { '+' }, i % 5, i % 11, i % 11 + 50))
+            .collect::<String>()
+            .repeat(2);
+        let serialized = |num_threads: usize| {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(num_threads).build().unwrap();
+            pool.install(|| {
+                let records = parse_paf(BufReader::new(paf_data.as_bytes())).unwrap();
+                let mut bytes = Vec::new();
+                Impg::from_paf_records(&records, "unused.paf", false, None).unwrap().to_serializable().serialize_into(&mut bytes).unwrap();
+                bytes
+            })
+        };
+        assert_eq!(serialized(1), serialized(4));
+    }
+
+    #[test]
+    fn test_trees_by_id() {
+        let paf_data: String = (0..20).map(|i| format!("q\t100\t0\t10\t+\tt{}\t100\t0\t10\t10\t10\t60\n", i)).collect();
+        let build = || {
+            let records = parse_paf(BufReader::new(paf_data.as_bytes())).unwrap();
+            Impg::from_paf_records(&records, "unused.paf", false, None).unwrap()
+        };
+        let target_ids = |impg: &Impg| impg.trees_by_id().into_iter().map(|(target_id, _)| target_id).collect::<Vec<_>>();
+        let (first, second) = (build(), build());
+        assert_eq!(target_ids(&first), (1..=20).collect::<Vec<u32>>());
+        assert_eq!(target_ids(&first), target_ids(&second));
+    }
+
+    #[test]
+    fn test_build_mem_limit() {
+        let paf_data: String = (0..200)
+            .map(|i| format!("q{}\t1000\t{}\t{}\t{}\tt{}\t1000\t{}\t{}\t50\t50\t60\tcg:Z:50=\n", i % 7, i, i + 50, if i % 3 == 0 { '-' } else { '+' }, i % 5, i % 11, i % 11 + 50))
+            .collect();
+        let records = parse_paf(BufReader::new(paf_data.as_bytes())).unwrap();
+        let serialized = |build_mem_limit: Option<usize>| {
+            let impg = Impg::from_multi_paf_records_with_mem_limit(&[(&records, "unused.paf")], false, Some(SelfAlignmentFilter::All), build_mem_limit).unwrap();
+            let mut bytes = Vec::new();
+            impg.to_serializable().serialize_into(&mut bytes).unwrap();
+            bytes
+        };
+        let in_memory = serialized(None);
+        // Spill after every few records, and after every one
+        assert_eq!(serialized(Some(1000)), in_memory);
+        assert_eq!(serialized(Some(1)), in_memory);
+    }
+
+    #[test]
+    fn test_validate_records() {
+        let paf_data = "b\t100\t0\t20\t+\ta\t100\t10\t30\t20\t20\t60\tcg:Z:20=\n\
+                        c\t50\t5\t25\t-\ta\t100\t20\t40\t20\t20\t60\tcg:Z:10=1X9=\n\
+                        c\t50\t0\t10\t+\tb\t100\t50\t60\t10\t10\t60\tcg:Z:10=\n";
+        let records = parse_paf(BufReader::new(paf_data.as_bytes())).unwrap();
+        let impg = Impg::from_paf_records(&records, "validate.paf", false, None).unwrap();
+        assert_eq!(impg.validate_records(records.iter(), None), Ok((3, 3)));
+
+        // An index missing the last records of its PAF
+        let truncated = Impg::from_paf_records(&records[..1], "validate.paf", false, None).unwrap();
+        assert_eq!(truncated.validate_records(records.iter(), None), Err(IndexDivergence::MissingSequence("c".to_string())));
+        let truncated = Impg::from_paf_records(&records[..2], "validate.paf", false, None).unwrap();
+        assert_eq!(truncated.validate_records(records.iter(), None).unwrap_err().to_string(),
+                   "'b' has 0 intervals in the index but 1 alignments in the PAF");
+    }
+
+    #[test]
+    fn test_initialize_thread_pool_twice() {
+        initialize_thread_pool(2);
+        // The pool is set up by now, so a second call keeps it instead of panicking
+        assert!(!initialize_thread_pool(3));
+        assert!(rayon::current_num_threads() >= 1);
+    }
+
+    #[test]
+    fn test_optional_fields() {
+        let paf_data = "b\t100\t0\t20\t+\ta\t100\t10\t30\t20\t20\t60\ttp:A:P\tcg:Z:20=\tde:f:0.01\n\
+                        c\t50\t5\t25\t-\ta\t100\t20\t40\t20\t20\t60\tcg:Z:10=1X9=\n\
+                        d\t50\t0\t10\t+\ta\t100\t50\t60\t10\t10\t60\tAS:i:10\n";
+        let impg = build_test_impg("optional_fields", paf_data);
+        let a = impg.seq_index.get_id("a").unwrap();
+        let fields: Vec<_> = impg.query(a, 0, 100).unwrap().iter().skip(1)
+            .map(|result| impg.optional_fields(&impg.source_alignment(result).unwrap()).unwrap())
+            .collect();
+        assert_eq!(fields, vec![vec!["tp:A:P".to_string(), "de:f:0.01".to_string()], vec![], vec!["AS:i:10".to_string()]]);
+
+        // The input range has no alignment
+        assert!(impg.source_alignment(&impg.query(a, 0, 100).unwrap()[0]).is_none());
+    }
+
+    #[test]
+    fn test_append_paf_records() {
+        let initial = "b\t100\t0\t20\t+\ta\t100\t10\t30\t20\t20\t60\tcg:Z:20=\n\
+                       c\t50\t5\t25\t-\ta\t100\t20\t40\t20\t20\t60\tcg:Z:10=1X9=\n";
+        let appended = "d\t80\t0\t10\t+\ta\t100\t0\t10\t10\t10\t60\tcg:Z:5=1I4=\n\
+                        e\t30\t0\t10\t+\tb\t100\t50\t60\t10\t10\t60\tcg:Z:10=\n";
+        let mut impg = build_test_impg("append", initial);
+        let paf_file = impg.paf_files[0].clone();
+        let paf_file = paf_file.as_str();
+        let fingerprint = PafFingerprint::of_file(paf_file).unwrap();
+
+        std::fs::write(paf_file, format!("{}{}", initial, appended)).unwrap();
+        assert_eq!(PafFingerprint::of_prefix(paf_file, fingerprint.size).unwrap(), fingerprint);
+        let records = parse_paf_with_progress(BufReader::new(appended.as_bytes()), fingerprint.size, false, true, usize::MAX, |_, _| {}).unwrap();
+        impg.append_paf_records(&records, 0, None).unwrap();
+
+        let entries: usize = impg.trees.keys().map(|&target_id| impg.tree_entries(target_id).len()).sum();
+        assert_eq!(entries, 4);
+        let a = impg.seq_index.get_id("a").unwrap();
+        let results: Vec<_> = impg.query(a, 0, 100).unwrap().into_iter()
+            .map(|(query, cigar, _)| (impg.seq_index.get_name(query.metadata).unwrap(), query.first, query.last, cigar.len()))
+            .collect();
+        assert_eq!(results, vec![("a", 0, 100, 1), ("d", 0, 10, 3), ("b", 0, 20, 1), ("c", 25, 5, 3)]);
+        let b = impg.seq_index.get_id("b").unwrap();
+        assert_eq!(impg.query(b, 0, 100).unwrap()[1].1, vec![CigarOp::new(10, '=')]);
+
+        // Rewriting the indexed part changes its fingerprint
+        std::fs::write(paf_file, initial.replace("60\tcg", "50\tcg")).unwrap();
+        assert_ne!(PafFingerprint::of_prefix(paf_file, fingerprint.size).unwrap(), fingerprint);
+    }
+
+    #[test]
+    fn test_no_cigar_index() {
+        let paf_data = "b\t100\t0\t20\t+\ta\t100\t10\t30\t20\t20\t60\tcg:Z:5=1X14=\n\
+                        c\t50\t10\t30\t-\ta\t100\t20\t40\t20\t20\t60\tcg:Z:20=\n";
+        let mut impg = build_test_impg("no_cigar", paf_data);
+        let a = impg.seq_index.get_id("a").unwrap();
+        let coordinates = |impg: &Impg| impg.query(a, 15, 35).unwrap().into_iter()
+            .map(|(query, _, target)| (query.metadata, query.first, query.last, target.first, target.last))
+            .collect::<Vec<_>>();
+        let with_cigars = coordinates(&impg);
+        let serialized = |impg: &Impg| {
+            let mut bytes = Vec::new();
+            impg.to_serializable().serialize_into(&mut bytes).unwrap();
+            bytes
+        };
+        let full_size = serialized(&impg).len();
+
+        impg.has_cigars = false;
+        let bytes = serialized(&impg);
+        assert!(bytes.len() < full_size);
+        let loaded = Impg::from_paf_and_serializable(&impg.paf_files, SerializableImpg::deserialize_from(bytes.as_slice()).unwrap());
+        assert!(!loaded.has_cigars);
+        assert_eq!(coordinates(&loaded), with_cigars);
+        assert!(loaded.query(a, 15, 35).unwrap().iter().skip(1).all(|(_, cigar, _)| cigar.is_empty()));
+    }
+
+    #[test]
+    fn test_invert_cigar() {
+        let cigar = vec![CigarOp::new(5, '='), CigarOp::new(2, 'D'), CigarOp::new(3, 'I'), CigarOp::new(1, 'X'), CigarOp::new(4, 'M')];
+        let forward = invert_cigar(&cigar, Strand::Forward);
+        assert_eq!(forward, vec![CigarOp::new(5, '='), CigarOp::new(2, 'I'), CigarOp::new(3, 'D'), CigarOp::new(1, 'X'), CigarOp::new(4, 'M')]);
+        let reverse = invert_cigar(&cigar, Strand::Reverse);
+        assert_eq!(reverse, vec![CigarOp::new(4, 'M'), CigarOp::new(1, 'X'), CigarOp::new(3, 'D'), CigarOp::new(2, 'I'), CigarOp::new(5, '=')]);
+
+        assert_eq!(invert_cigar(&forward, Strand::Forward), cigar);
+        assert_eq!(invert_cigar(&reverse, Strand::Reverse), cigar);
+    }
+
+    #[test]
+    fn test_lift_position() {
+        // b[0..5) = a[10..15), a[15..17) deleted from b, b[5..8) inserted, b[8..18) = a[17..27)
+        let paf_data = "b\t100\t0\t18\t+\ta\t100\t10\t27\t15\t20\t60\tcg:Z:5=2D3I10=\n\
+                        c\t100\t0\t18\t-\ta\t100\t10\t27\t15\t20\t60\tcg:Z:5=2D3I10=\n";
+        let impg = build_test_impg("lift", paf_data);
+        let a = impg.seq_index.get_id("a").unwrap();
+        let lift = |pos: i32| {
+            impg.query(a, pos, pos + 1).unwrap().iter().skip(1)
+                .map(|result| (impg.seq_index.get_name(result.0.metadata).unwrap(), lift_position(result, pos)))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(lift(12), vec![("b", Some(LiftedPosition::Aligned(2))), ("c", Some(LiftedPosition::Aligned(15)))]);
+        assert_eq!(lift(16), vec![("b", Some(LiftedPosition::Gap(5))), ("c", Some(LiftedPosition::Gap(13)))]);
+        assert_eq!(lift(17), vec![("b", Some(LiftedPosition::Aligned(8))), ("c", Some(LiftedPosition::Aligned(9)))]);
+        assert_eq!(lift(26), vec![("b", Some(LiftedPosition::Aligned(17))), ("c", Some(LiftedPosition::Aligned(0)))]);
+        assert!(lift(30).is_empty());
+    }
+
+    #[test]
+    fn test_zstd_paf() {
+        let paf_data = "b\t100\t0\t20\t+\ta\t100\t10\t30\t20\t20\t60\tcg:Z:20=\n\
+                        c\t50\t5\t25\t-\ta\t100\t20\t40\t20\t20\t60\tcg:Z:10=1X9=\n";
+        let plain = build_test_impg("zstd_plain", paf_data);
+        let zst_file = plain.dir.write("zstd.paf.zst", zstd::encode_all(paf_data.as_bytes(), 0).unwrap());
+        let records = parse_paf(BufReader::new(zstd::Decoder::new(File::open(&zst_file).unwrap()).unwrap())).unwrap();
+        let mut compressed = Impg::from_paf_records(&records, &zst_file, false, None).unwrap();
+
+        // The CIGARs of a zstd-compressed file can't be read at their offsets
+        let a = plain.seq_index.get_id("a").unwrap();
+        assert_eq!(compressed.query(a, 0, 100).unwrap_err().kind(), io::ErrorKind::Unsupported);
+
+        let mut plain = plain;
+        plain.has_cigars = false;
+        compressed.has_cigars = false;
+        let flatten = |impg: &Impg| impg.query(a, 0, 100).unwrap().into_iter()
+            .map(|(query, cigar, target)| (query.metadata, query.first, query.last, cigar, target.first, target.last))
+            .collect::<Vec<_>>();
+        assert_eq!(flatten(&plain).len(), 3);
+        assert_eq!(flatten(&plain), flatten(&compressed));
+    }
+
+    #[test]
+    fn test_coordinates_beyond_i32() {
+        let paf_data = "q\t100\t0\t100\t+\tt\t3000000000\t2999999900\t3000000000\t100\t100\t60\tcg:Z:100=\n";
+        let records = parse_paf(BufReader::new(paf_data.as_bytes())).unwrap();
+        let result = Impg::from_paf_records(&records, "unused.paf", false, None);
+        assert!(matches!(result, Err(ParseErr::InvalidFormat(message)) if message.contains("2147483647")));
+
+        let at_limit = "q\t100\t0\t100\t+\tt\t2147483647\t2147483547\t2147483647\t100\t100\t60\tcg:Z:100=\n";
+        let records = parse_paf(BufReader::new(at_limit.as_bytes())).unwrap();
+        let impg = Impg::from_paf_records(&records, "unused.paf", false, None).unwrap();
+        let t = impg.seq_index.get_id("t").unwrap();
+        assert_eq!(impg.tree_entries(t).len(), 1);
+    }
+
+    #[test]
+    fn test_tree_entries() {
+        let paf = "b\t100\t40\t60\t-\ta\t100\t50\t70\t20\t20\t60\tcg:Z:20=\n\
+                   c\t100\t10\t30\t+\ta\t100\t0\t20\t20\t20\t60\tcg:Z:20=\n";
+        let impg = build_test_impg("tree_entries", paf);
+        let a = impg.seq_index.get_id("a").unwrap();
+        let entries: Vec<_> = impg.tree_entries(a).into_iter()
+            .map(|(first, last, metadata)| (first, last, impg.seq_index.get_name(metadata.query_id()).unwrap(), metadata.query_start(), metadata.query_end(), metadata.strand()))
+            .collect();
+        assert_eq!(entries, vec![(0, 20, "c", 10, 30, Strand::Forward), (50, 70, "b", 40, 60, Strand::Reverse)]);
+        assert!(impg.tree_entries(impg.seq_index.get_id("b").unwrap()).is_empty());
+    }
+
+    #[test]
+    fn test_sequence_lengths() {
+        let paf_data = "q\t250\t0\t100\t+\tt\t1000\t0\t100\t100\t100\t60\n";
+        let records = parse_paf(BufReader::new(paf_data.as_bytes())).unwrap();
+        let impg = Impg::from_paf_records(&records, "unused.paf", false, None).unwrap();
+        let len = |name| impg.seq_index.get_len_from_id(impg.seq_index.get_id(name).unwrap());
+
+        assert_eq!(len("q"), Some(250));
+        assert_eq!(len("t"), Some(1000));
+        let mut serialized = Vec::new();
+        impg.to_serializable().serialize_into(&mut serialized).unwrap();
+        let loaded = Impg::from_paf_and_serializable(&["unused.paf".to_string()], SerializableImpg::deserialize_from(&serialized[..]).unwrap());
+        assert_eq!(loaded.seq_index.lengths().sum::<usize>(), 1250);
+    }
+
+    #[test]
+    fn test_index_format_version_mismatch() {
+        // A v1 header followed by data the current loader can't make sense of
+        let mut old_index = bincode::serialize(&1u32).unwrap();
+        old_index.extend_from_slice(&[0xff; 16]);
+        let err = SerializableImpg::deserialize_from(&old_index[..]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("version 1"));
+        assert!(err.to_string().contains("-I"));
+
+        assert!(SerializableImpg::deserialize_from(&[][..]).is_err());
+    }
+
+    #[test]
+    fn test_drop_self_alignments() {
+        let paf_data = "a\t100\t0\t100\t+\ta\t100\t0\t100\t100\t100\t60\n\
+                        a\t100\t0\t50\t+\ta\t100\t50\t100\t50\t50\t60\n\
+                        b\t100\t0\t100\t+\ta\t100\t0\t100\t100\t100\t60\n";
+        let records = parse_paf(BufReader::new(paf_data.as_bytes())).unwrap();
+        let count = |filter| Impg::from_paf_records(&records, "unused.paf", false, filter).unwrap().trees.values().map(|tree| tree.len()).sum::<usize>();
+
+        assert_eq!(count(None), 3);
+        assert_eq!(count(Some(SelfAlignmentFilter::Diagonal)), 2);
+        assert_eq!(count(Some(SelfAlignmentFilter::All)), 1);
+    }
+
+    #[test]
+    fn test_query_iter_matches_vec() {
+        let impg = build_test_impg("query_iter", CHAIN_PAF);
+        let target_id = impg.seq_index.get_id("d0").unwrap();
+        let key = |(query, cigar, target): AdjustedInterval| (query.metadata, query.first, query.last, cigar, target.first, target.last);
+
+        let from_vec: Vec<_> = impg.query(target_id, 10, 90).unwrap().into_iter().map(key).collect();
+        let from_iter: Vec<_> = impg.query_iter(target_id, 10, 90).map(key).collect();
+        assert_eq!(from_vec, from_iter);
+
+        let from_vec: Vec<_> = impg.query_transitive(target_id, 10, 90, None, None).unwrap().into_iter().map(key).collect();
+        let from_iter: Vec<_> = impg.query_transitive_iter(target_id, 10, 90, None, None).map(key).collect();
+        assert_eq!(from_vec, from_iter);
+        assert_eq!(from_iter.len(), 4);
+
+        // Taking the first results must not expand the whole frontier
+        let mut iter = impg.query_transitive_iter(target_id, 10, 90, None, None);
+        assert!(iter.next().is_some());
+        assert!(iter.next().is_some());
+        assert_eq!(iter.depth, 1);
+    }
+
+    #[test]
+    fn test_query_iter_marks_input_row() {
+        let impg = build_test_impg("input_row", "a\t100\t0\t100\t+\ta\t100\t0\t100\t100\t100\t60\tcg:Z:100=\n");
+        let target_id = impg.seq_index.get_id("a").unwrap();
+        let mut iter = impg.query_iter(target_id, 10, 20);
+        let marked: Vec<_> = std::iter::from_fn(|| iter.next_marked())
+            .map(|((query, _, target), mapq, _, input_row)| (query.first, query.last, target.first, target.last, mapq, input_row))
+            .collect();
+        // The identity self-alignment has the coordinates of the input range
+        assert_eq!(marked, vec![(10, 20, 10, 20, 255, true), (10, 20, 10, 20, 60, false)]);
+    }
+
+    #[test]
+    fn test_query_transitive_thread_count_independent() {
+        // Two branches fanning out of r0, each two hops long
+        let paf_data = "a1\t100\t0\t100\t+\tr0\t100\t0\t100\t100\t100\t60\tcg:Z:100=\n\
+                        b1\t100\t0\t100\t-\tr0\t100\t0\t100\t100\t100\t60\tcg:Z:100=\n\
+                        a2\t100\t0\t50\t+\ta1\t100\t20\t70\t50\t50\t60\tcg:Z:50=\n\
+                        b2\t100\t10\t100\t+\tb1\t100\t0\t90\t90\t90\t60\tcg:Z:90=\n";
+        let impg = build_test_impg("threads", paf_data);
+        let target_id = impg.seq_index.get_id("r0").unwrap();
+        let run = |num_threads: usize| {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(num_threads).build().unwrap();
+            pool.install(|| impg.query_transitive(target_id, 0, 100, None, None)).unwrap().into_iter()
+                .map(|(query, cigar, target)| (query.metadata, query.first, query.last, cigar, target.metadata, target.first, target.last))
+                .collect::<Vec<_>>()
+        };
+
+        let serial = run(1);
+        assert_eq!(serial.len(), 5);
+        assert_eq!(serial, run(4));
+    }
+
+    #[test]
+    fn test_query_transitive_max_sequences() {
+        // s1, s2 and s3 all aligned onto the hub
+        let paf_data = "s1\t100\t0\t100\t+\thub\t100\t0\t100\t100\t100\t60\tcg:Z:100=\n\
+                        s2\t100\t0\t100\t+\thub\t100\t0\t100\t100\t100\t60\tcg:Z:100=\n\
+                        s3\t100\t0\t100\t+\thub\t100\t0\t100\t100\t100\t60\tcg:Z:100=\n";
+        let impg = build_test_impg("max_sequences", paf_data);
+        let target_id = impg.seq_index.get_id("hub").unwrap();
+        let distinct = |results: &[AdjustedInterval]| results.iter().map(|(query, _, _)| query.metadata).collect::<HashSet<_>>().len();
+
+        assert_eq!(distinct(&impg.query_transitive(target_id, 0, 100, None, None).unwrap()), 4);
+        assert_eq!(distinct(&impg.query_transitive(target_id, 0, 100, None, Some(2)).unwrap()), 2);
+        assert_eq!(distinct(&impg.query_transitive(target_id, 0, 100, None, Some(1)).unwrap()), 1);
+    }
 
     #[test]
     fn test_project_target_range_through_alignment_forward() {
@@ -524,6 +2422,25 @@ mod tests {
         assert_eq!(result, (100, 0, cigar_ops.clone(), 100, 200));
     }
 
+    #[test]
+    fn test_project_target_range_through_skipped_region() {
+        // 10 aligned bases, a 100bp intron on the target, padding, then 10 more aligned bases
+        let record = (100, 220, 0, 20, Strand::Forward);
+        let cigar_ops = cigar_from_str("10=100N2P10=").unwrap();
+
+        let (query_start, query_end, cigar, target_start, target_end) = project_target_range_through_alignment((205, 215), record, &cigar_ops);
+        assert_eq!((query_start, query_end, target_start, target_end), (10, 15, 205, 215));
+        assert_eq!(cigar, vec![CigarOp::new(5, 'N'), CigarOp::new(5, '=')]);
+
+        // Past the intron, the target coordinates have advanced by its length but the query ones have not
+        let (query_start, query_end, cigar, target_start, target_end) = project_target_range_through_alignment((212, 220), record, &cigar_ops);
+        assert_eq!((query_start, query_end, target_start, target_end), (12, 20, 212, 220));
+        assert_eq!(cigar, vec![CigarOp::new(8, '=')]);
+
+        let reverse = project_target_range_through_alignment((212, 220), (100, 220, 0, 20, Strand::Reverse), &cigar_ops);
+        assert_eq!((reverse.0, reverse.1, reverse.3, reverse.4), (8, 0, 212, 220));
+    }
+
     #[test]
     fn test_project_target_range_through_alignment() {
         let cigar_ops = vec![
@@ -661,22 +2578,249 @@ mod tests {
     }
 
     #[test]
-    fn test_parse_cigar_to_delta_basic() {
+    fn test_find_divergence() {
+        let cigar = vec![CigarOp::new(10, '='), CigarOp::new(5, 'I'), CigarOp::new(10, '=')];
+        assert_eq!(find_divergence(&cigar, (0, 25), (100, 120)), None);
+        // The query span is too short: the last match run overflows it
+        assert_eq!(find_divergence(&cigar, (0, 20), (100, 120)), Some((15, 110, 20, 25)));
+        // The CIGAR ends before the target span does
+        assert_eq!(find_divergence(&cigar, (0, 25), (100, 130)), Some((25, 120, 30, 20)));
+        // Reverse query intervals count down
+        assert_eq!(find_divergence(&cigar, (20, 0), (100, 120)), Some((5, 110, 20, 25)));
+    }
+
+    #[test]
+    fn test_cigar_stats_identity() {
+        // 90 matches, 5 mismatches, one 3bp insertion and one 2bp deletion
+        let cigar = cigar_from_str("40=5X3I50=2D").unwrap();
+        let stats = CigarStats::from_cigar(&cigar);
+        assert_eq!(stats, CigarStats { matches: 90, mismatches: 5, insertions: 1, inserted_bp: 3, deletions: 1, deleted_bp: 2 });
+        assert_eq!(stats.block_len(), 100);
+        assert_eq!(stats.block_identity(), 0.9);
+        assert_eq!(stats.gap_compressed_identity(), 90.0 / 97.0);
+        assert_eq!(stats.edit_distance(), 5 + 3 + 2);
+        assert_eq!(CigarStats::from_cigar(&[]).block_identity(), 0.0);
+    }
+
+    #[test]
+    fn test_clip_to_target_range() {
+        let cigar = cigar_from_str("10=5I10=5D10=").unwrap();
+        let forward = (Interval { first: 0, last: 35, metadata: 1 }, cigar.clone(), Interval { first: 100, last: 135, metadata: 2 });
+        let reverse = (Interval { first: 35, last: 0, metadata: 1 }, cigar, Interval { first: 100, last: 135, metadata: 2 });
+
+        let (query, clipped_cigar, target) = clip_to_target_range(&forward, 105, 128).unwrap();
+        assert_eq!((query.first, query.last, target.first, target.last), (5, 28, 105, 128));
+        assert_eq!(clipped_cigar, cigar_from_str("5=5I10=5D3=").unwrap());
+        assert!(target.first >= 105 && target.last <= 128);
+
+        let (query, _, target) = clip_to_target_range(&reverse, 105, 128).unwrap();
+        assert_eq!((query.first, query.last, target.first, target.last), (30, 7, 105, 128));
+
+        assert!(clip_to_target_range(&forward, 0, 135).is_some());
+        assert!(clip_to_target_range(&forward, 135, 200).is_none());
+    }
+
+    #[test]
+    fn test_merge_adjusted_intervals() {
+        let interval = |first: i32, last: i32, query_id: u32, target_start: i32| {
+            let len = (last - first).abs();
+            (Interval { first, last, metadata: query_id }, vec![CigarOp::new(len, '=')], Interval { first: target_start, last: target_start + len, metadata: 9 })
+        };
+        let results = vec![
+            interval(50, 80, 1, 150),
+            interval(0, 60, 1, 100),
+            interval(90, 100, 1, 190),
+            interval(100, 70, 1, 0),
+            interval(10, 20, 2, 0),
+        ];
+
+        let merged = merge_adjusted_intervals(results.clone(), 0);
+        let spans: Vec<_> = merged.iter().map(|(query, cigar, target)| (query.metadata, query.first, query.last, cigar.len(), target.first, target.last)).collect();
+        assert_eq!(spans, vec![
+            (1, 0, 80, 0, 100, 180),
+            (1, 90, 100, 1, 190, 200),
+            (1, 100, 70, 1, 0, 30),
+            (2, 10, 20, 1, 0, 10),
+        ]);
+
+        // A 10 bp merge distance bridges the gap between 80 and 90
+        let merged = merge_adjusted_intervals(results, 10);
+        let (query, cigar, target) = &merged[0];
+        assert_eq!((query.first, query.last, target.first, target.last), (0, 100, 100, 200));
+        assert!(cigar.is_empty());
+        assert_eq!(merged.len(), 3);
+    }
+
+    #[test]
+    fn test_merge_adjusted_intervals_check_intervals() {
+        let paf_data = "b\t100\t0\t10\t+\ta\t100\t0\t10\t10\t10\t60\tcg:Z:10=\n\
+                        b\t100\t5\t20\t+\ta\t100\t20\t35\t15\t15\t60\tcg:Z:15=\n\
+                        c\t100\t0\t10\t+\ta\t100\t50\t60\t10\t10\t60\tcg:Z:10=\n";
+        let impg = build_test_impg("merge_check", paf_data);
+        let target_id = impg.seq_index.get_id("a").unwrap();
+        let b = impg.seq_index.get_id("b").unwrap();
+        let results: Vec<_> = impg.query(target_id, 0, 100).unwrap().into_iter().filter(|(query, _, _)| query.metadata != target_id).collect();
+        assert!(check_intervals(&impg, &results).is_empty());
+
+        let merged = merge_adjusted_intervals(results, 0);
+        assert_eq!(merged.len(), 2);
+        let (query, cigar, target) = merged.iter().find(|(query, _, _)| query.metadata == b).unwrap();
+        assert_eq!((query.first, query.last, target.first, target.last), (0, 20, 0, 35));
+        // The merged result has no CIGAR rather than one that disagrees with its spans
+        assert!(cigar.is_empty());
+        let with_cigar: Vec<_> = merged.into_iter().filter(|(_, cigar, _)| !cigar.is_empty()).collect();
+        assert_eq!(with_cigar.len(), 1);
+        assert!(check_intervals(&impg, &with_cigar).is_empty());
+    }
+
+    #[test]
+    fn test_merge_ranges() {
+        assert_eq!(merge_ranges(&[(40, 60), (10, 20), (15, 30), (30, 35), (50, 55)]), vec![(10, 35), (40, 60)]);
+        assert_eq!(merge_ranges(&[(5, 5), (70, 80)]), vec![(70, 80)]);
+        assert_eq!(merge_ranges(&[]), vec![]);
+    }
+
+    #[test]
+    fn test_complement_ranges() {
+        assert_eq!(complement_ranges(&[(40, 60), (10, 20), (15, 30)], 0, 100), vec![(0, 10), (30, 40), (60, 100)]);
+        // Fully covered, and not covered at all
+        assert_eq!(complement_ranges(&[(0, 60), (50, 120)], 0, 100), vec![]);
+        assert_eq!(complement_ranges(&[], 0, 100), vec![(0, 100)]);
+        assert_eq!(complement_ranges(&[(30, 30)], 0, 100), vec![(0, 100)]);
+        // Ranges reaching outside the bounds are cut to them
+        assert_eq!(complement_ranges(&[(-5, 10), (90, 200)], 0, 100), vec![(10, 90)]);
+    }
+
+    #[test]
+    fn test_clip_to_query_range() {
+        let cigar = cigar_from_str("10=5I10=5D10=").unwrap();
+        let forward = (Interval { first: 0, last: 35, metadata: 1 }, cigar.clone(), Interval { first: 100, last: 135, metadata: 2 });
+        let (query, clipped_cigar, target) = clip_to_query_range(&forward, 5, 28).unwrap();
+        assert_eq!((query.first, query.last, target.first, target.last), (5, 28, 105, 128));
+        assert_eq!(clipped_cigar, cigar_from_str("5=5I10=5D3=").unwrap());
+
+        // Reverse: query 35 -> 0 while the target goes 100 -> 135
+        let reverse = (Interval { first: 35, last: 0, metadata: 1 }, cigar, Interval { first: 100, last: 135, metadata: 2 });
+        let (query, clipped_cigar, target) = clip_to_query_range(&reverse, 7, 30).unwrap();
+        assert_eq!((query.first, query.last, target.first, target.last), (30, 7, 105, 128));
+        assert_eq!(clipped_cigar, cigar_from_str("5=5I10=5D3=").unwrap());
+
+        assert!(clip_to_query_range(&forward, 35, 50).is_none());
+    }
+
+    #[test]
+    fn test_subtract_query_regions_precise_vs_linear() {
+        // A large insertion in the query: query 10-90 has no target counterpart
+        let interval = (Interval { first: 0, last: 100, metadata: 1 }, cigar_from_str("10=80I10=").unwrap(), Interval { first: 0, last: 20, metadata: 2 });
+        let segments = subtract_query_regions(&interval, &[(0, 95)]);
+        assert_eq!(segments.len(), 1);
+        let (query, cigar, target) = &segments[0];
+        assert_eq!((query.first, query.last, target.first, target.last), (95, 100, 15, 20));
+        assert_eq!(cigar, &vec![CigarOp::new(5, '=')]);
+
+        // Projecting the kept target part linearly would bring back 20bp of the masked insertion
+        let (linear_query_start, linear_query_end, _, _, _) = project_target_range_linearly((15, 20), (0, 20, 0, 100, Strand::Forward));
+        assert_eq!((linear_query_start, linear_query_end), (75, 100));
+
+        // A mask in the middle leaves two segments
+        let segments = subtract_query_regions(&interval, &[(5, 95)]);
+        let spans: Vec<_> = segments.iter().map(|(query, _, target)| (query.first, query.last, target.first, target.last)).collect();
+        assert_eq!(spans, vec![(0, 5, 0, 5), (95, 100, 15, 20)]);
+    }
+
+    #[test]
+    fn test_refine_match_ops() {
+        // Half of the 8M are mismatches
+        let cigar = cigar_from_str("8M2I2=").unwrap();
+        let refined = refine_match_ops(&cigar, b"ACGTACGTAC", b"AgGAaTGAttAC");
+        assert_eq!(refined, cigar_from_str("1=1X1=1X1=1X1=1X2I2=").unwrap());
+        let stats = CigarStats::from_cigar(&refined);
+        assert_eq!((stats.matches, stats.mismatches), (6, 4));
+
+        // Runs merge with neighbouring ops of the same kind
+        let refined = refine_match_ops(&cigar_from_str("2=3M").unwrap(), b"ACGTT", b"ACGAA");
+        assert_eq!(refined, cigar_from_str("3=2X").unwrap());
+    }
+
+    #[test]
+    fn test_cigar_to_query_blocks() {
+        let cigar = cigar_from_str("10=5I3X2D20=").unwrap();
+        let blocks = cigar_to_query_blocks(&cigar, 100, 138);
+        assert_eq!(blocks, vec![(100, 110), (115, 118), (118, 138)]);
+        // Block sizes add up to the query span minus the inserted bases
+        assert_eq!(blocks.iter().map(|(start, end)| end - start).sum::<i32>(), 38 - 5);
+
+        // Reverse intervals walk the query downwards but still report ascending blocks
+        assert_eq!(cigar_to_query_blocks(&cigar, 138, 100), vec![(100, 120), (120, 123), (128, 138)]);
+    }
+
+    #[test]
+    fn test_cigar_anchor_points() {
+        // The insertion jumps the query by 5 bp at target 210, the mismatch stays in the run
+        let cigar = cigar_from_str("10=5I3X7=").unwrap();
+        assert_eq!(cigar_anchor_points(&cigar, 100, 125, 200), vec![(100, 200), (110, 210), (115, 210), (125, 220)]);
+        assert_eq!(cigar_anchor_points(&cigar, 125, 100, 200), vec![(125, 200), (115, 210), (110, 210), (100, 220)]);
+        // Deletions jump the target
+        assert_eq!(cigar_anchor_points(&cigar_from_str("5=2D5=").unwrap(), 0, 10, 0), vec![(0, 0), (5, 5), (5, 7), (10, 12)]);
+    }
+
+    #[test]
+    fn test_cigar_from_str_basic() {
         let cigar = "10=5I5D";
         let cigar_ops = vec![
             CigarOp::new(10, '='),
             CigarOp::new(5, 'I'),
             CigarOp::new(5, 'D'),
         ];
-        let ops = parse_cigar_to_delta(cigar).unwrap();
+        let ops = cigar_from_str(cigar).unwrap();
         assert_eq!(ops, cigar_ops);
     }
 
-    // #[test]
-    // fn test_parse_cigar_to_delta_invalid() {
-    //     let cigar = "10=5Q"; // Q is not a valid CIGAR operation
-    //     assert!(parse_cigar_to_delta(cigar).is_err());
-    // }
+    #[test]
+    fn test_cigar_str_roundtrip() {
+        // Pseudo-random valid CIGARs, from a fixed seed
+        let mut state: u64 = 0x2545F4914F6CDD1D;
+        let mut next = |bound: u64| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state % bound
+        };
+        for _ in 0..500 {
+            let mut cigar = String::new();
+            for _ in 0..1 + next(20) {
+                // Now and then a length near the 29-bit limit
+                let max_len = if next(10) == 0 { (1 << 29) - 1 } else { 1000 };
+                cigar.push_str(&format!("{}{}", 1 + next(max_len), ['=', 'X', 'I', 'D', 'M', 'N', 'P'][next(7) as usize]));
+            }
+            let ops = cigar_from_str(&cigar).unwrap();
+            assert_eq!(cigar_to_str(&ops), cigar);
+            assert_eq!(cigar_from_str(&cigar_to_str(&ops)).unwrap(), ops);
+        }
+
+        for invalid in ["10", "=", "10=5", "10=5Q", "5S10=", "-5=", "10=+5X", "536870912=", "99999999999="] {
+            assert!(cigar_from_str(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_cigar_from_str_invalid() {
+        let cigar = "10=5Q"; // Q is not a valid CIGAR operation
+        assert!(matches!(cigar_from_str(cigar), Err(ParseErr::UnsupportedCigarOperation)));
+        assert!(matches!(cigar_from_str("10=5"), Err(ParseErr::InvalidCigarFormat)));
+    }
+
+    #[test]
+    fn test_cigar_from_str_skip_and_padding() {
+        let ops = cigar_from_str("10=100N2P10=").unwrap();
+        assert_eq!(ops.iter().map(|op| (op.op(), op.target_delta(), op.query_delta(Strand::Forward))).collect::<Vec<_>>(),
+                   vec![('=', 10, 10), ('N', 100, 0), ('P', 0, 0), ('=', 10, 10)]);
+        // The skipped region is neither a deletion nor part of the alignment block
+        let stats = CigarStats::from_cigar(&ops);
+        assert_eq!((stats.deletions, stats.block_len()), (0, 20));
+        assert_eq!(parse_cigar(&ops), (20, 120));
+        assert!(is_valid_cigar(&ops).is_ok());
+    }
 
     #[test]
     fn test_parse_paf_valid() {
@@ -692,9 +2836,11 @@ mod tests {
                 target_length: 200,
                 target_start: 30,
                 target_end: 40,
+                record_offset: 0,
                 cigar_offset: 45,
                 cigar_bytes: 3,
                 strand: Strand::Forward,
+                mapq: 255,
             },
             // Add more test records as needed
         ];
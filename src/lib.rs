@@ -2,3 +2,10 @@
 pub mod impg;
 pub mod seqidx;
 pub mod paf;
+pub mod fasta;
+#[cfg(test)]
+mod test_util;
+#[cfg(test)]
+use crate::impg::Impg;
+#[cfg(feature = "python")]
+pub mod python;
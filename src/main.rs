@@ -1,46 +1,333 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use std::fs::File;
-use std::io::{self, BufReader, BufWriter};
-use std::num::NonZeroUsize;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::num::{IntErrorKind, NonZeroUsize};
+use std::process::ExitCode;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 use noodles::bgzf;
-use impg::impg::{Impg, SerializableImpg, AdjustedInterval, check_intervals};
+use impg::impg::{Impg, SerializableImpg, PafFingerprint, QueryMetadata, AdjustedInterval, CigarOp, CigarStats, cigar_to_str, SelfAlignmentFilter, RecordId, check_intervals, initialize_thread_pool, cigar_to_query_blocks, cigar_anchor_points, clip_to_target_range, merge_adjusted_intervals, refine_match_ops, subtract_query_regions, clip_to_query_range, complement_ranges, merge_ranges, invert_cigar, lift_position, LiftedPosition};
+use impg::fasta::{SequenceFetcher, read_fai_lengths};
+use impg::seqidx::SequenceIndex;
 use coitrees::IntervalTree;
-use impg::paf;
-use rayon::ThreadPoolBuilder;
+use impg::paf::{self, Strand};
 use std::io::BufRead;
+use log::{info, warn};
+use serde::Serialize;
+use rayon::prelude::*;
 
 /// Command-line tool for querying overlaps in PAF files.
 #[derive(Parser, Debug)]
 #[clap(author, version, about)]
 struct Args {
     /// Path to the PAF file. If specified without an index, the tool will look for or generate an associated index file.
-    #[clap(short='p', long, value_parser)]
-    paf_file: Option<String>,
+    /// Repeat, or give a comma-separated list, to index several PAF files together (requires `--index`).
+    /// Files may be bgzip-compressed (`.gz`/`.bgz`, with a `.gzi` index) or zstd-compressed (`.zst`, with `--no-cigar` only).
+    #[clap(short='p', long, value_parser, value_delimiter = ',')]
+    paf_file: Vec<String>,
+
+    /// Path of the index file to load or generate, instead of `{paf_file}.impg`.
+    #[clap(long, value_parser)]
+    index: Option<String>,
 
     /// Force the regeneration of the index, even if it already exists.
     #[clap(short='I', long, action)]
     force_reindex: bool,
 
-    /// Target range in the format `seq_name:start-end`.
+    /// Update an existing index with the records appended to its PAF files since it was built,
+    /// regenerating it if they were modified in any other way.
+    #[clap(long, action, conflicts_with = "force_reindex")]
+    append: bool,
+
+    /// Re-parse the PAF files and check that the index holds the same number of alignments onto
+    /// each sequence, reporting the first mismatch without rebuilding it.
+    #[clap(long, action, conflicts_with_all = ["force_reindex", "append"])]
+    validate_index: bool,
+
+    /// Assign sequence ids in lexicographic name order when building the index, making it independent of PAF line order.
+    #[clap(long, action)]
+    canonical_seq_order: bool,
+
+    /// Skip self-alignments when building the index: `diagonal` drops forward records of a sequence onto itself
+    /// with identical query and target coordinates, `all` drops every record whose query and target names match.
+    #[clap(long, value_enum)]
+    drop_self_alignments: Option<SelfAlignmentFilter>,
+
+    /// Build the index without the location of each CIGAR, making it smaller. Results are then
+    /// projected linearly through the alignments, and only BED and BEDPE output is available.
+    #[clap(long, action)]
+    no_cigar: bool,
+
+    /// Memory in MB that the intervals waiting to be built into trees may take while indexing,
+    /// beyond which they are moved to temporary files and read back one sequence at a time. This
+    /// doesn't cap peak memory: all parsed PAF records, with their sequence names, stay in memory
+    /// during the build, and so do all finished trees until the index is written.
+    #[clap(long, value_name = "MB", value_parser)]
+    build_mem_limit: Option<NonZeroUsize>,
+
+    /// samtools FASTA index whose sequence lengths the PAF records must match when indexing.
+    #[clap(long, value_name = "FILE", value_parser)]
+    fai: Option<String>,
+
+    /// Fail on unmapped PAF records, with `*` as query name, target name or CIGAR, and on records
+    /// without a `cg:Z:` CIGAR unless indexing with `--no-cigar`, instead of skipping them.
+    #[clap(long, action)]
+    strict: bool,
+
+    /// Leave PAF records with a mapping quality below this out of the index.
+    #[clap(long, value_parser)]
+    min_mapq: Option<u8>,
+
+    /// Target range in the format `seq_name:start-end`, or just `seq_name` for the whole sequence.
+    /// Coordinates are 0-based and half-open like BED.
     #[clap(short='r', long, value_parser)]
     target_range: Option<String>,
 
+    /// Treat the end of `--target-range` as inclusive (e.g. when pasting 1-based samtools regions).
+    #[clap(long, action)]
+    end_inclusive: bool,
+
     /// Path to the BED file containing target regions.
     #[clap(short='b', long, value_parser)]
     target_bed: Option<String>,
 
+    /// Target region in the `--target-range` format, queried like a line of `--target-bed`.
+    /// Can be given several times, and the regions are queried before those of `--target-bed`.
+    #[clap(long = "region", value_parser, conflicts_with = "target_range")]
+    regions: Vec<String>,
+
+    /// Skip BED regions that can't be queried (unknown sequence, out of bounds) instead of aborting.
+    #[clap(long, action)]
+    skip_invalid: bool,
+
+    /// Lift the single base at `seq_name:pos` (0-based) onto the sequences directly aligned to it,
+    /// reporting `seq_name`, position, strand and whether the base is `aligned` or falls in a `gap`.
+    #[clap(long, value_parser, conflicts_with_all = ["target_range", "target_bed", "regions", "transitive"])]
+    liftover: Option<String>,
+
+    /// Only lift over onto sequences whose name starts with this prefix.
+    #[clap(long, value_parser, requires = "liftover")]
+    to: Option<String>,
+
     /// Enable transitive overlap requests.
     #[clap(short='x', long, action)]
     transitive: bool,
 
-    /// Output results in PAF format.
+    /// Maximum number of transitive expansion rounds (0 behaves like a non-transitive query; unbounded by default).
+    #[clap(long, value_parser)]
+    max_depth: Option<usize>,
+
+    /// Maximum number of distinct sequences a transitive query may project onto (unbounded by default).
+    #[clap(long, value_parser)]
+    max_sequences: Option<usize>,
+
+    /// Stop a query once it finds more than this many results (including the input range), then
+    /// fail or keep the first ones depending on `--on-limit`.
+    #[clap(long, value_parser)]
+    max_results: Option<usize>,
+
+    /// What to do when a query exceeds `--max-results`.
+    #[clap(long, value_enum, default_value_t = OnLimit::Error, requires = "max_results")]
+    on_limit: OnLimit,
+
+    /// Only report results whose query side is this sequence.
+    #[clap(long, value_name = "SEQNAME", value_parser)]
+    against: Option<String>,
+
+    /// Stop expanding a query after this many seconds and keep the results found so far.
+    #[clap(long, value_name = "SECONDS", value_parser)]
+    timeout: Option<u64>,
+
+    /// Trim results projected onto the queried sequence so their target side stays within the
+    /// requested range, cutting the query side and CIGAR at the exact alignment positions.
+    #[clap(long, action)]
+    clamp: bool,
+
+    /// Drop results whose identity (see `--identity-metric`) is below this value.
+    #[clap(long, value_parser)]
+    min_identity: Option<f64>,
+
+    /// Drop results whose alignment block length (PAF column 11, matches, mismatches and indels) is below this value.
+    #[clap(long, value_parser)]
+    min_block_length: Option<i32>,
+
+    /// Identity metric used by `--min-identity`.
+    #[clap(long, value_enum, default_value_t = IdentityMetric::Gap)]
+    identity_metric: IdentityMetric,
+
+    /// Only report results whose query interval is on this strand relative to the target.
+    #[clap(long, value_enum, default_value_t = StrandFilter::Both)]
+    strand: StrandFilter,
+
+    /// Drop results on the queried sequence itself, including the query region, keeping only other sequences.
+    #[clap(long, action)]
+    no_self: bool,
+
+    /// Expand each query region by this many bp on both sides, clamped to the target sequence bounds.
+    #[clap(long, value_parser = clap::value_parser!(i32).range(0..), default_value_t = 0)]
+    padding: i32,
+
+    /// Only report results on sequences whose name starts with one of these prefixes (repeatable).
+    /// Applied after transitive expansion, so intermediate sequences still drive the projection.
+    #[clap(long, value_parser)]
+    target_prefix: Vec<String>,
+
+    /// Match `--target-prefix` against whole PanSN fields (`sample#haplotype#contig`), so `HG002#1`
+    /// selects the contigs of haplotype 1 of HG002 but not those of `HG002#10`.
+    #[clap(long, action)]
+    pansn: bool,
+
+    /// Field delimiter of the sequence names for `--pansn`.
+    #[clap(long, value_parser, default_value_t = '#', requires = "pansn")]
+    prefix_delimiter: char,
+
+    /// Merge results on the same sequence and strand that overlap or lie within `--merge-distance` bp.
+    /// Merged intervals have no CIGAR, so outputs that write CIGARs can't be merged.
+    #[clap(long, action)]
+    merge_output: bool,
+
+    /// Maximum gap between results merged by `--merge-output`.
+    #[clap(long, value_parser = clap::value_parser!(i32).range(0..), default_value_t = 0, requires = "merge_output")]
+    merge_distance: i32,
+
+    /// BED file of query regions to remove from the results, trimming the results that partly overlap them.
+    #[clap(long, value_parser)]
+    exclude_bed: Option<String>,
+
+    /// BED file of query regions to restrict the results to, keeping only the parts of each result inside them.
+    #[clap(long, value_parser)]
+    include_bed: Option<String>,
+
+    /// Report the parts of each sequence in the results that no result covers, as BED, instead of the results.
+    #[clap(long, action)]
+    invert: bool,
+
+    /// What `--invert` complements the results within.
+    #[clap(long, value_enum, default_value_t = InvertScope::Sequence, requires = "invert")]
+    invert_scope: InvertScope,
+
+    /// Report the number of alignments covering each base of the queried range as BEDGRAPH, instead
+    /// of the results. Only alignments onto the queried sequence count, so `--transitive` adds those
+    /// reached back onto it through other sequences.
+    #[clap(long, action, conflicts_with = "invert")]
+    coverage: bool,
+
+    /// Report, as BED, the union of the target spans of the results on each target sequence they
+    /// reach, instead of the results.
+    #[clap(long, action, conflicts_with_all = ["invert", "coverage"])]
+    summarize_targets: bool,
+
+    /// Report the mean `--coverage` depth over fixed windows of this many bp instead of depth runs.
+    #[clap(long, value_parser = clap::value_parser!(i32).range(1..), requires = "coverage")]
+    bin: Option<i32>,
+
+    /// After the results, print to stderr the total bp of each CIGAR operation over all results.
+    #[clap(long, action)]
+    op_stats: bool,
+
+    /// Output results in PAF format (same as `--output-format paf`).
     #[clap(short='P', long, action)]
     output_paf: bool,
+
+    /// Order of the results of each query: by target coordinate, by query name then start, or the
+    /// order they were found in, which starts with the input range.
+    #[clap(long, value_enum, default_value_t = ResultOrder::None)]
+    sort: ResultOrder,
+
+    /// Sequence the PAF output CIGARs read from: `query` as stored, or `target` with insertions and
+    /// deletions swapped, and reverse-strand CIGARs reversed.
+    #[clap(long, value_enum, default_value_t = CigarOrientation::Query)]
+    cigar_orientation: CigarOrientation,
+
+    /// Comma-separated PAF optional fields to copy into PAF output, e.g. `tp,de,AS`, taken from
+    /// the alignment each result was projected through (the last hop of transitive results).
+    #[clap(long, value_delimiter = ',')]
+    keep_tags: Vec<String>,
+
+    /// Report the alignment records each result was projected through, from the queried sequence
+    /// outwards, as `paf_file_index:byte_offset` of their PAF lines: a `tr:Z:` tag in PAF output
+    /// and a `trace` array in JSON output.
+    #[clap(long, action)]
+    trace: bool,
+
+    /// Output format; `auto` writes BED for `--target-range` and BEDPE for `--target-bed`.
+    #[clap(long, value_enum, default_value_t = OutputFormat::Auto)]
+    output_format: OutputFormat,
         
+    /// Write JSON output as a single array instead of one object per line (implies `--output-format json`).
+    #[clap(long, action)]
+    json_array: bool,
+
+    /// Project the `--target-bed` regions onto the sequences aligned to them as PAF, one line per
+    /// overlapping alignment with the region as the target and its BED name as an `an:Z:` tag.
+    #[clap(long, action, requires = "target_bed")]
+    bed_to_paf: bool,
+
+    /// Also write the `--bed-to-paf` line mapping each region onto itself.
+    #[clap(long, action, requires = "bed_to_paf")]
+    keep_input_row: bool,
+
+    /// Append the CIGAR of each result to BEDPE lines as a trailing `cg:Z:` column.
+    #[clap(long, action)]
+    output_bedpe_cigar: bool,
+
+    /// Append the gap-compressed and block identity of each result to BEDPE lines as `gi:f:` and
+    /// `bi:f:` columns, before any `cg:Z:` column.
+    #[clap(long, action)]
+    bedpe_extended: bool,
+
+    /// Write results to this file instead of stdout, bgzip-compressed if it ends in `.gz` or `.bgz`.
+    #[clap(short='o', long, value_parser)]
+    output: Option<String>,
+
+    /// Print the aligned sequences of each result, with gaps from the CIGAR, instead of BED/PAF (requires `--fasta`).
+    #[clap(long, action)]
+    show: bool,
+
+    /// Path to the FASTA file holding the target and query sequences.
+    #[clap(long, value_parser)]
+    fasta: Option<String>,
+
+    /// Recompute `=`/`X` for `M` operations from the bases in `--fasta`, for accurate match counts and identity.
+    #[clap(long, action)]
+    realign_md: bool,
+
+    /// Write the majority-vote consensus of the query bases aligned to each queried target range
+    /// as FASTA, instead of BED/PAF (requires `--fasta`). Inserted query bases are left out.
+    #[clap(long, action, conflicts_with = "show")]
+    consensus: bool,
+
+    /// Bases aligned to a target position needed to call its consensus, which is `N` otherwise.
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..), default_value_t = 1, requires = "consensus")]
+    min_depth: u32,
+
+    /// Line width of the alignments printed by `--show` and the sequences of `--consensus`.
+    #[clap(long, value_parser, default_value_t = 80)]
+    wrap: usize,
+
+    /// Print the intervals stored in the index for this target sequence, with their query mapping and CIGAR location in the PAF.
+    #[clap(long, value_parser)]
+    dump: Option<String>,
+
     /// Print stats about the index.
     #[clap(short='s', long, action)]
     stats: bool,
 
+    /// Column to sort the per-target table printed by `--stats` by; counts and bp sort descending.
+    #[clap(long, value_enum, default_value_t = StatsSortBy::Count)]
+    sort_by: StatsSortBy,
+
+    /// Only print the first N rows of the per-target table printed by `--stats`.
+    #[clap(long, value_parser)]
+    top: Option<usize>,
+
+    /// Also print, for each target sequence, its number of intervals and the largest number
+    /// covering a single base, deepest first.
+    #[clap(long, action, requires = "stats")]
+    detailed: bool,
+
     /// Number of threads for parallel processing.
     #[clap(short='t', long, value_parser, default_value_t = NonZeroUsize::new(1).unwrap())]
     num_threads: NonZeroUsize,
@@ -48,65 +335,385 @@ struct Args {
     /// Check the projected intervals, reporting the wrong ones (slow, useful for debugging).
     #[clap(short='c', long, action)]
     check_intervals: bool,
+
+    /// Benchmarking: run each query N times, logging the time of each run and their median, and
+    /// keep the results of the last one.
+    #[clap(long, value_name = "N", value_parser, hide = true)]
+    repeat: Option<NonZeroUsize>,
+
+    /// Verbosity level (0 = warnings, 1 = info, 2 = debug).
+    #[clap(short='v', long, value_parser, default_value_t = 0)]
+    verbose: u8,
+
+    /// Also write log output to this file.
+    #[clap(long, value_parser)]
+    log_file: Option<String>,
+
+    /// Only log errors, overriding -v.
+    #[clap(short='q', long, action)]
+    quiet: bool,
+
+    /// Report a fatal error as a single JSON object on stderr, with its `message` and `kind`.
+    #[clap(long, action)]
+    errors_json: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum StatsSortBy {
+    Count,
+    Bp,
+    Name,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum ResultOrder {
+    Target,
+    Query,
+    None,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum InvertScope {
+    /// The whole of every sequence the results are on
+    Sequence,
+    /// The queried range, reporting the parts of it no alignment covers
+    Query,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum OnLimit {
+    /// Fail the query
+    #[default]
+    Error,
+    /// Keep the first `--max-results` results with a warning
+    Truncate,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum CigarOrientation {
+    Query,
+    Target,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Auto,
+    Bed,
+    Bedpe,
+    Paf,
+    /// BED12 with one block per aligned run of the query
+    Bed12,
+    /// GAF with the target as a single oriented path segment
+    Gaf,
+    /// One JSON object per line with the CIGAR and identity details
+    Json,
+    /// A pair of VCF breakend records for each result onto another sequence or strand
+    Vcf,
+    /// SAM records of the query sides aligned to the target sequences, with bases from `--fasta`.
+    /// The rest of each query is hard-clipped (`H`), so SEQ only holds the aligned bases
+    Sam,
+    /// Dot plot anchor points where each aligned run of a result begins and ends, as
+    /// `query_name`, `target_name`, `query_pos` and `target_pos` columns
+    Dotplot,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum StrandFilter {
+    #[value(name = "+")]
+    Forward,
+    #[value(name = "-")]
+    Reverse,
+    Both,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum IdentityMetric {
+    /// Gap-compressed identity, counting each indel as one difference
+    Gap,
+    /// Block identity, matches over the alignment block length
+    Block,
+}
+
+/// Writer that duplicates log output to stderr and a log file.
+struct TeeWriter {
+    file: File,
+}
+
+impl Write for TeeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stderr().write_all(buf)?;
+        self.file.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()?;
+        self.file.flush()
+    }
+}
+
+fn initialize_logger(verbose: u8, quiet: bool, log_file: Option<&str>) -> io::Result<()> {
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(match verbose {
+        _ if quiet => log::LevelFilter::Error,
+        0 => log::LevelFilter::Warn,
+        1 => log::LevelFilter::Info,
+        _ => log::LevelFilter::Debug,
+    });
+    if let Some(log_file) = log_file {
+        let file = File::create(log_file)?;
+        builder.target(env_logger::Target::Pipe(Box::new(TeeWriter { file })));
+    }
+    builder.init();
+    Ok(())
 }
 
-fn main() -> io::Result<()> {
+fn main() -> ExitCode {
     let args = Args::parse();
+    let errors_json = args.errors_json;
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            if errors_json {
+                eprintln!("{}", format_error_json(&e));
+            } else {
+                eprintln!("Error: {:?}", e);
+            }
+            ExitCode::FAILURE
+        },
+    }
+}
+
+/// `error` as a one-line JSON object, for `--errors-json`.
+fn format_error_json(error: &io::Error) -> String {
+    serde_json::json!({ "message": error.to_string(), "kind": format!("{:?}", error.kind()) }).to_string()
+}
+
+fn run(args: Args) -> io::Result<()> {
+    initialize_logger(args.verbose, args.quiet, args.log_file.as_deref())?;
 
     // Configure the global thread pool to use the specified number of threads
-    ThreadPoolBuilder::new().num_threads(args.num_threads.into()).build_global().unwrap();
+    initialize_thread_pool(args.num_threads.into());
 
-    let impg = match args {
-        Args { paf_file: Some(paf), force_reindex: false, .. } => load_or_generate_index(&paf, args.num_threads)?,
-        Args { paf_file: Some(paf), force_reindex: true, .. } => generate_index(&paf, args.num_threads)?,
-        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "A PAF file must be provided")),
+    if args.paf_file.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "A PAF file must be provided"));
+    }
+    let index_file = index_file_path(&args.paf_file, args.index.as_deref())?;
+    let fai_lengths = args.fai.as_deref().map(read_fai_lengths).transpose()?;
+    let index_options = IndexOptions {
+        num_threads: args.num_threads,
+        canonical_seq_order: args.canonical_seq_order,
+        drop_self_alignments: args.drop_self_alignments,
+        no_cigar: args.no_cigar,
+        strict: args.strict,
+        min_mapq: args.min_mapq,
+        build_mem_limit: args.build_mem_limit.map(|mb| mb.get() * 1_000_000),
+        fai_lengths: fai_lengths.as_ref(),
+    };
+    let index_start = std::time::Instant::now();
+    let impg = if args.force_reindex {
+        generate_index(&args.paf_file, &index_file, index_options)?
+    } else if args.append {
+        append_index(&args.paf_file, &index_file, index_options)?
+    } else {
+        load_or_generate_index(&args.paf_file, &index_file, index_options)?
     };
 
-    if args.stats {
-        print_stats(&impg);
+    let index_time = index_start.elapsed();
+
+    if args.validate_index {
+        validate_index(&impg, &args.paf_file, index_options)?;
     }
 
-    if let Some(target_range) = args.target_range {
-        let (target_name, target_range) = parse_target_range(&target_range)?;
-        let results = perform_query(&impg, &target_name, target_range, args.transitive);
-        if args.check_intervals {
-            let invalid_cigars = check_intervals(&impg, &results);
-            if !invalid_cigars.is_empty() {
-                for (row, error_reason) in invalid_cigars {
-                    eprintln!("{}; {}", error_reason, row);
-                }
-                panic!("Invalid intervals encountered.");
+    if args.stats {
+        print_stats(&impg, args.sort_by, args.top);
+        if args.detailed {
+            println!("#target\tintervals\tmax_depth");
+            for (name, intervals, max_depth) in overlap_depths(&impg) {
+                println!("{}\t{}\t{}", name, intervals, max_depth);
             }
         }
-        if args.output_paf {
-            output_results_paf(&impg, results, &target_name, None);
-        } else {
-            output_results_bed(&impg, results);
-        }
-    } else if let Some(target_bed) = args.target_bed {
-        let targets = parse_bed_file(&target_bed)?;
-        for (target_name, target_range, name) in targets {
-            let results = perform_query(&impg, &target_name, target_range, args.transitive);
-            if args.check_intervals {
-                let invalid_cigars = check_intervals(&impg, &results);
-                if !invalid_cigars.is_empty() {
-                    for (row, error_reason) in invalid_cigars {
-                        eprintln!("{}; {}", error_reason, row);
-                    }
-                    panic!("Invalid intervals encountered.");
-                }
-            }
-            if args.output_paf {
-                output_results_paf(&impg, results, &target_name, name);
-            } else {
-                output_results_bedpe(&impg, results, &target_name, name);
-            }
+        print_index_stats(&impg, &index_file, index_time);
+    }
+
+    if !impg.has_cigars {
+        if let Some(option) = cigar_dependent_option(&args) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} needs CIGARs, but the index was built with --no-cigar", option)));
+        }
+    }
+    if args.trace && !matches!(requested_format(&args), OutputFormat::Paf | OutputFormat::Json) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "--trace requires PAF or JSON output"));
+    }
+    if args.show && args.fasta.is_none() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "--show requires --fasta"));
+    }
+    if args.consensus && args.fasta.is_none() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "--consensus requires --fasta"));
+    }
+    if args.realign_md && args.fasta.is_none() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "--realign-md requires --fasta"));
+    }
+    if let (true, Some(option)) = (args.merge_output, cigar_output_option(&args)) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("--merge-output can't be combined with {}, as merged results have no CIGAR", option)));
+    }
+    let ctx = QueryContext {
+        impg: &impg,
+        args: &args,
+        fetcher: args.fasta.as_deref().map(SequenceFetcher::new).transpose()?,
+        json_records: AtomicUsize::new(0),
+        excluded: args.exclude_bed.as_deref().map(|bed_file| load_region_mask(&impg, bed_file)).transpose()?.unwrap_or_default(),
+        included: args.include_bed.as_deref().map(|bed_file| load_region_mask(&impg, bed_file)).transpose()?,
+        op_totals: Mutex::new(OpTotals::default()),
+        bnd_pairs: AtomicUsize::new(0),
+    };
+    let mut out = create_output(args.output.as_deref())?;
+
+    if let Some(seq_name) = &args.dump {
+        dump_tree(&mut out, &impg, seq_name)?;
+    }
+
+    let json_array = args.json_array && (args.target_range.is_some() || args.target_bed.is_some() || !args.regions.is_empty());
+    if json_array {
+        write!(out, "[")?;
+    }
+    // Modes that replace the results with other records don't write VCF or SAM
+    if !(args.coverage || args.summarize_targets || args.invert || args.show || args.consensus || args.liftover.is_some()) {
+        match requested_format(&args) {
+            OutputFormat::Vcf => write_vcf_header(&mut out, &impg)?,
+            OutputFormat::Sam => write_sam_header(&mut out, &impg)?,
+            _ => {},
+        }
+    }
+    if let Some(position) = &args.liftover {
+        let (seq_name, pos) = parse_position(position)?;
+        output_liftover(&mut out, &impg, &seq_name, pos, args.to.as_deref())?;
+    } else if let Some(target_range) = &args.target_range {
+        let (target_name, target_range) = parse_target_range(target_range, args.end_inclusive, &impg.seq_index)?;
+        let results = query_region(&ctx, &target_name, target_range)?;
+        output_results(&mut out, &ctx, results, &target_name, target_range, None, OutputFormat::Bed)?;
+    } else if !args.regions.is_empty() || args.target_bed.is_some() {
+        let targets = query_targets(&args, &impg.seq_index)?;
+        query_and_output_targets(&mut out, &ctx, targets)?;
+    }
+    if json_array {
+        writeln!(out, "{}]", if ctx.json_records.load(Ordering::Relaxed) > 0 { "\n" } else { "" })?;
+    }
+    out.finish()?;
+    if args.op_stats {
+        let totals = *ctx.op_totals.lock().unwrap();
+        for (op, bp) in OpTotals::OPS.iter().zip(totals.bp) {
+            eprintln!("{}\t{}", op, bp);
+        }
+    }
+    Ok(())
+}
+
+/// A region to query as `(source, target_name, target_range, name)`, where `source` locates it
+/// in the command line for error messages.
+type QueryTarget = (String, String, (i32, i32), Option<String>);
+
+/// The `--region` regions followed by those of `--target-bed`.
+fn query_targets(args: &Args, seq_index: &SequenceIndex) -> io::Result<Vec<QueryTarget>> {
+    let mut targets = Vec::new();
+    for region in &args.regions {
+        let (target_name, target_range) = parse_target_range(region, args.end_inclusive, seq_index)
+            .map_err(|e| io::Error::new(e.kind(), format!("--region {}: {}", region, e)))?;
+        targets.push((format!("--region {}", region), target_name, target_range, None));
+    }
+    if let Some(target_bed) = &args.target_bed {
+        for (line, (target_name, target_range, name)) in parse_bed_file(target_bed)?.into_iter().enumerate() {
+            targets.push((format!("BED line {}", line + 1), target_name, target_range, name));
+        }
+    }
+    Ok(targets)
+}
+
+/// Number of regions queried in parallel per thread before their results are written.
+const REGIONS_PER_THREAD: usize = 16;
+
+/// Query `targets` in parallel batches, writing their results in input order as BEDPE unless
+/// another format is requested.
+fn query_and_output_targets(out: &mut dyn Write, ctx: &QueryContext, targets: Vec<QueryTarget>) -> io::Result<()> {
+    for batch in targets.chunks(rayon::current_num_threads() * REGIONS_PER_THREAD) {
+        let batch_results: Vec<io::Result<Vec<QueryResult>>> = batch.par_iter()
+            .map(|(_, target_name, target_range, _)| query_region(ctx, target_name, *target_range))
+            .collect();
+        for ((source, target_name, target_range, name), results) in batch.iter().zip(batch_results) {
+            let results = match results {
+                Ok(results) => results,
+                Err(e) if ctx.args.skip_invalid => {
+                    warn!("Skipping {}: {}", source, e);
+                    continue;
+                },
+                Err(e) => return Err(io::Error::new(e.kind(), format!("{}: {}", source, e))),
+            };
+            output_results(out, ctx, results, target_name, *target_range, name.clone(), OutputFormat::Bedpe)?;
         }
     }
     Ok(())
 }
 
-fn parse_bed_file(bed_file: &str) -> io::Result<Vec<(String, (i32, i32), Option<String>)>> {
+/// Write where base `pos` of `seq_name` lands on each directly aligned sequence starting with `to`.
+fn output_liftover(out: &mut dyn Write, impg: &Impg, seq_name: &str, pos: i32, to: Option<&str>) -> io::Result<()> {
+    let pos_end = pos.checked_add(1).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid position value"))?;
+    for (result, _, _, _) in perform_query(impg, seq_name, (pos, pos_end), 0, Expansion::default())?.into_iter().filter(|(_, _, _, input_row)| !input_row) {
+        let name = impg.seq_index.get_name(result.0.metadata).unwrap();
+        if to.is_some_and(|prefix| !name.starts_with(prefix)) {
+            continue;
+        }
+        let strand = if result.0.first <= result.0.last { '+' } else { '-' };
+        match lift_position(&result, pos) {
+            Some(LiftedPosition::Aligned(lifted)) => writeln!(out, "{}\t{}\t{}\taligned", name, lifted, strand)?,
+            Some(LiftedPosition::Gap(lifted)) => writeln!(out, "{}\t{}\t{}\tgap", name, lifted, strand)?,
+            None => {},
+        }
+    }
+    Ok(())
+}
+
+/// The destination of the results, which must be `finish`ed to report the errors of its last writes.
+enum Output {
+    Plain(BufWriter<Box<dyn Write>>),
+    Bgzf(bgzf::Writer<File>),
+}
+
+impl Output {
+    /// Flush the output and, for BGZF, write its last block and EOF marker.
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Output::Plain(mut writer) => writer.flush(),
+            Output::Bgzf(writer) => writer.finish().map(|_| ()),
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Plain(writer) => writer.write(buf),
+            Output::Bgzf(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Plain(writer) => writer.flush(),
+            Output::Bgzf(writer) => writer.flush(),
+        }
+    }
+}
+
+fn create_output(output: Option<&str>) -> io::Result<Output> {
+    match output {
+        Some(path) if [".gz", ".bgz"].iter().any(|e| path.ends_with(e)) => Ok(Output::Bgzf(bgzf::Writer::new(File::create(path)?))),
+        Some(path) => Ok(Output::Plain(BufWriter::new(Box::new(File::create(path)?)))),
+        None => Ok(Output::Plain(BufWriter::new(Box::new(io::stdout().lock())))),
+    }
+}
+
+type BedRegion = (String, (i32, i32), Option<String>);
+
+fn parse_bed_file(bed_file: &str) -> io::Result<Vec<BedRegion>> {
     let file = File::open(bed_file)?;
     let reader = BufReader::new(file);
     let mut ranges = Vec::new();
@@ -118,7 +725,7 @@ fn parse_bed_file(bed_file: &str) -> io::Result<Vec<(String, (i32, i32), Option<
             return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid BED file format"));
         }
 
-        let (start, end) = parse_range(&parts[1..=2])?;
+        let (start, end) = parse_range(&parts[1..=2], false)?;
         let name = parts.get(3).map(|s| s.to_string());
         ranges.push((parts[0].to_string(), (start, end), name));
     }
@@ -126,158 +733,2277 @@ fn parse_bed_file(bed_file: &str) -> io::Result<Vec<(String, (i32, i32), Option<
     Ok(ranges)
 }
 
-fn parse_target_range(target_range: &str) -> io::Result<(String, (i32, i32))> {
+/// Parse `seq_name:start-end`, or a bare sequence name of `seq_index` meaning its whole length.
+fn parse_target_range(target_range: &str, end_inclusive: bool, seq_index: &SequenceIndex) -> io::Result<(String, (i32, i32))> {
+    if let Some(length) = seq_index.get_id(target_range).and_then(|id| seq_index.get_len_from_id(id)) {
+        return Ok((target_range.to_string(), (0, length as i32)));
+    }
     let parts: Vec<&str> = target_range.rsplitn(2, ':').collect();
     if parts.len() != 2 {
         return Err(io::Error::new(io::ErrorKind::InvalidInput, "Target range format should be `seq_name:start-end`"));
     }
 
-    let (start, end) = parse_range(&parts[0].split('-').collect::<Vec<_>>())?;
+    let (start, end) = parse_range(&parts[0].split('-').collect::<Vec<_>>(), end_inclusive)?;
     Ok((parts[1].to_string(), (start, end)))
 }
 
-fn parse_range(range_parts: &[&str]) -> io::Result<(i32, i32)> {
+/// The first requested option that needs the CIGARs of the results, if any.
+fn cigar_dependent_option(args: &Args) -> Option<&'static str> {
+    [
+        (args.realign_md, "--realign-md"),
+        (args.check_intervals, "--check-intervals"),
+        (args.min_identity.is_some(), "--min-identity"),
+        (args.min_block_length.is_some(), "--min-block-length"),
+        (args.exclude_bed.is_some(), "--exclude-bed"),
+        (args.include_bed.is_some(), "--include-bed"),
+        (args.liftover.is_some(), "--liftover"),
+        (args.op_stats, "--op-stats"),
+        (!args.keep_tags.is_empty(), "--keep-tags"),
+        (args.clamp, "--clamp"),
+    ].into_iter().find(|(requested, _)| *requested).map(|(_, option)| option).or_else(|| cigar_output_option(args))
+}
+
+/// The first requested output that writes the CIGARs of the results, if any.
+fn cigar_output_option(args: &Args) -> Option<&'static str> {
+    let format = match args.output_format {
+        OutputFormat::Paf => Some("--output-format paf"),
+        OutputFormat::Bed12 => Some("--output-format bed12"),
+        OutputFormat::Gaf => Some("--output-format gaf"),
+        OutputFormat::Dotplot => Some("--output-format dotplot"),
+        OutputFormat::Sam => Some("--output-format sam"),
+        OutputFormat::Json => Some("--output-format json"),
+        OutputFormat::Auto | OutputFormat::Bed | OutputFormat::Bedpe | OutputFormat::Vcf => None,
+    };
+    [
+        (args.output_paf, "--output-paf"),
+        (args.bed_to_paf, "--bed-to-paf"),
+        (args.json_array, "--json-array"),
+        (args.output_bedpe_cigar, "--output-bedpe-cigar"),
+        (args.bedpe_extended, "--bedpe-extended"),
+        (args.show, "--show"),
+        (args.consensus, "--consensus"),
+    ].into_iter().find(|(requested, _)| *requested).map(|(_, option)| option).or(format)
+}
+
+/// Parse a single 0-based position given as `seq_name:pos`.
+fn parse_position(position: &str) -> io::Result<(String, i32)> {
+    let parts: Vec<&str> = position.rsplitn(2, ':').collect();
+    if parts.len() != 2 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Position format should be `seq_name:pos`"));
+    }
+    let pos = parts[0].parse::<i32>().map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid position value"))?;
+    Ok((parts[1].to_string(), pos))
+}
+
+/// Parse `start` and `end`, returning a half-open range. With `end_inclusive`, `end` is shifted by one.
+fn parse_range(range_parts: &[&str], end_inclusive: bool) -> io::Result<(i32, i32)> {
     if range_parts.len() != 2 {
         return Err(io::Error::new(io::ErrorKind::InvalidInput, "Range format should be `start-end`"));
     }
 
-    let start = range_parts[0].parse::<i32>().map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid start value"))?;
-    let end = range_parts[1].parse::<i32>().map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid end value"))?;
+    let parse = |value: &str, what: &str| value.parse::<i32>().map_err(|e| match e.kind() {
+        IntErrorKind::PosOverflow => io::Error::new(io::ErrorKind::InvalidInput, format!("{} value {} exceeds the maximum supported coordinate {}", what, value, i32::MAX)),
+        _ => io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid {} value", what.to_lowercase())),
+    });
+    let start = parse(range_parts[0], "Start")?;
+    let end = parse(range_parts[1], "End")?;
+    let end = if end_inclusive { end.checked_add(1).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("End value exceeds the maximum supported coordinate {}", i32::MAX)))? } else { end };
 
-    if start >= end {
-        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Start value must be less than end value"));
+    // Equal start and end make a point query
+    if start > end {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Start value must not be greater than end value"));
     }
 
     Ok((start, end))
 }
 
-fn load_or_generate_index(paf_file: &str, num_threads: NonZeroUsize) -> io::Result<Impg> {
-    let index_file = format!("{}.impg", paf_file);
-    if std::path::Path::new(&index_file).exists() {
-        load_index(paf_file)
+/// The index file to use for `paf_files`: `index` if given, `{paf_file}.impg` for a single PAF file.
+fn index_file_path(paf_files: &[String], index: Option<&str>) -> io::Result<String> {
+    match (index, paf_files) {
+        (Some(index), _) => Ok(index.to_string()),
+        (None, [paf_file]) => Ok(format!("{}.impg", paf_file)),
+        (None, _) => Err(io::Error::new(io::ErrorKind::InvalidInput, "--index is required when indexing several PAF files")),
+    }
+}
+
+/// How to build an index, from the command line.
+#[derive(Clone, Copy)]
+struct IndexOptions<'a> {
+    num_threads: NonZeroUsize,
+    canonical_seq_order: bool,
+    drop_self_alignments: Option<SelfAlignmentFilter>,
+    no_cigar: bool,
+    strict: bool,
+    min_mapq: Option<u8>,
+    /// `--build-mem-limit` in bytes
+    build_mem_limit: Option<usize>,
+    /// `--fai` sequence lengths by name
+    fai_lengths: Option<&'a HashMap<String, usize>>,
+}
+
+fn load_or_generate_index(paf_files: &[String], index_file: &str, options: IndexOptions) -> io::Result<Impg> {
+    if std::path::Path::new(index_file).exists() {
+        for paf_file in stale_paf_files(paf_files, index_file) {
+            warn!("PAF file {} is newer than index {}, consider regenerating it with -I or updating it with --append", paf_file, index_file);
+        }
+        info!("Loading index {}", index_file);
+        load_index(paf_files, index_file)
     } else {
-        generate_index(paf_file, num_threads)
+        generate_index(paf_files, index_file, options)
     }
 }
 
-fn generate_index(paf_file: &str, num_threads: NonZeroUsize) -> io::Result<Impg> {
-    let file = File::open(paf_file)?;
-    let reader: Box<dyn io::Read> = if [".gz", ".bgz"].iter().any(|e| paf_file.ends_with(e)) {
-        Box::new(bgzf::MultithreadedReader::with_worker_count(num_threads, file))
+/// The PAF files modified after `index_file`, which may be missing from it.
+fn stale_paf_files<'a>(paf_files: &'a [String], index_file: &str) -> Vec<&'a str> {
+    let modified = |path: &str| std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+    paf_files.iter()
+        .filter(|paf_file| matches!((modified(paf_file), modified(index_file)), (Some(paf_time), Some(index_time)) if paf_time > index_time))
+        .map(String::as_str)
+        .collect()
+}
+
+/// Number of PAF records between two progress lines while indexing.
+const PROGRESS_INTERVAL: usize = 1_000_000;
+
+fn is_compressed(paf_file: &str) -> bool {
+    [".gz", ".bgz", ".zst"].iter().any(|e| paf_file.ends_with(e))
+}
+
+/// Parse the records of `paf_file` from byte `start_offset` on, which must be 0 for compressed files.
+fn read_paf_records(paf_file: &str, start_offset: u64, options: IndexOptions) -> io::Result<Vec<paf::PafRecord>> {
+    // Queries read each CIGAR at its offset, and zstd frames can't be seeked into
+    if paf_file.ends_with(".zst") && !options.no_cigar {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Can't read the CIGARs of zstd-compressed {} at query time, index it with --no-cigar or recompress it with bgzip", paf_file)));
+    }
+    let mut file = File::open(paf_file)?;
+    let reader: Box<dyn io::Read> = if paf_file.ends_with(".zst") {
+        Box::new(zstd::Decoder::new(file)?)
+    } else if is_compressed(paf_file) {
+        Box::new(bgzf::MultithreadedReader::with_worker_count(options.num_threads, file))
     } else {
+        file.seek(SeekFrom::Start(start_offset))?;
         Box::new(file)
     };
     let reader = BufReader::new(reader);
-    let records = paf::parse_paf(reader).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Failed to parse PAF records: {:?}", e)))?;
-    let impg = Impg::from_paf_records(&records, paf_file).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Failed to create index: {:?}", e)))?;
-
-    let index_file = format!("{}.impg", paf_file);
-    let serializable = impg.to_serializable();
-    let file = File::create(index_file)?;
-    let writer = BufWriter::new(file);
-    bincode::serialize_into(writer, &serializable).map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Failed to serialize index: {:?}", e)))?;
-
-    Ok(impg)
+    // The consumed bytes are uncompressed, so only plain PAF files get a percentage
+    let total_bytes = match is_compressed(paf_file) {
+        true => None,
+        false => std::fs::metadata(paf_file).ok().map(|metadata| metadata.len()),
+    };
+    let progress = |records: usize, bytes: u64| match total_bytes {
+        Some(total) if total > 0 => info!("Parsed {} records, {} MB ({:.1}%)", records, bytes / 1_000_000, 100.0 * bytes as f64 / total as f64),
+        _ => info!("Parsed {} records, {} MB", records, bytes / 1_000_000),
+    };
+    let mut records = paf::parse_paf_with_progress(reader, start_offset, options.strict, !options.no_cigar, PROGRESS_INTERVAL, progress).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Failed to parse PAF records from {}: {}", paf_file, e)))?;
+    if let Some(min_mapq) = options.min_mapq {
+        let parsed = records.len();
+        records.retain(|record| record.mapq >= min_mapq);
+        info!("Skipped {} records of {} with a mapping quality below {}", parsed - records.len(), paf_file, min_mapq);
+    }
+    if let Some(fai_lengths) = options.fai_lengths {
+        check_fai_lengths(&records, paf_file, fai_lengths)?;
+    }
+    Ok(records)
 }
 
-fn load_index(paf_file: &str) -> io::Result<Impg> {
-    let index_file = format!("{}.impg", paf_file);
-    let file = File::open(index_file)?;
-    let reader = BufReader::new(file);
-    let serializable: SerializableImpg = bincode::deserialize_from(reader).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Failed to deserialize index: {:?}", e)))?;
-    Ok(Impg::from_paf_and_serializable(paf_file, serializable))
+/// Fail if a sequence of `records` has another length than in the FAI. Sequences missing from the
+/// FAI keep the lengths stated in the PAF.
+fn check_fai_lengths(records: &[paf::PafRecord], paf_file: &str, fai_lengths: &HashMap<String, usize>) -> io::Result<()> {
+    for record in records {
+        for (name, length) in [(&record.query_name, record.query_length), (&record.target_name, record.target_length)] {
+            match fai_lengths.get(name) {
+                Some(&fai_length) if fai_length != length => return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                    "Length of {} is {} in {} but {} in the FAI", name, length, paf_file, fai_length
+                ))),
+                _ => {},
+            }
+        }
+    }
+    Ok(())
 }
 
-fn perform_query(impg: &Impg, target_name: &str, target_range: (i32, i32), transitive: bool) -> Vec<AdjustedInterval> {
-    let (target_start, target_end) = target_range;
-    let target_id = impg.seq_index.get_id(target_name).expect("Target name not found in index");
-    let target_length = impg.seq_index.get_len_from_id(target_id).expect("Target length not found in index");
-    if target_end > target_length as i32 {
-        panic!("Target range end ({}) exceeds the target sequence length ({})", target_end, target_length);
+fn validate_index(impg: &Impg, paf_files: &[String], options: IndexOptions) -> io::Result<()> {
+    let options = IndexOptions { min_mapq: impg.min_mapq, ..options };
+    let mut records = Vec::new();
+    for paf_file in paf_files {
+        records.extend(read_paf_records(paf_file, 0, options)?);
     }
-    if transitive {
-        impg.query_transitive(target_id, target_start, target_end)
-    } else {
-        impg.query(target_id, target_start, target_end)
+    match impg.validate_records(records.iter(), options.drop_self_alignments) {
+        Ok((records, sequences)) => {
+            println!("Index consistent: {} records, {} sequences", records, sequences);
+            Ok(())
+        },
+        Err(divergence) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Index inconsistent with the PAF files: {}", divergence))),
     }
 }
 
-fn output_results_bed(impg: &Impg, results: Vec<AdjustedInterval>) {
-    for (overlap, _, _) in results {
-        let overlap_name = impg.seq_index.get_name(overlap.metadata).unwrap();
-        let (first, last, strand) = if overlap.first <= overlap.last {
-            (overlap.first, overlap.last, '+')
-        } else {
-            (overlap.last, overlap.first, '-')
-        };
-        println!("{}\t{}\t{}\t.\t{}", overlap_name, first, last, strand);
-    }
+fn write_index(impg: &Impg, index_file: &str) -> io::Result<()> {
+    let serializable = impg.to_serializable();
+    write_atomically(index_file, |writer| serializable.serialize_into(writer))
 }
 
-fn output_results_bedpe(impg: &Impg, results: Vec<AdjustedInterval>, target_name: &str, name: Option<String>) {
-    for (overlap_query, _, overlap_target) in results {
-        let overlap_name = impg.seq_index.get_name(overlap_query.metadata).unwrap();
-        let (first, last, strand) = if overlap_query.first <= overlap_query.last {
-            (overlap_query.first, overlap_query.last, '+')
-        } else {
-            (overlap_query.last, overlap_query.first, '-')
-        };
-        println!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t0\t{}\t+",
-                 overlap_name, first, last,
-                 target_name, overlap_target.first, overlap_target.last,
-                 name.as_deref().unwrap_or("."), strand);
+/// Write `path` through `write` into `{path}.tmp`, renamed into place once complete so readers
+/// never see a partial file. The temporary file is removed on failure.
+fn write_atomically(path: &str, write: impl FnOnce(&mut dyn Write) -> io::Result<()>) -> io::Result<()> {
+    let tmp_path = format!("{}.tmp", path);
+    let result = File::create(&tmp_path).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        write(&mut writer)?;
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()
+    }).and_then(|_| std::fs::rename(&tmp_path, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
     }
+    result
 }
 
-fn output_results_paf(impg: &Impg, results: Vec<AdjustedInterval>, target_name: &str, name: Option<String>) { 
-    let target_length = impg.seq_index.get_len_from_id(impg.seq_index.get_id(target_name).unwrap()).unwrap();  
-    for (overlap_query, cigar, overlap_target) in results {
-        let overlap_name = impg.seq_index.get_name(overlap_query.metadata).unwrap();
-        let (first, last, strand) = if overlap_query.first <= overlap_query.last {
-            (overlap_query.first, overlap_query.last, '+')
-        } else {
-            (overlap_query.last, overlap_query.first, '-')
-        };
+fn generate_index(paf_files: &[String], index_file: &str, options: IndexOptions) -> io::Result<Impg> {
+    let mut records_by_file = Vec::with_capacity(paf_files.len());
+    let mut paf_fingerprints = Vec::with_capacity(paf_files.len());
+    for paf_file in paf_files {
+        info!("Generating index for {}", paf_file);
+        records_by_file.push(read_paf_records(paf_file, 0, options)?);
+        paf_fingerprints.push(PafFingerprint::of_file(paf_file)?);
+    }
+    let records_by_file: Vec<(&[paf::PafRecord], &str)> = records_by_file.iter().zip(paf_files).map(|(records, paf_file)| (records.as_slice(), paf_file.as_str())).collect();
+    let mut impg = Impg::from_multi_paf_records_with_mem_limit(&records_by_file, options.canonical_seq_order, options.drop_self_alignments, options.build_mem_limit).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Failed to create index: {}", e)))?;
+    impg.paf_fingerprints = paf_fingerprints;
+    impg.has_cigars = !options.no_cigar;
+    impg.min_mapq = options.min_mapq;
 
-        let query_length = impg.seq_index.get_len_from_id(overlap_query.metadata).unwrap();  
+    write_index(&impg, index_file)?;
+    Ok(impg)
+}
 
-        let has_m_operation = cigar.iter().any(|op| op.op() == 'M');
-        let (matches, block_len) = if has_m_operation {
-            // We overestimate the number of matches by counting all M operations
-            cigar.iter().fold((0, 0), |(matches, block_len), op| {
-                let len = op.len();
-                match op.op() {
-                    'M' => (matches + len, block_len + len),
-                    'I' | 'D' => (matches, block_len + len),
-                    _ => (matches, block_len),
-                }
-            })
-        } else {
-            cigar.iter().fold((0, 0), |(matches, block_len), op| {
-                let len = op.len();
-                match op.op() {
-                    '=' => (matches + len, block_len + len),
-                    'X' | 'I' | 'D' => (matches, block_len + len),
-                    _ => (matches, block_len),
-                }
-            })
-        };
-        let cigar_str : String = cigar.iter().map(|op| format!("{}{}", op.len(), op.op())).collect();
+/// Load `index_file` and add the records appended to the PAF files since it was built, falling
+/// back to `generate_index` when a file shrank, changed before its indexed end, or is compressed
+/// and changed at all.
+fn append_index(paf_files: &[String], index_file: &str, options: IndexOptions) -> io::Result<Impg> {
+    if !std::path::Path::new(index_file).exists() {
+        return generate_index(paf_files, index_file, options);
+    }
+    info!("Loading index {}", index_file);
+    let mut impg = load_index(paf_files, index_file)?;
+    // Appended records and a rebuild keep the CIGAR mode and mapping quality threshold of the existing index
+    let options = IndexOptions { no_cigar: options.no_cigar || !impg.has_cigars, min_mapq: impg.min_mapq, ..options };
+    if impg.paf_fingerprints.len() != paf_files.len() {
+        warn!("Index {} does not record the indexed extent of its PAF files, regenerating it", index_file);
+        return generate_index(paf_files, index_file, options);
+    }
 
-        match name {
-            Some(ref name) => println!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\tcg:Z:{}\tan:Z:{}",
-                                    overlap_name, query_length, first, last, strand,
-                                    target_name, target_length, overlap_target.first, overlap_target.last,
-                                    matches, block_len, 255, cigar_str, name),
-            None => println!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\tcg:Z:{}",
-                                overlap_name, query_length, first, last, strand,
-                                target_name, target_length, overlap_target.first, overlap_target.last,
-                                matches, block_len, 255, cigar_str),
+    let mut appended = false;
+    for (paf_file_index, paf_file) in paf_files.iter().enumerate() {
+        let indexed = impg.paf_fingerprints[paf_file_index];
+        let current = PafFingerprint::of_file(paf_file)?;
+        if current == indexed {
+            continue;
+        }
+        let prefix_unchanged = !is_compressed(paf_file) && current.size > indexed.size && PafFingerprint::of_prefix(paf_file, indexed.size)? == indexed;
+        if !prefix_unchanged {
+            warn!("PAF file {} changed other than by appending records, regenerating index {}", paf_file, index_file);
+            return generate_index(paf_files, index_file, options);
         }
+
+        info!("Appending records from byte {} of {}", indexed.size, paf_file);
+        let records = read_paf_records(paf_file, indexed.size, options)?;
+        impg.append_paf_records(&records, paf_file_index as u32, options.drop_self_alignments).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Failed to append to index: {}", e)))?;
+        impg.paf_fingerprints[paf_file_index] = current;
+        appended = true;
+    }
+
+    if appended {
+        write_index(&impg, index_file)?;
+    }
+    Ok(impg)
+}
+
+fn load_index(paf_files: &[String], index_file: &str) -> io::Result<Impg> {
+    let file = File::open(index_file)?;
+    let reader = BufReader::new(file);
+    let serializable = SerializableImpg::deserialize_from(reader)?;
+    // The records point into the PAF files by position, so the files must be given in the same order
+    let file_name = |path: &str| std::path::Path::new(path).file_name().map(|name| name.to_os_string());
+    if serializable.paf_files.len() != paf_files.len() || serializable.paf_files.iter().zip(paf_files).any(|(indexed, given)| file_name(indexed) != file_name(given)) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+            "Index {} was built from PAF files [{}], which do not match the given ones [{}], regenerate it with -I",
+            index_file, serializable.paf_files.join(", "), paf_files.join(", ")
+        )));
     }
+    Ok(Impg::from_paf_and_serializable(paf_files, serializable))
 }
 
-fn print_stats(impg: &Impg) {
-    println!("Number of sequences: {}", impg.seq_index.len());
-    println!("Number of overlaps: {}", impg.trees.values().map(|tree| tree.len()).sum::<usize>());
+/// A query result with the lowest mapping quality of the alignments it was projected through,
+/// those alignments from the queried sequence outwards with `--trace`, and whether it is the
+/// queried range itself rather than an alignment.
+type QueryResult = (AdjustedInterval, u8, Vec<RecordId>, bool);
+
+/// Drop the mapping qualities of `results`, for the outputs that don't report them.
+fn without_mapq(results: Vec<QueryResult>) -> Vec<AdjustedInterval> {
+    results.into_iter().map(|(adjusted_interval, _, _, _)| adjusted_interval).collect()
+}
+
+/// How far a query expands, from the command line.
+#[derive(Clone, Copy, Default)]
+struct Expansion {
+    transitive: bool,
+    max_depth: Option<usize>,
+    max_sequences: Option<usize>,
+    max_results: Option<usize>,
+    on_limit: OnLimit,
+    timeout: Option<Duration>,
+    against: Option<u32>,
+    trace: bool,
+    /// Skip reconstructing CIGARs, when no output needs them
+    coordinates_only: bool,
+}
+
+impl Expansion {
+    fn from_args(args: &Args, seq_index: &SequenceIndex) -> io::Result<Self> {
+        let against = args.against.as_ref().map(|name| seq_index.get_id(name)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Sequence '{}' of --against not found in index", name))))
+            .transpose()?;
+        Ok(Self {
+            transitive: args.transitive,
+            max_depth: args.max_depth,
+            max_sequences: args.max_sequences,
+            max_results: args.max_results,
+            on_limit: args.on_limit,
+            timeout: args.timeout.map(Duration::from_secs),
+            against,
+            trace: args.trace,
+            coordinates_only: cigar_dependent_option(args).is_none(),
+        })
+    }
+}
+
+fn perform_query(impg: &Impg, target_name: &str, target_range: (i32, i32), padding: i32, expansion: Expansion) -> io::Result<Vec<QueryResult>> {
+    let (target_start, target_end) = target_range;
+    let target_id = impg.seq_index.get_id(target_name).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Target sequence '{}' not found in index", target_name)))?;
+    let target_length = impg.seq_index.get_len_from_id(target_id).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Length of target sequence '{}' not found in index", target_name)))?;
+    // Point queries need the base at their position
+    if target_start < 0 || target_end > target_length as i32 || (target_start == target_end && target_start >= target_length as i32) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+            "Target range {}:{}-{} is outside the target sequence bounds [0, {})", target_name, target_start, target_end, target_length
+        )));
+    }
+    let (target_start, target_end) = pad_range(target_range, padding, target_length as i32);
+    if padding > 0 {
+        info!("Querying padded range {}:{}-{}", target_name, target_start, target_end);
+    }
+    let mut iter = if expansion.transitive {
+        impg.query_transitive_iter(target_id, target_start, target_end, expansion.max_depth, expansion.max_sequences)
+    } else {
+        impg.query_iter(target_id, target_start, target_end)
+    };
+    if let Some(timeout) = expansion.timeout {
+        iter = iter.with_deadline(Instant::now() + timeout);
+    }
+    if let Some(against) = expansion.against {
+        iter = iter.against(against);
+    }
+    if expansion.trace {
+        iter = iter.with_trace();
+    }
+    if expansion.coordinates_only {
+        iter = iter.coordinates_only();
+    }
+    // Expansion is lazy, so taking one result past the limit stops it after the current round
+    let mut results: Vec<QueryResult> = std::iter::from_fn(|| iter.next_marked())
+        .take(expansion.max_results.map_or(usize::MAX, |max| max.saturating_add(1)))
+        .collect();
+    if let Some(e) = iter.take_error() {
+        return Err(e);
+    }
+    if iter.timed_out() {
+        warn!("Query of {}:{}-{} timed out after {}s, keeping the {} results found so far", target_name, target_start, target_end, expansion.timeout.unwrap().as_secs(), results.len());
+    }
+    let Some(max_results) = expansion.max_results else {
+        return Ok(results);
+    };
+    if results.len() > max_results {
+        match expansion.on_limit {
+            OnLimit::Error => return Err(io::Error::other(format!(
+                "Query of {}:{}-{} has more than {} results, raise --max-results or use --on-limit truncate", target_name, target_start, target_end, max_results
+            ))),
+            OnLimit::Truncate => {
+                warn!("Query of {}:{}-{} has more than {} results, keeping the first {}", target_name, target_start, target_end, max_results, max_results);
+                results.truncate(max_results);
+            },
+        }
+    }
+    Ok(results)
+}
+
+/// Drop the results whose query side lies on `target_id`.
+fn remove_self_overlaps(mut results: Vec<QueryResult>, target_id: u32) -> Vec<QueryResult> {
+    results.retain(|((overlap_query, _, _), _, _, _)| overlap_query.metadata != target_id);
+    results
+}
+
+/// Expand `range` by `padding` bp on each side, clamped to `[0, seq_len]`.
+fn pad_range(range: (i32, i32), padding: i32, seq_len: i32) -> (i32, i32) {
+    (range.0.saturating_sub(padding).max(0), range.1.saturating_add(padding).min(seq_len))
+}
+
+/// State shared by the queries of one run, which may run in parallel, and the writing of their results.
+struct QueryContext<'a> {
+    impg: &'a Impg,
+    args: &'a Args,
+    fetcher: Option<SequenceFetcher>,
+    /// Number of JSON objects written so far, to separate the elements of `--json-array`
+    json_records: AtomicUsize,
+    /// Sorted `--exclude-bed` ranges by sequence id
+    excluded: HashMap<u32, Vec<(i32, i32)>>,
+    /// Sorted `--include-bed` ranges by sequence id
+    included: Option<HashMap<u32, Vec<(i32, i32)>>>,
+    /// `--op-stats` totals over the results so far
+    op_totals: Mutex<OpTotals>,
+    /// Number of VCF breakend pairs written so far, to number their IDs
+    bnd_pairs: AtomicUsize,
+}
+
+/// Write results in the requested format; `auto` falls back to `default_format`.
+fn output_results(out: &mut dyn Write, ctx: &QueryContext, results: Vec<QueryResult>, target_name: &str, target_range: (i32, i32), name: Option<String>, default_format: OutputFormat) -> io::Result<()> {
+    let (impg, args) = (ctx.impg, ctx.args);
+    let mut results = results;
+    if args.bed_to_paf && !args.keep_input_row {
+        results = without_input_row(results);
+    }
+    if args.op_stats {
+        ctx.op_totals.lock().unwrap().add(results.iter().filter(|(_, _, _, input_row)| !input_row).map(|(result, _, _, _)| result));
+    }
+    if args.coverage {
+        return output_coverage_bedgraph(out, impg, &without_mapq(without_input_row(results)), target_name, target_range, args.bin);
+    }
+    let format = match requested_format(args) {
+        OutputFormat::Auto => default_format,
+        format => format,
+    };
+    let results = if args.merge_output {
+        // Merged results span several alignments, so their mapping quality is unknown
+        // The input row is kept apart, so that the merged rows are all alignments
+        let (input_rows, alignments): (Vec<_>, Vec<_>) = results.into_iter().partition(|(_, _, _, input_row)| *input_row);
+        let merged = merge_adjusted_intervals(without_mapq(alignments), args.merge_distance).into_iter().map(|merged| (merged, 255, Vec::new(), false));
+        input_rows.into_iter().chain(merged).collect()
+    } else {
+        results
+    };
+    if args.summarize_targets {
+        return output_target_summary(out, impg, &without_mapq(results), name);
+    }
+    let results = sort_results(impg, results, args.sort);
+    if args.invert {
+        return match args.invert_scope {
+            InvertScope::Sequence => output_complement_bed(out, impg, &without_mapq(results), name),
+            InvertScope::Query => {
+                let target_length = impg.seq_index.get_len_from_id(impg.seq_index.get_id(target_name).unwrap()).unwrap() as i32;
+                output_unaligned_query_bed(out, impg, &without_mapq(without_input_row(results)), target_name, pad_range(target_range, args.padding, target_length), name)
+            },
+        };
+    }
+    if let (true, Some(fetcher)) = (args.show, &ctx.fetcher) {
+        return output_results_alignment(out, impg, without_mapq(results), fetcher, args.wrap);
+    }
+    if let (true, Some(fetcher)) = (args.consensus, &ctx.fetcher) {
+        let target_length = impg.seq_index.get_len_from_id(impg.seq_index.get_id(target_name).unwrap()).unwrap() as i32;
+        let (start, end) = pad_range(target_range, args.padding, target_length);
+        let consensus = pileup_consensus(impg, &without_mapq(without_input_row(results)), fetcher, target_name, (start, end), args.min_depth)?;
+        let header = match name {
+            Some(name) => format!("{}:{}-{} {}", target_name, start, end, name),
+            None => format!("{}:{}-{}", target_name, start, end),
+        };
+        return write_fasta_record(out, &header, &consensus, args.wrap);
+    }
+    match format {
+        OutputFormat::Bed | OutputFormat::Auto => output_results_bed(out, impg, without_mapq(results), name),
+        OutputFormat::Bedpe => output_results_bedpe(out, impg, without_mapq(results), target_name, name, args.bedpe_extended, args.output_bedpe_cigar),
+        OutputFormat::Paf => output_results_paf(out, impg, results, target_name, name, args.cigar_orientation, &args.keep_tags),
+        OutputFormat::Bed12 => output_results_bed12(out, impg, without_mapq(results), name),
+        OutputFormat::Gaf => output_results_gaf(out, impg, results, name),
+        OutputFormat::Dotplot => output_results_dotplot(out, impg, without_mapq(results)),
+        OutputFormat::Sam => output_results_sam(out, ctx, results, name),
+        OutputFormat::Json => output_results_json(out, ctx, results, name),
+        OutputFormat::Vcf => output_results_vcf(out, ctx, without_mapq(results)),
+    }
+}
+
+/// The output format asked for on the command line, `auto` if it depends on the kind of query.
+fn requested_format(args: &Args) -> OutputFormat {
+    match args.output_format {
+        _ if args.output_paf || args.bed_to_paf => OutputFormat::Paf,
+        _ if args.json_array => OutputFormat::Json,
+        format => format,
+    }
+}
+
+/// Total bp of each CIGAR operation over a set of results.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct OpTotals {
+    /// Indexed like `OPS`
+    bp: [u64; 7],
+}
+
+impl OpTotals {
+    const OPS: [char; 7] = ['=', 'X', 'I', 'D', 'M', 'N', 'P'];
+
+    fn add<'a>(&mut self, results: impl Iterator<Item = &'a AdjustedInterval>) {
+        for (_, cigar, _) in results {
+            for op in cigar {
+                if let Some(index) = Self::OPS.iter().position(|&counted| counted == op.op()) {
+                    self.bp[index] += op.len() as u64;
+                }
+            }
+        }
+    }
+}
+
+/// Drop the queried range itself from `results`, keeping the alignments.
+fn without_input_row(mut results: Vec<QueryResult>) -> Vec<QueryResult> {
+    results.retain(|(_, _, _, input_row)| !input_row);
+    results
+}
+
+/// Stably sort `results` by target name and coordinates or by query name and coordinates.
+fn sort_results(impg: &Impg, mut results: Vec<QueryResult>, order: ResultOrder) -> Vec<QueryResult> {
+    let name = |id: u32| impg.seq_index.get_name(id).unwrap_or_default();
+    let span = |interval: &coitrees::Interval<u32>| (interval.first.min(interval.last), interval.first.max(interval.last));
+    match order {
+        ResultOrder::Target => results.sort_by(|((_, _, a), _, _, _), ((_, _, b), _, _, _)| (name(a.metadata), span(a)).cmp(&(name(b.metadata), span(b)))),
+        ResultOrder::Query => results.sort_by(|((a, _, _), _, _, _), ((b, _, _), _, _, _)| (name(a.metadata), span(a)).cmp(&(name(b.metadata), span(b)))),
+        ResultOrder::None => {},
+    }
+    results
+}
+
+/// Read the regions of `bed_file` as sorted, merged ranges by sequence id, skipping sequences missing from the index.
+fn load_region_mask(impg: &Impg, bed_file: &str) -> io::Result<HashMap<u32, Vec<(i32, i32)>>> {
+    let mut mask: HashMap<u32, Vec<(i32, i32)>> = HashMap::new();
+    for (seq_name, range, _) in parse_bed_file(bed_file)? {
+        match impg.seq_index.get_id(&seq_name) {
+            Some(seq_id) => mask.entry(seq_id).or_default().push(range),
+            None => warn!("Sequence '{}' of {} is not in the index, ignoring it", seq_name, bed_file),
+        }
+    }
+    Ok(mask.into_iter().map(|(seq_id, ranges)| (seq_id, merge_ranges(&ranges))).collect())
+}
+
+/// Cut the `excluded` query ranges out of `results`, keeping the unmasked pieces of each result.
+fn exclude_regions(results: Vec<QueryResult>, excluded: &HashMap<u32, Vec<(i32, i32)>>) -> Vec<QueryResult> {
+    results.into_iter().flat_map(|(result, mapq, trace, input_row)| match excluded.get(&result.0.metadata) {
+        Some(ranges) => subtract_query_regions(&result, ranges).into_iter().map(|piece| (piece, mapq, trace.clone(), input_row)).collect(),
+        None => vec![(result, mapq, trace, input_row)],
+    }).collect()
+}
+
+/// Keep the parts of `results` inside the `included` query ranges, one clipped piece per range.
+fn include_regions(results: Vec<QueryResult>, included: &HashMap<u32, Vec<(i32, i32)>>) -> Vec<QueryResult> {
+    results.into_iter().flat_map(|(result, mapq, trace, input_row)| {
+        let (start, end) = (result.0.first.min(result.0.last), result.0.first.max(result.0.last));
+        included.get(&result.0.metadata).into_iter().flatten()
+            .filter(|&&(range_start, range_end)| range_start < end && range_end > start)
+            .filter_map(|&(range_start, range_end)| clip_to_query_range(&result, range_start.max(start), range_end.min(end)))
+            .map(|piece| (piece, mapq, trace.clone(), input_row))
+            .collect::<Vec<_>>()
+    }).collect()
+}
+
+/// Query a single region and apply the result filters and checks requested on the command line.
+fn query_region(ctx: &QueryContext, target_name: &str, target_range: (i32, i32)) -> io::Result<Vec<QueryResult>> {
+    let (impg, args) = (ctx.impg, ctx.args);
+    let expansion = Expansion::from_args(args, &impg.seq_index)?;
+    let mut results = match args.repeat {
+        Some(repeat) => repeat_query(repeat.get(), || perform_query(impg, target_name, target_range, args.padding, expansion))?,
+        None => perform_query(impg, target_name, target_range, args.padding, expansion)?,
+    };
+    if args.no_self {
+        results = remove_self_overlaps(results, impg.seq_index.get_id(target_name).unwrap());
+    }
+    if args.clamp {
+        let target_id = impg.seq_index.get_id(target_name).unwrap();
+        let target_length = impg.seq_index.get_len_from_id(target_id).unwrap() as i32;
+        let target_range = pad_range(target_range, args.padding, target_length);
+        results = results.into_iter().filter_map(|(result, mapq, trace, input_row)| {
+            if result.2.metadata == target_id {
+                clip_to_target_range(&result, target_range.0, target_range.1).map(|clipped| (clipped, mapq, trace, input_row))
+            } else {
+                Some((result, mapq, trace, input_row))
+            }
+        }).collect();
+    }
+    if let Some(included) = &ctx.included {
+        results = include_regions(results, included);
+    }
+    if !ctx.excluded.is_empty() {
+        results = exclude_regions(results, &ctx.excluded);
+    }
+    if let (true, Some(fetcher)) = (args.realign_md, &ctx.fetcher) {
+        results = refine_results(impg, results, fetcher)?;
+    }
+    let results = filter_results(impg, results, args);
+    if args.check_intervals {
+        let intervals: Vec<AdjustedInterval> = results.iter().map(|(adjusted_interval, _, _, _)| adjusted_interval.clone()).collect();
+        let invalid_cigars = check_intervals(impg, &intervals);
+        if !invalid_cigars.is_empty() {
+            for (row, error_reason, divergence) in invalid_cigars {
+                match divergence {
+                    Some((query_pos, target_pos, expected_len, actual_len)) => eprintln!("{}; first divergence at query {}, target {} (expected length {}, got {}); {}", error_reason, query_pos, target_pos, expected_len, actual_len, row),
+                    None => eprintln!("{}; {}", error_reason, row),
+                }
+            }
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid intervals encountered"));
+        }
+    }
+    Ok(results)
+}
+
+/// Run `query` `repeat` times, logging how long each run and the median run took, and return the
+/// results of the last run.
+fn repeat_query(repeat: usize, mut query: impl FnMut() -> io::Result<Vec<QueryResult>>) -> io::Result<Vec<QueryResult>> {
+    let mut times = Vec::with_capacity(repeat);
+    let mut results = Vec::new();
+    for run in 1..=repeat {
+        let start = Instant::now();
+        results = query()?;
+        times.push(start.elapsed());
+        info!("Query run {} of {}: {:.6}s, {} results", run, repeat, times[run - 1].as_secs_f64(), results.len());
+    }
+    times.sort();
+    info!("Median query time over {} runs: {:.6}s", repeat, times[repeat / 2].as_secs_f64());
+    Ok(results)
+}
+
+/// Reclassify the `M` operations of each result into `=`/`X` using the sequences from `fetcher`.
+fn refine_results(impg: &Impg, results: Vec<QueryResult>, fetcher: &SequenceFetcher) -> io::Result<Vec<QueryResult>> {
+    results.into_iter().map(|((overlap_query, cigar, overlap_target), mapq, trace, input_row)| {
+        if !cigar.iter().any(|op| op.op() == 'M') {
+            return Ok(((overlap_query, cigar, overlap_target), mapq, trace, input_row));
+        }
+        let query_name = impg.seq_index.get_name(overlap_query.metadata).unwrap();
+        let target_name = impg.seq_index.get_name(overlap_target.metadata).unwrap();
+        let (first, last) = (overlap_query.first.min(overlap_query.last), overlap_query.first.max(overlap_query.last));
+        let target_seq = fetcher.fetch(target_name, overlap_target.first, overlap_target.last, false)?;
+        let query_seq = fetcher.fetch(query_name, first, last, overlap_query.first > overlap_query.last)?;
+        let cigar = refine_match_ops(&cigar, &target_seq, &query_seq);
+        Ok(((overlap_query, cigar, overlap_target), mapq, trace, input_row))
+    }).collect()
+}
+
+fn filter_results(impg: &Impg, mut results: Vec<QueryResult>, args: &Args) -> Vec<QueryResult> {
+    if !args.target_prefix.is_empty() {
+        results = filter_by_prefix(impg, results, &args.target_prefix, args.pansn.then_some(args.prefix_delimiter));
+    }
+    match args.strand {
+        StrandFilter::Forward => results.retain(|((overlap_query, _, _), _, _, _)| overlap_query.first <= overlap_query.last),
+        StrandFilter::Reverse => results.retain(|((overlap_query, _, _), _, _, _)| overlap_query.first > overlap_query.last),
+        StrandFilter::Both => {},
+    }
+    if let Some(min_block_length) = args.min_block_length {
+        results.retain(|((_, cigar, _), _, _, _)| CigarStats::from_cigar(cigar).block_len() >= min_block_length);
+    }
+    if let Some(min_identity) = args.min_identity {
+        results.retain(|((_, cigar, _), _, _, _)| {
+            let stats = CigarStats::from_cigar(cigar);
+            let identity = match args.identity_metric {
+                IdentityMetric::Gap => stats.gap_compressed_identity(),
+                IdentityMetric::Block => stats.block_identity(),
+            };
+            identity >= min_identity
+        });
+    }
+    results
+}
+
+/// Keep the results on sequences matching one of `prefixes`, as whole `delimiter`-separated fields if given.
+fn filter_by_prefix(impg: &Impg, mut results: Vec<QueryResult>, prefixes: &[String], delimiter: Option<char>) -> Vec<QueryResult> {
+    results.retain(|((overlap_query, _, _), _, _, _)| {
+        let name = impg.seq_index.get_name(overlap_query.metadata).unwrap();
+        prefixes.iter().any(|prefix| matches_prefix(name, prefix, delimiter))
+    });
+    results
+}
+
+/// Whether `name` starts with `prefix`, or with its fields when split on `delimiter`. A trailing
+/// delimiter on `prefix` is optional in the latter case.
+fn matches_prefix(name: &str, prefix: &str, delimiter: Option<char>) -> bool {
+    match delimiter {
+        None => name.starts_with(prefix),
+        Some(delimiter) => {
+            let mut fields = name.split(delimiter);
+            prefix.strip_suffix(delimiter).unwrap_or(prefix).split(delimiter).all(|field| fields.next() == Some(field))
+        },
+    }
+}
+
+fn output_results_bed(out: &mut dyn Write, impg: &Impg, results: Vec<AdjustedInterval>, name: Option<String>) -> io::Result<()> {
+    let name = name.as_deref().unwrap_or(".");
+    for (overlap, _, _) in results {
+        let overlap_name = impg.seq_index.get_name(overlap.metadata).unwrap();
+        let (first, last, strand) = if overlap.first <= overlap.last {
+            (overlap.first, overlap.last, '+')
+        } else {
+            (overlap.last, overlap.first, '-')
+        };
+        writeln!(out, "{}\t{}\t{}\t{}\t{}", overlap_name, first, last, name, strand)?;
+    }
+    Ok(())
+}
+
+/// Write the alignment depth over `target_range` of `target_name` as BEDGRAPH, either as runs of
+/// equal depth or as the mean depth of `bin` bp windows.
+fn output_coverage_bedgraph(out: &mut dyn Write, impg: &Impg, results: &[AdjustedInterval], target_name: &str, target_range: (i32, i32), bin: Option<i32>) -> io::Result<()> {
+    let target_id = impg.seq_index.get_id(target_name).unwrap();
+    let spans: Vec<(i32, i32)> = results.iter()
+        .filter(|result| result.2.metadata == target_id)
+        .map(|(_, _, target)| (target.first, target.last))
+        .collect();
+    let runs = coverage_runs(&spans, target_range.0, target_range.1);
+    match bin {
+        Some(bin) => {
+            for (start, end, depth) in bin_coverage(&runs, target_range.0, target_range.1, bin) {
+                writeln!(out, "{}\t{}\t{}\t{:.2}", target_name, start, end, depth)?;
+            }
+        },
+        None => {
+            for (start, end, depth) in runs {
+                writeln!(out, "{}\t{}\t{}\t{}", target_name, start, end, depth)?;
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Sweep the half-open `spans` and return the runs of equal depth tiling `[start, end)`.
+fn coverage_runs(spans: &[(i32, i32)], start: i32, end: i32) -> Vec<(i32, i32, usize)> {
+    let mut events: Vec<(i32, i32)> = spans.iter()
+        .map(|&(first, last)| (first.max(start), last.min(end)))
+        .filter(|(first, last)| first < last)
+        .flat_map(|(first, last)| [(first, 1), (last, -1)])
+        .collect();
+    events.sort_unstable();
+
+    let mut runs = Vec::new();
+    let (mut pos, mut depth) = (start, 0i32);
+    for (event_pos, change) in events {
+        if event_pos > pos {
+            runs.push((pos, event_pos, depth as usize));
+            pos = event_pos;
+        }
+        depth += change;
+    }
+    if pos < end {
+        runs.push((pos, end, 0));
+    }
+    runs
+}
+
+/// Average depth `runs` over consecutive `bin` bp windows of `[start, end)`, the last one possibly shorter.
+fn bin_coverage(runs: &[(i32, i32, usize)], start: i32, end: i32, bin: i32) -> Vec<(i32, i32, f64)> {
+    let mut bins = Vec::new();
+    let mut runs = runs.iter().peekable();
+    let mut bin_start = start;
+    while bin_start < end {
+        let bin_end = bin_start.saturating_add(bin).min(end);
+        let mut covered_bp = 0u64;
+        while let Some(&&(run_start, run_end, depth)) = runs.peek() {
+            covered_bp += (run_end.min(bin_end) - run_start.max(bin_start)).max(0) as u64 * depth as u64;
+            if run_end > bin_end {
+                break;
+            }
+            runs.next();
+        }
+        bins.push((bin_start, bin_end, covered_bp as f64 / (bin_end - bin_start) as f64));
+        bin_start = bin_end;
+    }
+    bins
+}
+
+/// Write the parts of each sequence not covered by `results`, for every sequence they involve.
+fn output_complement_bed(out: &mut dyn Write, impg: &Impg, results: &[AdjustedInterval], name: Option<String>) -> io::Result<()> {
+    let name = name.as_deref().unwrap_or(".");
+    for (seq_id, gaps) in uncovered_ranges(impg, results) {
+        let seq_name = impg.seq_index.get_name(seq_id).unwrap();
+        for (start, end) in gaps {
+            writeln!(out, "{}\t{}\t{}\t{}", seq_name, start, end, name)?;
+        }
+    }
+    Ok(())
+}
+
+fn output_unaligned_query_bed(out: &mut dyn Write, impg: &Impg, results: &[AdjustedInterval], target_name: &str, target_range: (i32, i32), name: Option<String>) -> io::Result<()> {
+    let name = name.as_deref().unwrap_or(".");
+    for (start, end) in unaligned_query_ranges(impg, results, target_name, target_range) {
+        writeln!(out, "{}\t{}\t{}\t{}", target_name, start, end, name)?;
+    }
+    Ok(())
+}
+
+fn output_target_summary(out: &mut dyn Write, impg: &Impg, results: &[AdjustedInterval], name: Option<String>) -> io::Result<()> {
+    let name = name.as_deref().unwrap_or(".");
+    for (seq_id, spans) in target_spans(results) {
+        let seq_name = impg.seq_index.get_name(seq_id).unwrap();
+        for (start, end) in spans {
+            writeln!(out, "{}\t{}\t{}\t{}", seq_name, start, end, name)?;
+        }
+    }
+    Ok(())
+}
+
+/// Group the target side of `results` by sequence and return, in sequence id order, the union of
+/// the spans on each sequence.
+fn target_spans(results: &[AdjustedInterval]) -> Vec<(u32, Vec<(i32, i32)>)> {
+    let mut spans: BTreeMap<u32, Vec<(i32, i32)>> = BTreeMap::new();
+    for (_, _, overlap_target) in results {
+        spans.entry(overlap_target.metadata).or_default().push((overlap_target.first, overlap_target.last));
+    }
+    spans.into_iter().map(|(seq_id, ranges)| (seq_id, merge_ranges(&ranges))).collect()
+}
+
+/// Group the query side of `results` by sequence and return, in sequence id order, the ranges of
+/// each sequence that no result covers.
+fn uncovered_ranges(impg: &Impg, results: &[AdjustedInterval]) -> Vec<(u32, Vec<(i32, i32)>)> {
+    let mut covered: BTreeMap<u32, Vec<(i32, i32)>> = BTreeMap::new();
+    for (overlap_query, _, _) in results {
+        covered.entry(overlap_query.metadata).or_default()
+            .push((overlap_query.first.min(overlap_query.last), overlap_query.first.max(overlap_query.last)));
+    }
+    covered.into_iter().map(|(seq_id, ranges)| {
+        let seq_len = impg.seq_index.get_len_from_id(seq_id).unwrap() as i32;
+        (seq_id, complement_ranges(&ranges, 0, seq_len))
+    }).collect()
+}
+
+/// The parts of `target_range` on `target_name` that the target side of no alignment in `results`
+/// covers, which must not hold the queried range itself.
+fn unaligned_query_ranges(impg: &Impg, results: &[AdjustedInterval], target_name: &str, target_range: (i32, i32)) -> Vec<(i32, i32)> {
+    let target_id = impg.seq_index.get_id(target_name).unwrap();
+    let aligned: Vec<(i32, i32)> = results.iter()
+        .filter(|result| result.2.metadata == target_id)
+        .map(|(_, _, overlap_target)| (overlap_target.first, overlap_target.last))
+        .collect();
+    complement_ranges(&aligned, target_range.0, target_range.1)
+}
+
+fn output_results_bedpe(out: &mut dyn Write, impg: &Impg, results: Vec<AdjustedInterval>, target_name: &str, name: Option<String>, with_identity: bool, with_cigar: bool) -> io::Result<()> {
+    for (overlap_query, cigar, overlap_target) in results {
+        let overlap_name = impg.seq_index.get_name(overlap_query.metadata).unwrap();
+        let (first, last, strand) = if overlap_query.first <= overlap_query.last {
+            (overlap_query.first, overlap_query.last, '+')
+        } else {
+            (overlap_query.last, overlap_query.first, '-')
+        };
+        write!(out, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t0\t{}\t+",
+               overlap_name, first, last,
+               target_name, overlap_target.first, overlap_target.last,
+               name.as_deref().unwrap_or("."), strand)?;
+        if with_identity {
+            let stats = CigarStats::from_cigar(&cigar);
+            write!(out, "\tgi:f:{:.6}\tbi:f:{:.6}", stats.gap_compressed_identity(), stats.block_identity())?;
+        }
+        if with_cigar {
+            write!(out, "\tcg:Z:{}", cigar_to_str(&cigar))?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+fn output_results_bed12(out: &mut dyn Write, impg: &Impg, results: Vec<AdjustedInterval>, name: Option<String>) -> io::Result<()> {
+    for (overlap_query, cigar, _) in results {
+        let overlap_name = impg.seq_index.get_name(overlap_query.metadata).unwrap();
+        let (first, last, strand) = if overlap_query.first <= overlap_query.last {
+            (overlap_query.first, overlap_query.last, '+')
+        } else {
+            (overlap_query.last, overlap_query.first, '-')
+        };
+        let mut blocks = cigar_to_query_blocks(&cigar, overlap_query.first, overlap_query.last);
+        if blocks.is_empty() {
+            // BED12 needs at least one block
+            blocks.push((first, last));
+        }
+        let block_sizes: String = blocks.iter().map(|(start, end)| format!("{},", end - start)).collect();
+        let block_starts: String = blocks.iter().map(|(start, _)| format!("{},", start - first)).collect();
+        writeln!(out, "{}\t{}\t{}\t{}\t0\t{}\t{}\t{}\t0\t{}\t{}\t{}",
+                 overlap_name, first, last, name.as_deref().unwrap_or("."), strand,
+                 first, last, blocks.len(), block_sizes, block_starts)?;
+    }
+    Ok(())
+}
+
+fn output_results_paf(out: &mut dyn Write, impg: &Impg, results: Vec<QueryResult>, target_name: &str, name: Option<String>, orientation: CigarOrientation, keep_tags: &[String]) -> io::Result<()> {
+    let target_length = impg.seq_index.get_len_from_id(impg.seq_index.get_id(target_name).unwrap()).unwrap();  
+    for ((overlap_query, cigar, overlap_target), mapq, trace, _) in results {
+        let overlap_name = impg.seq_index.get_name(overlap_query.metadata).unwrap();
+        let (first, last, strand) = if overlap_query.first <= overlap_query.last {
+            (overlap_query.first, overlap_query.last, '+')
+        } else {
+            (overlap_query.last, overlap_query.first, '-')
+        };
+
+        let query_length = impg.seq_index.get_len_from_id(overlap_query.metadata).unwrap();  
+
+        // We overestimate the number of matches by counting all M operations, unless refined with --realign-md
+        let stats = CigarStats::from_cigar(&cigar);
+        let (matches, block_len) = (stats.matches, stats.block_len());
+        let cigar_str = match orientation {
+            CigarOrientation::Query => cigar_to_str(&cigar),
+            CigarOrientation::Target => cigar_to_str(&invert_cigar(&cigar, if strand == '+' { Strand::Forward } else { Strand::Reverse })),
+        };
+
+        write!(out, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\tNM:i:{}\tcg:Z:{}",
+               overlap_name, query_length, first, last, strand,
+               target_name, target_length, overlap_target.first, overlap_target.last,
+               matches, block_len, mapq, stats.edit_distance(), cigar_str)?;
+        if !keep_tags.is_empty() {
+            if let Some(alignment) = impg.source_alignment(&(overlap_query, cigar, overlap_target)) {
+                for field in impg.optional_fields(&alignment)? {
+                    if keep_tags.iter().any(|tag| field.split(':').next() == Some(tag.as_str())) {
+                        write!(out, "\t{}", field)?;
+                    }
+                }
+            }
+        }
+        if !trace.is_empty() {
+            write!(out, "\ttr:Z:{}", format_trace(&trace))?;
+        }
+        match name {
+            Some(ref name) => writeln!(out, "\tan:Z:{}", name)?,
+            None => writeln!(out)?,
+        }
+    }
+    Ok(())
+}
+
+/// Join record ids as comma-separated `paf_file_index:byte_offset` pairs.
+fn format_trace(trace: &[RecordId]) -> String {
+    trace.iter().map(|(paf_file_index, offset)| format!("{}:{}", paf_file_index, offset)).collect::<Vec<_>>().join(",")
+}
+
+/// Write a minimal VCF header declaring every indexed sequence as a contig.
+fn write_vcf_header(out: &mut dyn Write, impg: &Impg) -> io::Result<()> {
+    writeln!(out, "##fileformat=VCFv4.2")?;
+    for id in impg.seq_index.ids() {
+        writeln!(out, "##contig=<ID={},length={}>", impg.seq_index.get_name(id).unwrap(), impg.seq_index.get_len_from_id(id).unwrap())?;
+    }
+    writeln!(out, "##INFO=<ID=SVTYPE,Number=1,Type=String,Description=\"Type of structural variant\">")?;
+    writeln!(out, "##INFO=<ID=MATEID,Number=.,Type=String,Description=\"ID of mate breakend\">")?;
+    writeln!(out, "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO")
+}
+
+/// Write a SAM header declaring every indexed sequence as a reference.
+fn write_sam_header(out: &mut dyn Write, impg: &Impg) -> io::Result<()> {
+    writeln!(out, "@HD\tVN:1.6\tSO:unsorted")?;
+    for id in impg.seq_index.ids() {
+        writeln!(out, "@SQ\tSN:{}\tLN:{}", impg.seq_index.get_name(id).unwrap(), impg.seq_index.get_len_from_id(id).unwrap())?;
+    }
+    Ok(())
+}
+
+/// Write each result as a SAM record of its query side aligned to its target side, reverse
+/// complemented on the reverse strand. The rest of the query sequence is hard-clipped, as the
+/// flanks of whole chromosomes are too long to carry as soft-clipped bases. SEQ is `*` without `--fasta`.
+fn output_results_sam(out: &mut dyn Write, ctx: &QueryContext, results: Vec<QueryResult>, name: Option<String>) -> io::Result<()> {
+    let impg = ctx.impg;
+    for ((overlap_query, cigar, overlap_target), mapq, _, _) in results {
+        let query_name = impg.seq_index.get_name(overlap_query.metadata).unwrap();
+        let query_length = impg.seq_index.get_len_from_id(overlap_query.metadata).unwrap() as i32;
+        let reverse = overlap_query.first > overlap_query.last;
+        let (first, last) = (overlap_query.first.min(overlap_query.last), overlap_query.first.max(overlap_query.last));
+        // The CIGAR follows the target, which runs along the reverse complement of reverse-strand queries
+        let (left_clip, right_clip) = if reverse { (query_length - last, first) } else { (first, query_length - last) };
+        let clip = |len: i32| if len > 0 { format!("{}H", len) } else { String::new() };
+        let sequence = match &ctx.fetcher {
+            Some(fetcher) => String::from_utf8(fetcher.fetch(query_name, first, last, reverse)?)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            None => "*".to_string(),
+        };
+
+        write!(out, "{}\t{}\t{}\t{}\t{}\t{}{}{}\t*\t0\t0\t{}\t*\tNM:i:{}",
+               query_name, if reverse { 16 } else { 0 },
+               impg.seq_index.get_name(overlap_target.metadata).unwrap(), overlap_target.first + 1, mapq,
+               clip(left_clip), cigar_to_str(&cigar), clip(right_clip), if sequence.is_empty() { "*" } else { &sequence },
+               CigarStats::from_cigar(&cigar).edit_distance())?;
+        match name {
+            Some(ref name) => writeln!(out, "\tan:Z:{}", name)?,
+            None => writeln!(out)?,
+        }
+    }
+    Ok(())
+}
+
+/// Write each result onto another sequence or the reverse strand as a pair of VCF breakends
+/// joining the start of its target side to the matching end of its query side. The bases are
+/// not known, so REF is `N`. Forward results on the target sequence are skipped.
+fn output_results_vcf(out: &mut dyn Write, ctx: &QueryContext, results: Vec<AdjustedInterval>) -> io::Result<()> {
+    for (overlap_query, _, overlap_target) in results {
+        let forward = overlap_query.first <= overlap_query.last;
+        if overlap_query.metadata == overlap_target.metadata && forward {
+            continue;
+        }
+        // Transitive results lie on other targets than the queried one
+        let target_name = ctx.impg.seq_index.get_name(overlap_target.metadata).unwrap();
+        let query_name = ctx.impg.seq_index.get_name(overlap_query.metadata).unwrap();
+        let target_pos = overlap_target.first + 1;
+        // 1-based query base aligned to the first target base, and the brackets orienting the join
+        let (query_pos, target_alt, query_alt) = if forward {
+            let query_pos = overlap_query.first + 1;
+            (query_pos, format!("N[{}:{}[", query_name, query_pos), format!("]{}:{}]N", target_name, target_pos))
+        } else {
+            let query_pos = overlap_query.first;
+            (query_pos, format!("N]{}:{}]", query_name, query_pos), format!("N]{}:{}]", target_name, target_pos))
+        };
+
+        let pair = ctx.bnd_pairs.fetch_add(1, Ordering::Relaxed) + 1;
+        writeln!(out, "{}\t{}\tbnd_{}_1\tN\t{}\t.\tPASS\tSVTYPE=BND;MATEID=bnd_{}_2", target_name, target_pos, pair, target_alt, pair)?;
+        writeln!(out, "{}\t{}\tbnd_{}_2\tN\t{}\t.\tPASS\tSVTYPE=BND;MATEID=bnd_{}_1", query_name, query_pos, pair, query_alt, pair)?;
+    }
+    Ok(())
+}
+
+/// Write results as GAF, with the query as the read and the target as a one-segment path.
+/// Reverse-strand results walk the target backwards (`<target`), so the path coordinates are
+/// taken from the end of the target and the CIGAR is reversed to follow the path.
+fn output_results_gaf(out: &mut dyn Write, impg: &Impg, results: Vec<QueryResult>, name: Option<String>) -> io::Result<()> {
+    for ((overlap_query, cigar, overlap_target), mapq, _, _) in results {
+        // Transitive results lie on other targets than the queried one
+        let target_name = impg.seq_index.get_name(overlap_target.metadata).unwrap();
+        let target_length = impg.seq_index.get_len_from_id(overlap_target.metadata).unwrap() as i32;
+        let overlap_name = impg.seq_index.get_name(overlap_query.metadata).unwrap();
+        let query_length = impg.seq_index.get_len_from_id(overlap_query.metadata).unwrap();
+        let stats = CigarStats::from_cigar(&cigar);
+        let (matches, block_len) = (stats.matches, stats.block_len());
+
+        let (first, last, path, path_start, path_end, cigar_str) = if overlap_query.first <= overlap_query.last {
+            let cigar_str = cigar_to_str(&cigar);
+            (overlap_query.first, overlap_query.last, format!(">{}", target_name), overlap_target.first, overlap_target.last, cigar_str)
+        } else {
+            let cigar_str = cigar_to_str(&cigar.iter().rev().cloned().collect::<Vec<_>>());
+            (overlap_query.last, overlap_query.first, format!("<{}", target_name), target_length - overlap_target.last, target_length - overlap_target.first, cigar_str)
+        };
+
+        write!(out, "{}\t{}\t{}\t{}\t+\t{}\t{}\t{}\t{}\t{}\t{}\t{}\tcg:Z:{}",
+               overlap_name, query_length, first, last,
+               path, target_length, path_start, path_end,
+               matches, block_len, mapq, cigar_str)?;
+        match name {
+            Some(ref name) => writeln!(out, "\tan:Z:{}", name)?,
+            None => writeln!(out)?,
+        }
+    }
+    Ok(())
+}
+
+fn output_results_dotplot(out: &mut dyn Write, impg: &Impg, results: Vec<AdjustedInterval>) -> io::Result<()> {
+    for (overlap_query, cigar, overlap_target) in results {
+        let query_name = impg.seq_index.get_name(overlap_query.metadata).unwrap();
+        let target_name = impg.seq_index.get_name(overlap_target.metadata).unwrap();
+        for (query_pos, target_pos) in cigar_anchor_points(&cigar, overlap_query.first, overlap_query.last, overlap_target.first) {
+            writeln!(out, "{}\t{}\t{}\t{}", query_name, target_name, query_pos, target_pos)?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    query_name: &'a str,
+    query_start: i32,
+    query_end: i32,
+    strand: char,
+    target_name: &'a str,
+    target_start: i32,
+    target_end: i32,
+    cigar: String,
+    matches: i32,
+    mismatches: i32,
+    insertions: i32,
+    inserted_bp: i32,
+    deletions: i32,
+    deleted_bp: i32,
+    gap_compressed_identity: f64,
+    block_identity: f64,
+    mapq: u8,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    trace: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<&'a str>,
+}
+
+impl<'a> JsonRecord<'a> {
+    fn new(impg: &'a Impg, ((overlap_query, cigar, overlap_target), mapq, trace, _): &QueryResult, name: Option<&'a str>) -> Self {
+        let (query_start, query_end, strand) = if overlap_query.first <= overlap_query.last {
+            (overlap_query.first, overlap_query.last, '+')
+        } else {
+            (overlap_query.last, overlap_query.first, '-')
+        };
+        let stats = CigarStats::from_cigar(cigar);
+        JsonRecord {
+            query_name: impg.seq_index.get_name(overlap_query.metadata).unwrap(),
+            query_start,
+            query_end,
+            strand,
+            target_name: impg.seq_index.get_name(overlap_target.metadata).unwrap(),
+            target_start: overlap_target.first,
+            target_end: overlap_target.last,
+            cigar: cigar_to_str(cigar),
+            matches: stats.matches,
+            mismatches: stats.mismatches,
+            insertions: stats.insertions,
+            inserted_bp: stats.inserted_bp,
+            deletions: stats.deletions,
+            deleted_bp: stats.deleted_bp,
+            gap_compressed_identity: stats.gap_compressed_identity(),
+            block_identity: stats.block_identity(),
+            mapq: *mapq,
+            trace: trace.iter().map(|record_id| format_trace(&[*record_id])).collect(),
+            name,
+        }
+    }
+}
+
+/// Write results as JSON objects, one per line, or as elements of the `--json-array` array.
+fn output_results_json(out: &mut dyn Write, ctx: &QueryContext, results: Vec<QueryResult>, name: Option<String>) -> io::Result<()> {
+    for result in &results {
+        let record = JsonRecord::new(ctx.impg, result, name.as_deref());
+        if ctx.args.json_array {
+            write!(out, "{}", if ctx.json_records.load(Ordering::Relaxed) == 0 { "\n" } else { ",\n" })?;
+            serde_json::to_writer(&mut *out, &record)?;
+        } else {
+            serde_json::to_writer(&mut *out, &record)?;
+            writeln!(out)?;
+        }
+        ctx.json_records.fetch_add(1, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+fn output_results_alignment(out: &mut dyn Write, impg: &Impg, results: Vec<AdjustedInterval>, fetcher: &SequenceFetcher, wrap: usize) -> io::Result<()> {
+    for (overlap_query, cigar, overlap_target) in results {
+        let query_name = impg.seq_index.get_name(overlap_query.metadata).unwrap();
+        let target_name = impg.seq_index.get_name(overlap_target.metadata).unwrap();
+        let (first, last, strand) = if overlap_query.first <= overlap_query.last {
+            (overlap_query.first, overlap_query.last, '+')
+        } else {
+            (overlap_query.last, overlap_query.first, '-')
+        };
+
+        let target_seq = fetcher.fetch(target_name, overlap_target.first, overlap_target.last, false)?;
+        let query_seq = fetcher.fetch(query_name, first, last, strand == '-')?;
+
+        writeln!(out, "{}:{}-{} {} {}:{}-{}", target_name, overlap_target.first, overlap_target.last, strand, query_name, first, last)?;
+        write!(out, "{}", format_alignment(&target_seq, &query_seq, &cigar, wrap))?;
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// The base aligned most often to each position of `target_range` on `target_name` by the
+/// alignments in `results`, or `N` where fewer than `min_depth` bases are aligned.
+/// Ties go to the first of A, C, G, T.
+fn pileup_consensus(impg: &Impg, results: &[AdjustedInterval], fetcher: &SequenceFetcher, target_name: &str, target_range: (i32, i32), min_depth: u32) -> io::Result<Vec<u8>> {
+    let target_id = impg.seq_index.get_id(target_name).unwrap();
+    let (range_start, range_end) = target_range;
+    let mut counts = vec![[0u32; 4]; (range_end - range_start).max(0) as usize];
+    for result in results.iter().filter(|result| result.2.metadata == target_id) {
+        let (overlap_query, cigar, overlap_target) = result;
+        let query_name = impg.seq_index.get_name(overlap_query.metadata).unwrap();
+        let reverse = overlap_query.first > overlap_query.last;
+        let query_seq = fetcher.fetch(query_name, overlap_query.first.min(overlap_query.last), overlap_query.first.max(overlap_query.last), reverse)?;
+
+        let (mut target_pos, mut query_pos) = (overlap_target.first, 0);
+        for op in cigar {
+            match op.op() {
+                'I' => query_pos += op.len() as usize,
+                'D' | 'N' => target_pos += op.len(),
+                'P' => {},
+                _ => for _ in 0..op.len() {
+                    let base = match query_seq.get(query_pos).map(u8::to_ascii_uppercase) {
+                        Some(b'A') => Some(0),
+                        Some(b'C') => Some(1),
+                        Some(b'G') => Some(2),
+                        Some(b'T') => Some(3),
+                        _ => None,
+                    };
+                    if let (Some(base), true) = (base, (range_start..range_end).contains(&target_pos)) {
+                        counts[(target_pos - range_start) as usize][base] += 1;
+                    }
+                    target_pos += 1;
+                    query_pos += 1;
+                },
+            }
+        }
+    }
+
+    Ok(counts.iter().map(|column| {
+        let (base, _) = column.iter().enumerate().rev().max_by_key(|(_, &count)| count).unwrap();
+        let depth: u32 = column.iter().sum();
+        if depth >= min_depth && depth > 0 { b"ACGT"[base] } else { b'N' }
+    }).collect())
+}
+
+/// Write `sequence` as a FASTA record with lines of `wrap` bases.
+fn write_fasta_record(out: &mut dyn Write, header: &str, sequence: &[u8], wrap: usize) -> io::Result<()> {
+    writeln!(out, ">{}", header)?;
+    for line in sequence.chunks(wrap.max(1)) {
+        out.write_all(line)?;
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// Lay out the target and query sequences with gaps per the CIGAR, marking matches with `|`
+/// and mismatches with `.`, wrapped every `wrap` columns.
+fn format_alignment(target_seq: &[u8], query_seq: &[u8], cigar: &[CigarOp], wrap: usize) -> String {
+    let (mut target_line, mut query_line, mut match_line) = (Vec::new(), Vec::new(), Vec::new());
+    let (mut target_pos, mut query_pos) = (0, 0);
+    for op in cigar {
+        for _ in 0..op.len() {
+            let (t, q) = match op.op() {
+                'I' => {
+                    query_pos += 1;
+                    (b'-', query_seq.get(query_pos - 1).copied().unwrap_or(b'N'))
+                },
+                'D' | 'N' => {
+                    target_pos += 1;
+                    (target_seq.get(target_pos - 1).copied().unwrap_or(b'N'), b'-')
+                },
+                'P' => continue,
+                _ => {
+                    target_pos += 1;
+                    query_pos += 1;
+                    (target_seq.get(target_pos - 1).copied().unwrap_or(b'N'), query_seq.get(query_pos - 1).copied().unwrap_or(b'N'))
+                },
+            };
+            target_line.push(t);
+            query_line.push(q);
+            match_line.push(if t == b'-' || q == b'-' {
+                b' '
+            } else if t.eq_ignore_ascii_case(&q) {
+                b'|'
+            } else {
+                b'.'
+            });
+        }
+    }
+
+    let mut text = String::new();
+    let wrap = wrap.max(1);
+    for ((t, m), q) in target_line.chunks(wrap).zip(match_line.chunks(wrap)).zip(query_line.chunks(wrap)) {
+        text.push_str(&format!("T {}\n  {}\nQ {}\n", String::from_utf8_lossy(t), String::from_utf8_lossy(m), String::from_utf8_lossy(q)));
+    }
+    text
+}
+
+/// Write every interval stored on `seq_name` as a tab-delimited row, sorted by target start.
+fn dump_tree(out: &mut dyn Write, impg: &Impg, seq_name: &str) -> io::Result<()> {
+    let target_id = impg.seq_index.get_id(seq_name).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Sequence '{}' not found in index", seq_name)))?;
+    writeln!(out, "#target\ttarget_start\ttarget_end\tquery\tquery_start\tquery_end\tstrand\tcigar_offset\tcigar_bytes")?;
+    for (first, last, metadata) in impg.tree_entries(target_id) {
+        let strand = if metadata.strand() == Strand::Forward { '+' } else { '-' };
+        writeln!(out, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                 seq_name, first, last,
+                 impg.seq_index.get_name(metadata.query_id()).unwrap(), metadata.query_start(), metadata.query_end(), strand,
+                 metadata.cigar_offset(), metadata.cigar_bytes())?;
+    }
+    Ok(())
+}
+
+fn print_stats(impg: &Impg, sort_by: StatsSortBy, top: Option<usize>) {
+    println!("Number of sequences: {}", impg.seq_index.len());
+    println!("Number of overlaps: {}", impg.trees.values().map(|tree| tree.len()).sum::<usize>());
+    println!("Total sequence length: {}", impg.seq_index.lengths().sum::<usize>());
+
+    // Bucket sequence lengths by order of magnitude, from <1kb to >=100Mb
+    let bounds = [1_000, 10_000, 100_000, 1_000_000, 10_000_000, 100_000_000];
+    let mut buckets = vec![0; bounds.len() + 1];
+    for len in impg.seq_index.lengths() {
+        buckets[bounds.iter().take_while(|&&bound| len >= bound).count()] += 1;
+    }
+    println!("Sequence length histogram:");
+    for (i, count) in buckets.iter().enumerate() {
+        let lower = if i == 0 { 0 } else { bounds[i - 1] };
+        match bounds.get(i) {
+            Some(upper) => println!("  [{}, {})\t{}", lower, upper, count),
+            None => println!("  [{}, inf)\t{}", lower, count),
+        }
+    }
+
+    println!("#target\trecords\taligned_bp\tmean_length");
+    for (name, records, aligned_bp) in target_stats(impg, sort_by, top) {
+        println!("{}\t{}\t{}\t{:.2}", name, records, aligned_bp, aligned_bp as f64 / records as f64);
+    }
+}
+
+/// Print the size of the index in memory and on disk, and how long it took to load or build.
+fn print_index_stats(impg: &Impg, index_file: &str, index_time: std::time::Duration) {
+    let nodes: usize = impg.trees.values().map(|tree| tree.len()).sum();
+    println!("Index:");
+    println!("  trees\t{}", impg.trees.len());
+    println!("  nodes\t{}", nodes);
+    println!("  min_mapq\t{}", impg.min_mapq.map_or("NA".to_string(), |min_mapq| min_mapq.to_string()));
+    println!("  tree_bytes\t{}", approximate_tree_bytes(nodes));
+    match std::fs::metadata(index_file) {
+        Ok(metadata) => println!("  file_bytes\t{}", metadata.len()),
+        Err(_) => println!("  file_bytes\tNA"),
+    }
+    println!("  build_or_load_seconds\t{:.3}", index_time.as_secs_f64());
+}
+
+/// Approximate memory taken by `nodes` tree nodes: each node stores its interval and metadata
+/// plus the subtree end and two child links, ignoring allocator and map overhead.
+fn approximate_tree_bytes(nodes: usize) -> usize {
+    nodes * (std::mem::size_of::<coitrees::Interval<QueryMetadata>>() + std::mem::size_of::<i32>() + 2 * std::mem::size_of::<u32>())
+}
+
+/// Count the alignment records and aligned target bp of each target sequence, sorted by `sort_by`
+/// (ties broken by name) and truncated to `top` rows.
+fn target_stats(impg: &Impg, sort_by: StatsSortBy, top: Option<usize>) -> Vec<(String, usize, u64)> {
+    let mut stats: Vec<(String, usize, u64)> = impg.trees_by_id().into_iter().map(|(target_id, tree)| {
+        let aligned_bp = tree.iter().map(|interval| (interval.last - interval.first) as u64).sum();
+        (impg.seq_index.get_name(target_id).unwrap().to_string(), tree.len(), aligned_bp)
+    }).collect();
+    match sort_by {
+        StatsSortBy::Count => stats.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0))),
+        StatsSortBy::Bp => stats.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0))),
+        StatsSortBy::Name => stats.sort_by(|a, b| a.0.cmp(&b.0)),
+    }
+    stats.truncate(top.unwrap_or(stats.len()));
+    stats
+}
+
+/// The number of intervals and maximum overlap depth of each target sequence, deepest first
+/// (ties broken by name).
+fn overlap_depths(impg: &Impg) -> Vec<(String, usize, usize)> {
+    let mut depths: Vec<(String, usize, usize)> = impg.trees_by_id().into_iter()
+        .map(|(target_id, tree)| (impg.seq_index.get_name(target_id).unwrap().to_string(), tree.len(), impg.max_overlap_depth(target_id)))
+        .collect();
+    depths.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+    depths
+}
+
+#[cfg(test)]
+#[path = "test_util.rs"]
+mod test_util;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use coitrees::Interval;
+    use std::io::Read;
+    use test_util::{build_test_impg, TestDir};
+
+    fn build_impg(paf_data: &str) -> Impg {
+        let records = paf::parse_paf(BufReader::new(paf_data.as_bytes())).unwrap();
+        Impg::from_paf_records(&records, "unused.paf", false, None).unwrap()
+    }
+
+    #[test]
+    fn test_generate_index_min_mapq() {
+        let dir = TestDir::new("min_mapq");
+        let paf_files = vec![dir.write("min_mapq.paf", "b\t100\t0\t20\t+\ta\t100\t0\t20\t20\t20\t60\tcg:Z:20=\n\
+                                                        c\t100\t0\t20\t+\ta\t100\t10\t30\t20\t20\t10\tcg:Z:20=\n")];
+        let index_file = format!("{}.impg", paf_files[0]);
+        let options = IndexOptions { num_threads: NonZeroUsize::new(1).unwrap(), canonical_seq_order: false, drop_self_alignments: None, no_cigar: false, strict: false, min_mapq: Some(30), build_mem_limit: None, fai_lengths: None };
+
+        let impg = generate_index(&paf_files, &index_file, options).unwrap();
+        let a = impg.seq_index.get_id("a").unwrap();
+        let names = |impg: &Impg| impg.query(a, 0, 100).unwrap().into_iter().map(|(query, _, _)| impg.seq_index.get_name(query.metadata).unwrap().to_string()).collect::<Vec<_>>();
+        assert_eq!(names(&impg), vec!["a", "b"]);
+
+        let loaded = load_index(&paf_files, &index_file).unwrap();
+        assert_eq!(loaded.min_mapq, Some(30));
+        assert_eq!(names(&loaded), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_generate_index_zstd() {
+        let dir = TestDir::new("zstd_index");
+        let paf_data = "b\t100\t0\t20\t+\ta\t100\t0\t20\t20\t20\t60\tcg:Z:20=\n";
+        let paf_files = vec![dir.write("zstd_index.paf.zst", zstd::encode_all(paf_data.as_bytes(), 0).unwrap())];
+        let index_file = format!("{}.impg", paf_files[0]);
+        let options = IndexOptions { num_threads: NonZeroUsize::new(1).unwrap(), canonical_seq_order: false, drop_self_alignments: None, no_cigar: false, strict: false, min_mapq: None, build_mem_limit: None, fai_lengths: None };
+
+        let error = generate_index(&paf_files, &index_file, options).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(error.to_string().contains("--no-cigar"));
+
+        let impg = generate_index(&paf_files, &index_file, IndexOptions { no_cigar: true, ..options }).unwrap();
+        let a = impg.seq_index.get_id("a").unwrap();
+        let (query, _, _) = &impg.query(a, 5, 15).unwrap()[1];
+        assert_eq!((impg.seq_index.get_name(query.metadata), query.first, query.last), (Some("b"), 5, 15));
+    }
+
+    #[test]
+    fn test_write_atomically() {
+        let dir = TestDir::new("atomic");
+        let path = dir.path("atomic.impg");
+        let path = path.as_str();
+        let failing = |writer: &mut dyn Write| {
+            writer.write_all(b"partial")?;
+            Err(io::Error::other("simulated failure"))
+        };
+
+        assert!(write_atomically(path, failing).is_err());
+        assert!(!std::path::Path::new(path).exists());
+
+        write_atomically(path, |writer| writer.write_all(b"complete")).unwrap();
+        assert!(write_atomically(path, failing).is_err());
+        assert_eq!(std::fs::read(path).unwrap(), b"complete");
+        assert!(!std::path::Path::new(&format!("{}.tmp", path)).exists());
+    }
+
+    #[test]
+    fn test_bgzf_output() {
+        let impg = build_test_impg("bgzf_output", MIXED_PAF);
+        let args = Args::parse_from(["impg", "--region", "grch38#chr1:0-100", "-x", "--output-format", "paf"]);
+        let ctx = query_context(&impg, &args);
+        let mut expected = Vec::new();
+        query_and_output_targets(&mut expected, &ctx, query_targets(&args, &impg.seq_index).unwrap()).unwrap();
+
+        let path = impg.dir.path("bgzf_output.paf.gz");
+        let mut out = create_output(Some(&path)).unwrap();
+        query_and_output_targets(&mut out, &ctx, query_targets(&args, &impg.seq_index).unwrap()).unwrap();
+        out.finish().unwrap();
+        let mut decompressed = String::new();
+        bgzf::Reader::new(File::open(&path).unwrap()).read_to_string(&mut decompressed).unwrap();
+        assert!(!expected.is_empty());
+        assert_eq!(decompressed, String::from_utf8(expected).unwrap());
+    }
+
+    #[test]
+    fn test_explicit_index_path() {
+        let paf_dir = TestDir::new("index_path_paf");
+        let paf_files = vec![paf_dir.write("index_path.paf", "b\t100\t0\t20\t+\ta\t100\t0\t20\t20\t20\t60\tcg:Z:20=\n")];
+        let index_dir = TestDir::new("index_path_index");
+        let index = index_dir.path("custom.impg");
+        let index_file = index_file_path(&paf_files, Some(&index)).unwrap();
+        assert_eq!(index_file, index);
+        let options = IndexOptions { num_threads: NonZeroUsize::new(1).unwrap(), canonical_seq_order: false, drop_self_alignments: None, no_cigar: false, strict: false, min_mapq: None, build_mem_limit: None, fai_lengths: None };
+        let names = |impg: &Impg| impg.query(impg.seq_index.get_id("a").unwrap(), 0, 20).unwrap().into_iter().map(|(query, _, _)| impg.seq_index.get_name(query.metadata).unwrap().to_string()).collect::<Vec<_>>();
+
+        let built = load_or_generate_index(&paf_files, &index_file, options).unwrap();
+        assert!(std::path::Path::new(&index_file).exists());
+        assert!(!std::path::Path::new(&format!("{}.impg", paf_files[0])).exists());
+        let loaded = load_or_generate_index(&paf_files, &index_file, options).unwrap();
+        assert_eq!(names(&loaded), names(&built));
+        assert!(stale_paf_files(&paf_files, &index_file).is_empty());
+
+        // Touching the PAF file makes the explicit index stale
+        let later = std::fs::metadata(&index_file).unwrap().modified().unwrap() + Duration::from_secs(60);
+        File::options().write(true).open(&paf_files[0]).unwrap().set_modified(later).unwrap();
+        assert_eq!(stale_paf_files(&paf_files, &index_file), vec![paf_files[0].as_str()]);
+    }
+
+    #[test]
+    fn test_generate_index_fai() {
+        let dir = TestDir::new("fai");
+        let paf_files = vec![dir.write("fai.paf", "b\t100\t0\t20\t+\ta\t100\t0\t20\t20\t20\t60\tcg:Z:20=\n")];
+        let index_file = format!("{}.impg", paf_files[0]);
+        let build = |fai: &str| {
+            let fai_lengths = read_fai_lengths(&dir.write("fai.fa.fai", fai)).unwrap();
+            let options = IndexOptions { num_threads: NonZeroUsize::new(1).unwrap(), canonical_seq_order: false, drop_self_alignments: None, no_cigar: false, strict: false, min_mapq: None, build_mem_limit: None, fai_lengths: Some(&fai_lengths) };
+            generate_index(&paf_files, &index_file, options)
+        };
+
+        // Only the sequences present in the FAI are checked
+        let impg = build("a\t100\t3\t60\t61\nunaligned\t50\t110\t60\t61\n").unwrap();
+        let a_length = impg.seq_index.get_len_from_id(impg.seq_index.get_id("a").unwrap()).unwrap();
+        assert_eq!(pad_range((10, 90), 50, a_length as i32), (0, 100));
+
+        let err = build("a\t100\t3\t60\t61\nb\t120\t110\t60\t61\n").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("Length of b is 100"));
+    }
+
+    #[test]
+    fn test_repeat_query() {
+        let mut impg = build_impg(MIXED_PAF);
+        impg.has_cigars = false;
+        let mut runs = 0;
+        let results = repeat_query(3, || {
+            runs += 1;
+            perform_query(&impg, "grch38#chr1", (0, 10), 0, Expansion::default())
+        }).unwrap();
+        assert_eq!(runs, 3);
+        assert_eq!(results.len(), 3);
+    }
+
+    /// A context for querying `impg` with `args`, without FASTA or region masks.
+    fn query_context<'a>(impg: &'a Impg, args: &'a Args) -> QueryContext<'a> {
+        QueryContext {
+            impg,
+            args,
+            fetcher: None,
+            json_records: AtomicUsize::new(0),
+            excluded: HashMap::new(),
+            included: None,
+            op_totals: Mutex::new(OpTotals::default()),
+            bnd_pairs: AtomicUsize::new(0),
+        }
+    }
+
+    fn result(impg: &Impg, query_name: &str, first: i32, last: i32, target_name: &str) -> AdjustedInterval {
+        let query_id = impg.seq_index.get_id(query_name).unwrap();
+        let target_id = impg.seq_index.get_id(target_name).unwrap();
+        let len = (last - first).abs();
+        (Interval { first, last, metadata: query_id }, vec![CigarOp::new(len, '=')], Interval { first: 0, last: len, metadata: target_id })
+    }
+
+    const MIXED_PAF: &str = "chm13#chr1\t100\t0\t100\t+\tgrch38#chr1\t100\t0\t100\t100\t100\t60\n\
+                             HG002#1#chr1\t100\t0\t100\t-\tgrch38#chr1\t100\t0\t100\t100\t100\t60\n\
+                             chm13#chr2\t100\t0\t100\t+\tHG002#1#chr1\t100\t0\t100\t100\t100\t60\n";
+
+    #[test]
+    fn test_perform_query_invalid_inputs() {
+        let impg = build_impg(MIXED_PAF);
+
+        let err = perform_query(&impg, "missing#chr1", (0, 10), 0, Expansion::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("missing#chr1"));
+
+        let err = perform_query(&impg, "grch38#chr1", (50, 150), 0, Expansion::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("grch38#chr1:50-150"));
+    }
+
+    #[test]
+    fn test_perform_query_max_results() {
+        // Project linearly, as the test PAF is not on disk for reading CIGARs
+        let mut impg = build_impg(MIXED_PAF);
+        impg.has_cigars = false;
+        let expansion = |max_results: Option<usize>, on_limit: OnLimit| Expansion { transitive: true, max_results, on_limit, ..Expansion::default() };
+        let unlimited = perform_query(&impg, "grch38#chr1", (0, 100), 0, expansion(None, OnLimit::Error)).unwrap();
+        assert_eq!(unlimited.len(), 4);
+
+        let err = perform_query(&impg, "grch38#chr1", (0, 100), 0, expansion(Some(2), OnLimit::Error)).unwrap_err();
+        assert_eq!(err.to_string(), "Query of grch38#chr1:0-100 has more than 2 results, raise --max-results or use --on-limit truncate");
+        let truncated = perform_query(&impg, "grch38#chr1", (0, 100), 0, expansion(Some(2), OnLimit::Truncate)).unwrap();
+        let coordinates = |results: &[QueryResult]| results.iter().map(|((query, _, target), _, _, _)| (query.metadata, query.first, query.last, target.first, target.last)).collect::<Vec<_>>();
+        assert_eq!(coordinates(&truncated), coordinates(&unlimited[..2]));
+        assert_eq!(perform_query(&impg, "grch38#chr1", (0, 100), 0, expansion(Some(4), OnLimit::Error)).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_target_stats() {
+        let impg = build_impg(&format!("{}chm13#chr2\t100\t0\t30\t+\tHG002#1#chr1\t100\t0\t30\t30\t30\t60\n\
+                                        chm13#chr1\t100\t0\t90\t+\tchm13#chr2\t100\t0\t90\t90\t90\t60\n", MIXED_PAF));
+        let stats = |sort_by, top| target_stats(&impg, sort_by, top);
+
+        assert_eq!(stats(StatsSortBy::Count, None), vec![
+            ("HG002#1#chr1".to_string(), 2, 130),
+            ("grch38#chr1".to_string(), 2, 200),
+            ("chm13#chr2".to_string(), 1, 90),
+        ]);
+        assert_eq!(stats(StatsSortBy::Bp, Some(2)), vec![
+            ("grch38#chr1".to_string(), 2, 200),
+            ("HG002#1#chr1".to_string(), 2, 130),
+        ]);
+        assert_eq!(stats(StatsSortBy::Name, Some(1)), vec![("HG002#1#chr1".to_string(), 2, 130)]);
+    }
+
+    #[test]
+    fn test_stats_reproducible() {
+        // Every target ties on each count, so only a stable order keeps the reports equal
+        let paf_data: String = (0..20).map(|i| format!("q\t100\t0\t10\t+\tt{}\t100\t0\t10\t10\t10\t60\n", i)).collect();
+        let (first, second) = (build_impg(&paf_data), build_impg(&paf_data));
+        assert_eq!(overlap_depths(&first), overlap_depths(&second));
+        assert_eq!(target_stats(&first, StatsSortBy::Count, None), target_stats(&second, StatsSortBy::Count, None));
+    }
+
+    #[test]
+    fn test_output_results_gaf() {
+        let impg = build_impg(MIXED_PAF);
+        let query_id = impg.seq_index.get_id("HG002#1#chr1").unwrap();
+        let target_id = impg.seq_index.get_id("grch38#chr1").unwrap();
+        let results = vec![
+            (result(&impg, "chm13#chr1", 10, 40, "grch38#chr1"), 255, Vec::new(), false),
+            ((Interval { first: 90, last: 60, metadata: query_id }, vec![CigarOp::new(10, '='), CigarOp::new(2, 'I'), CigarOp::new(18, 'X')], Interval { first: 20, last: 48, metadata: target_id }), 60, Vec::new(), false),
+        ];
+
+        let mut out = Vec::new();
+        output_results_gaf(&mut out, &impg, results, None).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "chm13#chr1\t100\t10\t40\t+\t>grch38#chr1\t100\t0\t30\t30\t30\t255\tcg:Z:30=\n\
+                    HG002#1#chr1\t100\t60\t90\t+\t<grch38#chr1\t100\t52\t80\t10\t30\t60\tcg:Z:18X2I10=\n");
+    }
+
+    #[test]
+    fn test_output_results_gaf_transitive() {
+        // c reaches a through b, in reverse on the second hop
+        let paf_data = "b\t40\t0\t40\t+\ta\t60\t10\t50\t40\t40\t60\tcg:Z:40=\n\
+                        c\t30\t0\t30\t-\tb\t40\t0\t30\t30\t30\t60\tcg:Z:30=\n";
+        let impg = build_test_impg("gaf_transitive", paf_data);
+        let args = Args::parse_from(["impg", "--region", "a:20-50", "-x", "--output-format", "gaf"]);
+        let ctx = query_context(&impg, &args);
+        let mut out = Vec::new();
+        query_and_output_targets(&mut out, &ctx, query_targets(&args, &impg.seq_index).unwrap()).unwrap();
+        let rows: Vec<Vec<String>> = String::from_utf8(out).unwrap().lines()
+            .map(|line| line.split('\t').take(9).map(str::to_string).collect())
+            .collect();
+        assert_eq!(rows, vec![
+            vec!["a", "60", "20", "50", "+", ">a", "60", "20", "50"],
+            vec!["b", "40", "10", "40", "+", ">a", "60", "20", "50"],
+            // On b, 40 bp long, and walked backwards from its end
+            vec!["c", "30", "0", "20", "+", "<b", "40", "10", "30"],
+        ]);
+    }
+
+    #[test]
+    fn test_output_results_bedpe_cigar() {
+        let impg = build_impg(MIXED_PAF);
+        let query_id = impg.seq_index.get_id("HG002#1#chr1").unwrap();
+        let target_id = impg.seq_index.get_id("grch38#chr1").unwrap();
+        let results = vec![(Interval { first: 90, last: 60, metadata: query_id }, vec![CigarOp::new(10, '='), CigarOp::new(2, 'I'), CigarOp::new(18, 'X')], Interval { first: 20, last: 48, metadata: target_id })];
+
+        let mut out = Vec::new();
+        output_results_bedpe(&mut out, &impg, results.clone(), "grch38#chr1", None, false, false).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "HG002#1#chr1\t60\t90\tgrch38#chr1\t20\t48\t.\t0\t-\t+\n");
+
+        let mut out = Vec::new();
+        output_results_bedpe(&mut out, &impg, results.clone(), "grch38#chr1", None, false, true).unwrap();
+        let bedpe_cigar = String::from_utf8(out).unwrap().trim_end().rsplit('\t').next().unwrap().to_string();
+        let mut out = Vec::new();
+        output_results_paf(&mut out, &impg, results.into_iter().map(|r| (r, 255, Vec::new(), false)).collect(), "grch38#chr1", None, CigarOrientation::Query, &[]).unwrap();
+        let paf_cigar = String::from_utf8(out).unwrap().trim_end().rsplit('\t').next().unwrap().to_string();
+        assert_eq!(bedpe_cigar, "cg:Z:10=2I18X");
+        assert_eq!(bedpe_cigar, paf_cigar);
+    }
+
+    #[test]
+    fn test_query_against() {
+        let mut impg = build_impg(MIXED_PAF);
+        impg.has_cigars = false;
+        let query_names = |extra: &[&str]| {
+            let args = Args::parse_from([&["impg", "--region", "grch38#chr1:0-100"], extra].concat());
+            let ctx = query_context(&impg, &args);
+            query_region(&ctx, "grch38#chr1", (0, 100)).unwrap().iter()
+                .map(|((query, _, target), _, _, _)| (impg.seq_index.get_name(query.metadata).unwrap(), impg.seq_index.get_name(target.metadata).unwrap()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(query_names(&["--against", "chm13#chr1"]), vec![("chm13#chr1", "grch38#chr1")]);
+        assert_eq!(query_names(&["--against", "chm13#chr2"]), vec![]);
+        // Transitive queries still go through the other sequences
+        assert_eq!(query_names(&["--against", "chm13#chr2", "-x"]), vec![("chm13#chr2", "HG002#1#chr1")]);
+
+        let args = Args::parse_from(["impg", "--against", "missing"]);
+        assert!(query_region(&query_context(&impg, &args), "grch38#chr1", (0, 100)).is_err());
+    }
+
+    #[test]
+    fn test_query_regions() {
+        // Project linearly, as the test PAF is not on disk for reading CIGARs
+        let mut impg = build_impg(MIXED_PAF);
+        impg.has_cigars = false;
+        let output = |regions: &[&str]| {
+            let mut command_line = vec!["impg"];
+            for region in regions {
+                command_line.extend(["--region", region]);
+            }
+            let args = Args::parse_from(command_line);
+            let ctx = query_context(&impg, &args);
+            let mut out = Vec::new();
+            query_and_output_targets(&mut out, &ctx, query_targets(&args, &impg.seq_index).unwrap()).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let (first, second) = (output(&["grch38#chr1:10-20"]), output(&["HG002#1#chr1:50-90"]));
+        assert!(!first.is_empty() && !second.is_empty());
+        assert_eq!(output(&["grch38#chr1:10-20", "HG002#1#chr1:50-90"]), first + &second);
+    }
+
+    #[test]
+    fn test_bed_to_paf() {
+        let paf_data = "b\t100\t0\t20\t+\ta\t100\t10\t30\t20\t20\t60\tcg:Z:20=\n\
+                        c\t50\t5\t25\t-\ta\t100\t20\t40\t20\t20\t60\tcg:Z:10=1X9=\n";
+        let impg = build_test_impg("bed_to_paf", paf_data);
+        let bed_file = impg.dir.write("bed_to_paf.bed", "a\t0\t15\tfeature1\na\t25\t35\tfeature2\n");
+
+        let output = |extra: &[&str]| {
+            let args = Args::parse_from([&["impg", "-b", bed_file.as_str(), "--bed-to-paf"], extra].concat());
+            let ctx = query_context(&impg, &args);
+            let mut out = Vec::new();
+            query_and_output_targets(&mut out, &ctx, query_targets(&args, &impg.seq_index).unwrap()).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        // feature1 overlaps the alignment of b, feature2 those of b and c
+        assert_eq!(output(&[]),
+                   "b\t100\t0\t5\t+\ta\t100\t10\t15\t5\t5\t60\tNM:i:0\tcg:Z:5=\tan:Z:feature1\n\
+                    b\t100\t15\t20\t+\ta\t100\t25\t30\t5\t5\t60\tNM:i:0\tcg:Z:5=\tan:Z:feature2\n\
+                    c\t50\t10\t20\t-\ta\t100\t25\t35\t9\t10\t60\tNM:i:1\tcg:Z:5=1X4=\tan:Z:feature2\n");
+        let with_input = output(&["--keep-input-row"]);
+        assert!(with_input.starts_with("a\t100\t0\t15\t+\ta\t100\t0\t15\t15\t15\t255\tNM:i:0\tcg:Z:15=\tan:Z:feature1\n"));
+        assert_eq!(with_input.lines().count(), 5);
+    }
+
+    #[test]
+    fn test_identity_self_alignment() {
+        // The projection of the region through this alignment has the coordinates of the region
+        let impg = build_test_impg("identity_self_alignment", "a\t100\t0\t100\t+\ta\t100\t0\t100\t100\t100\t60\tcg:Z:100=\n");
+        let bed_file = impg.dir.write("identity_self_alignment.bed", "a\t10\t20\tfeature\n");
+        let output = |extra: &[&str]| {
+            let args = Args::parse_from([&["impg", "-b", bed_file.as_str()], extra].concat());
+            let ctx = query_context(&impg, &args);
+            let mut out = Vec::new();
+            query_and_output_targets(&mut out, &ctx, query_targets(&args, &impg.seq_index).unwrap()).unwrap();
+            (String::from_utf8(out).unwrap(), ctx.op_totals.into_inner().unwrap())
+        };
+
+        assert_eq!(output(&["--bed-to-paf"]).0, "a\t100\t10\t20\t+\ta\t100\t10\t20\t10\t10\t60\tNM:i:0\tcg:Z:10=\tan:Z:feature\n");
+        assert_eq!(output(&["--op-stats"]).1.bp, [10, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(output(&["--invert", "--invert-scope", "query"]).0, "");
+    }
+
+    #[test]
+    fn test_keep_tags() {
+        let paf_data = "b\t100\t0\t20\t+\ta\t100\t10\t30\t20\t20\t60\ttp:A:P\tcg:Z:20=\tde:f:0.01\tAS:i:20\n\
+                        c\t50\t0\t10\t+\tb\t100\t0\t10\t10\t10\t60\ttp:A:S\tcg:Z:10=\n";
+        let impg = build_test_impg("keep_tags", paf_data);
+
+        let args = Args::parse_from(["impg", "--region", "a:0-100", "-x", "--output-format", "paf", "--keep-tags", "tp,de"]);
+        let ctx = query_context(&impg, &args);
+        let mut out = Vec::new();
+        query_and_output_targets(&mut out, &ctx, query_targets(&args, &impg.seq_index).unwrap()).unwrap();
+        let tags: Vec<_> = String::from_utf8(out).unwrap().lines()
+            .map(|line| line.split('\t').skip(14).collect::<Vec<_>>().join("\t"))
+            .collect();
+        // Tags follow the CIGAR in source order; the transitive hop to c keeps those of c onto b
+        assert_eq!(tags, vec!["", "tp:A:P\tde:f:0.01", "tp:A:S"]);
+
+        let args = Args::parse_from(["impg", "--region", "a:0-100", "-x", "--output-format", "paf", "--trace"]);
+        let mut out = Vec::new();
+        query_and_output_targets(&mut out, &query_context(&impg, &args), query_targets(&args, &impg.seq_index).unwrap()).unwrap();
+        let second_offset = paf_data.find("\nc").unwrap() + 1;
+        let traces: Vec<_> = String::from_utf8(out).unwrap().lines().map(|line| line.split('\t').find(|field| field.starts_with("tr:Z:")).map(str::to_string)).collect();
+        assert_eq!(traces, vec![None, Some("tr:Z:0:0".to_string()), Some(format!("tr:Z:0:0,0:{}", second_offset))]);
+    }
+
+    #[test]
+    fn test_output_results_vcf() {
+        let impg = build_impg(MIXED_PAF);
+        let args = Args::parse_from(["impg", "--output-format", "vcf"]);
+        let ctx = query_context(&impg, &args);
+        let results = vec![
+            result(&impg, "grch38#chr1", 0, 100, "grch38#chr1"),
+            result(&impg, "chm13#chr1", 10, 30, "grch38#chr1"),
+            result(&impg, "HG002#1#chr1", 90, 70, "grch38#chr1"),
+        ];
+
+        let mut out = Vec::new();
+        write_vcf_header(&mut out, &impg).unwrap();
+        output_results_vcf(&mut out, &ctx, results).unwrap();
+        let vcf = String::from_utf8(out).unwrap();
+        let (header, records): (Vec<&str>, Vec<&str>) = vcf.lines().partition(|line| line.starts_with('#'));
+        assert_eq!(header.first(), Some(&"##fileformat=VCFv4.2"));
+        assert!(header.contains(&"##contig=<ID=HG002#1#chr1,length=100>"));
+        // The input range is skipped, and each other result becomes a pair of mates
+        assert_eq!(records, vec![
+            "grch38#chr1\t1\tbnd_1_1\tN\tN[chm13#chr1:11[\t.\tPASS\tSVTYPE=BND;MATEID=bnd_1_2",
+            "chm13#chr1\t11\tbnd_1_2\tN\t]grch38#chr1:1]N\t.\tPASS\tSVTYPE=BND;MATEID=bnd_1_1",
+            "grch38#chr1\t1\tbnd_2_1\tN\tN]HG002#1#chr1:90]\t.\tPASS\tSVTYPE=BND;MATEID=bnd_2_2",
+            "HG002#1#chr1\t90\tbnd_2_2\tN\tN]grch38#chr1:1]\t.\tPASS\tSVTYPE=BND;MATEID=bnd_2_1",
+        ]);
+    }
+
+    #[test]
+    fn test_output_results_vcf_transitive() {
+        // c reaches a through b, in reverse on the second hop
+        let paf_data = "b\t40\t0\t40\t+\ta\t60\t10\t50\t40\t40\t60\tcg:Z:40=\n\
+                        c\t30\t0\t30\t-\tb\t40\t0\t30\t30\t30\t60\tcg:Z:30=\n";
+        let impg = build_test_impg("vcf_transitive", paf_data);
+        let args = Args::parse_from(["impg", "--region", "a:20-50", "-x", "--output-format", "vcf"]);
+        let ctx = query_context(&impg, &args);
+        let mut out = Vec::new();
+        query_and_output_targets(&mut out, &ctx, query_targets(&args, &impg.seq_index).unwrap()).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap().lines().collect::<Vec<_>>(), vec![
+            "a\t21\tbnd_1_1\tN\tN[b:11[\t.\tPASS\tSVTYPE=BND;MATEID=bnd_1_2",
+            "b\t11\tbnd_1_2\tN\t]a:21]N\t.\tPASS\tSVTYPE=BND;MATEID=bnd_1_1",
+            // The breakends of the second hop join c to b, not to a
+            "b\t11\tbnd_2_1\tN\tN]c:20]\t.\tPASS\tSVTYPE=BND;MATEID=bnd_2_2",
+            "c\t20\tbnd_2_2\tN\tN]b:11]\t.\tPASS\tSVTYPE=BND;MATEID=bnd_2_1",
+        ]);
+    }
+
+    #[test]
+    fn test_output_results_sam() {
+        let impg = build_impg(MIXED_PAF);
+        let dir = TestDir::new("sam");
+        let fasta_file = dir.write("sam.fa", format!(">HG002#1#chr1\n{}ACGTTGCA{}\n", "A".repeat(60), "C".repeat(32)));
+        let args = Args::parse_from(["impg", "--output-format", "sam"]);
+        let mut ctx = query_context(&impg, &args);
+        let query_id = impg.seq_index.get_id("HG002#1#chr1").unwrap();
+        let target_id = impg.seq_index.get_id("grch38#chr1").unwrap();
+        let results = vec![
+            (result(&impg, "chm13#chr1", 10, 30, "grch38#chr1"), 60, Vec::new(), false),
+            ((Interval { first: 68, last: 60, metadata: query_id }, vec![CigarOp::new(5, '='), CigarOp::new(2, 'D'), CigarOp::new(1, 'I'), CigarOp::new(2, 'X')], Interval { first: 20, last: 29, metadata: target_id }), 255, Vec::new(), false),
+        ];
+
+        let mut out = Vec::new();
+        write_sam_header(&mut out, &impg).unwrap();
+        output_results_sam(&mut out, &ctx, results.clone(), None).unwrap();
+        let sam = String::from_utf8(out).unwrap();
+        let (header, records): (Vec<&str>, Vec<&str>) = sam.lines().partition(|line| line.starts_with('@'));
+        assert_eq!(header[0], "@HD\tVN:1.6\tSO:unsorted");
+        assert!(header.contains(&"@SQ\tSN:grch38#chr1\tLN:100"));
+        // The reverse-strand query [60, 68) is clipped by what lies past it on the query's reverse complement
+        assert_eq!(records, vec![
+            "chm13#chr1\t0\tgrch38#chr1\t1\t60\t10H20=70H\t*\t0\t0\t*\t*\tNM:i:0",
+            "HG002#1#chr1\t16\tgrch38#chr1\t21\t255\t32H5=2D1I2X60H\t*\t0\t0\t*\t*\tNM:i:5",
+        ]);
+
+        ctx.fetcher = Some(SequenceFetcher::new(&fasta_file).unwrap());
+        let mut out = Vec::new();
+        output_results_sam(&mut out, &ctx, results[1..].to_vec(), Some("region1".to_string())).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "HG002#1#chr1\t16\tgrch38#chr1\t21\t255\t32H5=2D1I2X60H\t*\t0\t0\tTGCAACGT\t*\tNM:i:5\tan:Z:region1\n");
+    }
+
+    #[test]
+    fn test_clamp_coordinates_only() {
+        // The transitive result b -> a only partly overlaps a:0-30, so it has to be trimmed
+        let paf_data = "b\t40\t0\t40\t+\ta\t60\t0\t40\t40\t40\t60\tcg:Z:40=\n\
+                        a\t60\t20\t60\t+\tb\t40\t0\t40\t40\t40\t60\tcg:Z:40=\n";
+        let impg = build_test_impg("clamp", paf_data);
+        let coordinates = |format: &str| {
+            let args = Args::parse_from(["impg", "--region", "a:0-30", "-x", "--clamp", "--output-format", format]);
+            let mut out = Vec::new();
+            query_and_output_targets(&mut out, &query_context(&impg, &args), query_targets(&args, &impg.seq_index).unwrap()).unwrap();
+            String::from_utf8(out).unwrap().lines()
+                .map(|line| {
+                    let fields: Vec<&str> = line.split('\t').collect();
+                    match format {
+                        "paf" => [fields[0], fields[2], fields[3], fields[5], fields[7], fields[8]].join("\t"),
+                        _ => fields[..6].join("\t"),
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+        let bedpe = coordinates("bedpe");
+        assert!(bedpe.contains(&"b\t20\t30\ta\t20\t30".to_string()), "{:?}", bedpe);
+        assert_eq!(bedpe, coordinates("paf"));
+    }
+
+    #[test]
+    fn test_parallel_target_bed() {
+        let mut impg = build_impg(MIXED_PAF);
+        impg.has_cigars = false;
+        let dir = TestDir::new("parallel");
+        let bed: String = (0..200).map(|i| format!("grch38#chr1\t{}\t{}\tregion{}\n", i % 90, i % 90 + 10, i)).collect();
+        let bed_file = dir.write("parallel.bed", bed);
+        let output = |num_threads: usize, format: &str| {
+            let args = Args::parse_from(["impg", "-b", bed_file.as_str(), "-x", "--output-format", format]);
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(num_threads).build().unwrap();
+            pool.install(|| {
+                let mut out = Vec::new();
+                query_and_output_targets(&mut out, &query_context(&impg, &args), query_targets(&args, &impg.seq_index).unwrap()).unwrap();
+                out
+            })
+        };
+        for format in ["bedpe", "json"] {
+            let serial = output(1, format);
+            assert_eq!(String::from_utf8_lossy(&serial).lines().filter(|line| line.contains("region199")).count(), 4);
+            assert_eq!(output(4, format), serial);
+        }
+    }
+
+    #[test]
+    fn test_consensus() {
+        // q1 to q3 agree with t, q4 (reverse strand) reads G instead of A at t:4, and only q1 and q4
+        // reach past t:12
+        let paf_data = "q1\t20\t0\t20\t+\tt\t20\t0\t20\t20\t20\t60\tcg:Z:20=\n\
+                        q2\t12\t0\t12\t+\tt\t20\t0\t12\t12\t12\t60\tcg:Z:12=\n\
+                        q3\t12\t0\t12\t+\tt\t20\t0\t12\t12\t12\t60\tcg:Z:12=\n\
+                        q4\t20\t0\t20\t-\tt\t20\t0\t20\t19\t20\t60\tcg:Z:4=1X15=\n";
+        let impg = build_test_impg("consensus", paf_data);
+        let target = "ACGTACGTACGTTTTTCCCC";
+        let q4 = String::from_utf8(impg::fasta::reverse_complement(b"ACGTGCGTACGTTTTTCCCC")).unwrap();
+        let fasta_file = impg.dir.write("consensus.fa", format!(">t\n{}\n>q1\n{}\n>q2\n{}\n>q3\n{}\n>q4\n{}\n", target, target, &target[..12], &target[..12], q4));
+
+        let consensus = |min_depth: &str| {
+            let args = Args::parse_from(["impg", "--region", "t:0-20", "--consensus", "--fasta", fasta_file.as_str(), "--min-depth", min_depth, "--wrap", "10"]);
+            let mut ctx = query_context(&impg, &args);
+            ctx.fetcher = Some(SequenceFetcher::new(&fasta_file).unwrap());
+            let mut out = Vec::new();
+            query_and_output_targets(&mut out, &ctx, query_targets(&args, &impg.seq_index).unwrap()).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(consensus("1"), ">t:0-20\nACGTACGTAC\nGTTTTTCCCC\n");
+        assert_eq!(consensus("3"), ">t:0-20\nACGTACGTAC\nGTNNNNNNNN\n");
+    }
+
+    #[test]
+    fn test_output_results_bedpe_extended() {
+        let impg = build_impg(MIXED_PAF);
+        let query_id = impg.seq_index.get_id("HG002#1#chr1").unwrap();
+        let target_id = impg.seq_index.get_id("grch38#chr1").unwrap();
+        let result = (Interval { first: 90, last: 60, metadata: query_id }, vec![CigarOp::new(10, '='), CigarOp::new(2, 'I'), CigarOp::new(8, '='), CigarOp::new(10, 'X')], Interval { first: 20, last: 48, metadata: target_id });
+
+        let mut out = Vec::new();
+        output_results_bedpe(&mut out, &impg, vec![result.clone()], "grch38#chr1", None, true, true).unwrap();
+        let line = String::from_utf8(out).unwrap();
+        let columns: Vec<&str> = line.trim_end().split('\t').skip(10).collect();
+        assert_eq!(columns, vec!["gi:f:0.620690", "bi:f:0.600000", "cg:Z:10=2I8=10X"]);
+
+        // The same identities as the JSON output
+        let record = JsonRecord::new(&impg, &(result, 60, Vec::new(), false), None);
+        assert_eq!(columns[0], format!("gi:f:{:.6}", record.gap_compressed_identity));
+        assert_eq!(columns[1], format!("bi:f:{:.6}", record.block_identity));
+    }
+
+    #[test]
+    fn test_target_spans() {
+        let impg = build_impg(MIXED_PAF);
+        let grch38 = impg.seq_index.get_id("grch38#chr1").unwrap();
+        let hg002 = impg.seq_index.get_id("HG002#1#chr1").unwrap();
+        let results = vec![
+            result(&impg, "chm13#chr1", 0, 30, "grch38#chr1"),
+            (Interval { first: 80, last: 60, metadata: hg002 }, vec![], Interval { first: 20, last: 40, metadata: grch38 }),
+            (Interval { first: 0, last: 10, metadata: grch38 }, vec![], Interval { first: 50, last: 60, metadata: grch38 }),
+            result(&impg, "chm13#chr2", 0, 5, "HG002#1#chr1"),
+        ];
+        // The first two projections onto grch38 overlap and merge into one span
+        let mut expected = vec![(grch38, vec![(0, 40), (50, 60)]), (hg002, vec![(0, 5)])];
+        expected.sort();
+        assert_eq!(target_spans(&results), expected);
+    }
+
+    #[test]
+    fn test_uncovered_ranges() {
+        let impg = build_impg(MIXED_PAF);
+        let results = vec![
+            result(&impg, "grch38#chr1", 0, 100, "grch38#chr1"),
+            result(&impg, "chm13#chr1", 20, 50, "grch38#chr1"),
+            result(&impg, "HG002#1#chr1", 10, 0, "grch38#chr1"),
+            result(&impg, "chm13#chr1", 5, 5, "grch38#chr1"),
+            result(&impg, "chm13#chr2", 30, 30, "HG002#1#chr1"),
+        ];
+        let gaps: Vec<_> = uncovered_ranges(&impg, &results).into_iter()
+            .map(|(seq_id, gaps)| (impg.seq_index.get_name(seq_id).unwrap().to_string(), gaps))
+            .collect();
+        assert_eq!(gaps, vec![
+            ("chm13#chr1".to_string(), vec![(0, 20), (50, 100)]),
+            ("grch38#chr1".to_string(), vec![]),
+            ("HG002#1#chr1".to_string(), vec![(10, 100)]),
+            ("chm13#chr2".to_string(), vec![(0, 100)]),
+        ]);
+    }
+
+    #[test]
+    fn test_invert_scope() {
+        let paf_data = "b\t100\t0\t20\t+\ta\t100\t10\t30\t20\t20\t60\n\
+                        c\t100\t50\t70\t+\ta\t100\t40\t60\t20\t20\t60\n";
+        let mut impg = build_impg(paf_data);
+        impg.has_cigars = false;
+        let output = |scope: &str| {
+            let args = Args::parse_from(["impg", "--region", "a:0-50", "--invert", "--invert-scope", scope]);
+            let mut out = Vec::new();
+            query_and_output_targets(&mut out, &query_context(&impg, &args), query_targets(&args, &impg.seq_index).unwrap()).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        // Everything outside the results on each sequence, a included
+        assert_eq!(output("sequence"), "b\t20\t100\t.\na\t50\t100\t.\nc\t0\t50\t.\nc\t60\t100\t.\n");
+        // The queried range minus the target sides of b's [10, 30) and c's [40, 50)
+        assert_eq!(output("query"), "a\t0\t10\t.\na\t30\t40\t.\n");
+    }
+
+    #[test]
+    fn test_filter_results_strand() {
+        let impg = build_impg(MIXED_PAF);
+        let results = vec![
+            result(&impg, "grch38#chr1", 0, 100, "grch38#chr1"),
+            result(&impg, "chm13#chr1", 0, 100, "grch38#chr1"),
+            result(&impg, "HG002#1#chr1", 100, 0, "grch38#chr1"),
+        ].into_iter().map(|result| (result, 255, Vec::new(), false)).collect::<Vec<_>>();
+        let strands = |strand: &str| {
+            let args = Args::parse_from(["impg", "--strand", strand]);
+            filter_results(&impg, results.clone(), &args).iter()
+                .map(|((query, _, _), _, _, _)| (impg.seq_index.get_name(query.metadata).unwrap().to_string(), query.first <= query.last))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(strands("-"), vec![("HG002#1#chr1".to_string(), false)]);
+        assert_eq!(strands("+"), vec![("grch38#chr1".to_string(), true), ("chm13#chr1".to_string(), true)]);
+        assert_eq!(strands("both").len(), 3);
+    }
+
+    #[test]
+    fn test_filter_results_min_block_length() {
+        let impg = build_impg(MIXED_PAF);
+        let query_id = impg.seq_index.get_id("chm13#chr1").unwrap();
+        let target_id = impg.seq_index.get_id("grch38#chr1").unwrap();
+        let block = |query_len: i32, target_len: i32, cigar: Vec<CigarOp>| ((Interval { first: 0, last: query_len, metadata: query_id }, cigar, Interval { first: 0, last: target_len, metadata: target_id }), 60, Vec::new(), false);
+        let results = vec![
+            block(50, 50, vec![CigarOp::new(50, '=')]),
+            // A 40bp query span, but a 5000bp block counting the deletion
+            block(40, 5000, vec![CigarOp::new(20, '='), CigarOp::new(4960, 'D'), CigarOp::new(20, '=')]),
+        ];
+
+        let args = Args::parse_from(["impg", "--min-block-length", "1000"]);
+        let kept = filter_results(&impg, results, &args);
+        assert_eq!(kept.len(), 1);
+        assert_eq!(CigarStats::from_cigar(&kept[0].0.1).block_len(), 5000);
+    }
+
+    #[test]
+    fn test_op_totals() {
+        let impg = build_impg(MIXED_PAF);
+        let query_id = impg.seq_index.get_id("chm13#chr1").unwrap();
+        let target_id = impg.seq_index.get_id("grch38#chr1").unwrap();
+        let block = |query_len: i32, target_len: i32, cigar: Vec<CigarOp>| (Interval { first: 0, last: query_len, metadata: query_id }, cigar, Interval { first: 0, last: target_len, metadata: target_id });
+        let results = [
+            (Interval { first: 0, last: 30, metadata: target_id }, vec![CigarOp::new(30, '=')], Interval { first: 0, last: 30, metadata: target_id }),
+            block(30, 28, vec![CigarOp::new(10, '='), CigarOp::new(3, 'I'), CigarOp::new(5, 'X'), CigarOp::new(1, 'D'), CigarOp::new(12, '=')]),
+            block(28, 30, vec![CigarOp::new(20, 'M'), CigarOp::new(2, 'D'), CigarOp::new(8, 'M')]),
+        ];
+
+        let mut totals = OpTotals::default();
+        totals.add(results[1..].iter());
+        assert_eq!(totals.bp, [22, 5, 3, 3, 28, 0, 0]);
+    }
+
+    #[test]
+    fn test_json_record() {
+        let impg = build_impg(MIXED_PAF);
+        let query_id = impg.seq_index.get_id("HG002#1#chr1").unwrap();
+        let target_id = impg.seq_index.get_id("grch38#chr1").unwrap();
+        let result = ((Interval { first: 90, last: 60, metadata: query_id }, vec![CigarOp::new(10, '='), CigarOp::new(2, 'I'), CigarOp::new(8, '='), CigarOp::new(10, 'X')], Interval { first: 20, last: 48, metadata: target_id }), 60, Vec::new(), false);
+
+        let json = serde_json::to_value(JsonRecord::new(&impg, &result, Some("region1"))).unwrap();
+        assert_eq!(json, serde_json::json!({
+            "query_name": "HG002#1#chr1", "query_start": 60, "query_end": 90, "strand": "-",
+            "target_name": "grch38#chr1", "target_start": 20, "target_end": 48,
+            "cigar": "10=2I8=10X", "matches": 18, "mismatches": 10, "insertions": 1, "inserted_bp": 2, "deletions": 0, "deleted_bp": 0,
+            "gap_compressed_identity": 18.0 / 29.0, "block_identity": 18.0 / 30.0, "mapq": 60, "name": "region1",
+        }));
+        assert!(serde_json::to_value(JsonRecord::new(&impg, &result, None)).unwrap().get("name").is_none());
+    }
+
+    #[test]
+    fn test_remove_self_overlaps() {
+        let impg = build_impg(MIXED_PAF);
+        let results = vec![
+            result(&impg, "HG002#1#chr1", 0, 100, "HG002#1#chr1"),
+            result(&impg, "grch38#chr1", 0, 100, "HG002#1#chr1"),
+            result(&impg, "HG002#1#chr1", 40, 10, "grch38#chr1"),
+            result(&impg, "chm13#chr2", 0, 100, "HG002#1#chr1"),
+        ].into_iter().map(|result| (result, 255, Vec::new(), false)).collect();
+        let names: Vec<_> = remove_self_overlaps(results, impg.seq_index.get_id("HG002#1#chr1").unwrap()).iter()
+            .map(|((query, _, _), _, _, _)| impg.seq_index.get_name(query.metadata).unwrap().to_string())
+            .collect();
+        assert_eq!(names, vec!["grch38#chr1", "chm13#chr2"]);
+    }
+
+    #[test]
+    fn test_format_error_json() {
+        let error = parse_range(&["200", "100"], false).unwrap_err();
+        let json: serde_json::Value = serde_json::from_str(&format_error_json(&error)).unwrap();
+        assert_eq!(json["message"], "Start value must not be greater than end value");
+        assert_eq!(json["kind"], "InvalidInput");
+    }
+
+    #[test]
+    fn test_parse_range_overflow() {
+        assert_eq!(parse_range(&["0", "2147483647"], false).unwrap(), (0, i32::MAX));
+        let error = parse_range(&["0", "3000000000"], false).unwrap_err();
+        assert!(error.to_string().contains("exceeds the maximum supported coordinate 2147483647"));
+        assert!(parse_range(&["0", "2147483647"], true).unwrap_err().to_string().contains("exceeds"));
+        assert_eq!(parse_range(&["x", "10"], false).unwrap_err().to_string(), "Invalid start value");
+    }
+
+    #[test]
+    fn test_point_query() {
+        assert_eq!(parse_range(&["100", "100"], false).unwrap(), (100, 100));
+        assert!(parse_range(&["101", "100"], false).is_err());
+
+        let mut impg = build_impg(MIXED_PAF);
+        impg.has_cigars = false;
+        let results = perform_query(&impg, "grch38#chr1", (50, 50), 0, Expansion::default()).unwrap();
+        assert_eq!(results.iter().map(|((query, _, _), _, _, _)| (query.first, query.last)).collect::<Vec<_>>(), vec![(50, 51), (50, 51), (50, 49)]);
+        assert_eq!(perform_query(&impg, "grch38#chr1", (100, 100), 0, Expansion::default()).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_cigar_dependent_option() {
+        let option = |extra: &[&str]| cigar_dependent_option(&Args::parse_from([&["impg", "-p", "x.paf"], extra].concat()));
+        assert_eq!(option(&[]), None);
+        assert_eq!(option(&["--output-format", "bedpe", "-r", "a:0-10"]), None);
+        assert_eq!(option(&["--output-format", "gaf"]), Some("--output-format gaf"));
+        assert_eq!(option(&["-P"]), Some("--output-paf"));
+        assert_eq!(option(&["--min-identity", "0.9"]), Some("--min-identity"));
+        assert_eq!(option(&["--clamp", "--output-format", "bed"]), Some("--clamp"));
+
+        let output = |extra: &[&str]| cigar_output_option(&Args::parse_from([&["impg", "-p", "x.paf", "--merge-output"], extra].concat()));
+        assert_eq!(output(&["--output-format", "paf"]), Some("--output-format paf"));
+        assert_eq!(output(&["--output-format", "bedpe", "--bedpe-extended"]), Some("--bedpe-extended"));
+        assert_eq!(output(&["--min-identity", "0.9", "--output-format", "bed"]), None);
+    }
+
+    #[test]
+    fn test_merge_distance() {
+        let parse = |extra: &[&str]| Args::try_parse_from([&["impg", "-p", "x.paf", "-r", "a:10-20"], extra].concat());
+        assert_eq!(parse(&["--merge-output", "--merge-distance", "5"]).unwrap().merge_distance, 5);
+        assert!(parse(&["--merge-output", "--merge-distance=-3"]).is_err());
+        assert!(parse(&["--merge-distance", "5"]).is_err());
+    }
+
+    #[test]
+    fn test_padding_not_negative() {
+        let parse = |padding: &str| Args::try_parse_from(["impg", "-p", "x.paf", "-r", "a:10-20", &format!("--padding={}", padding)]);
+        assert_eq!(parse("8").unwrap().padding, 8);
+        assert!(parse("-1").is_err());
+    }
+
+    #[test]
+    fn test_sort_results() {
+        let impg = build_impg(MIXED_PAF);
+        let results: Vec<QueryResult> = vec![
+            result(&impg, "grch38#chr1", 0, 100, "grch38#chr1"),
+            result(&impg, "chm13#chr1", 60, 70, "grch38#chr1"),
+            result(&impg, "HG002#1#chr1", 50, 10, "grch38#chr1"),
+            result(&impg, "chm13#chr1", 5, 15, "grch38#chr1"),
+            result(&impg, "HG002#1#chr1", 5, 8, "grch38#chr1"),
+        ].into_iter().map(|r| (r, 255, Vec::new(), false)).collect();
+        let order = |sorted: Vec<QueryResult>| sorted.into_iter()
+            .map(|((query, _, _), _, _, _)| (impg.seq_index.get_name(query.metadata).unwrap(), query.first))
+            .collect::<Vec<_>>();
+
+        assert_eq!(order(sort_results(&impg, results.clone(), ResultOrder::Query)), vec![
+            ("HG002#1#chr1", 5), ("HG002#1#chr1", 50), ("chm13#chr1", 5), ("chm13#chr1", 60), ("grch38#chr1", 0),
+        ]);
+        // All target spans start at 0, and equal spans keep their order
+        assert_eq!(order(sort_results(&impg, results.clone(), ResultOrder::Target)), vec![
+            ("HG002#1#chr1", 5), ("chm13#chr1", 60), ("chm13#chr1", 5), ("HG002#1#chr1", 50), ("grch38#chr1", 0),
+        ]);
+        assert_eq!(order(sort_results(&impg, results.clone(), ResultOrder::None)), order(results));
+    }
+
+    #[test]
+    fn test_parse_target_range_full_sequence() {
+        let impg = build_impg(MIXED_PAF);
+        assert_eq!(parse_target_range("grch38#chr1", false, &impg.seq_index).unwrap(), ("grch38#chr1".to_string(), (0, 100)));
+        assert_eq!(parse_target_range("grch38#chr1:0-100", false, &impg.seq_index).unwrap(), ("grch38#chr1".to_string(), (0, 100)));
+        assert!(parse_target_range("missing", false, &impg.seq_index).is_err());
+    }
+
+    #[test]
+    fn test_coverage() {
+        // Three records over [0, 100): [10, 50), [30, 60) and [40, 45)
+        let spans = [(10, 50), (30, 60), (40, 45)];
+        let runs = coverage_runs(&spans, 0, 100);
+        assert_eq!(runs, vec![(0, 10, 0), (10, 30, 1), (30, 40, 2), (40, 45, 3), (45, 50, 2), (50, 60, 1), (60, 100, 0)]);
+        for pos in 0..100 {
+            let records = spans.iter().filter(|&&(first, last)| first <= pos && pos < last).count();
+            assert_eq!(runs.iter().find(|&&(start, end, _)| start <= pos && pos < end).unwrap().2, records);
+        }
+        assert_eq!(coverage_runs(&spans, 20, 42), vec![(20, 30, 1), (30, 40, 2), (40, 42, 3)]);
+
+        assert_eq!(bin_coverage(&runs, 0, 100, 40), vec![(0, 40, 1.0), (40, 80, 0.875), (80, 100, 0.0)]);
+    }
+
+    #[test]
+    fn test_exclude_regions() {
+        let impg = build_impg(MIXED_PAF);
+        let chm13 = impg.seq_index.get_id("chm13#chr1").unwrap();
+        let hg002 = impg.seq_index.get_id("HG002#1#chr1").unwrap();
+        let excluded = HashMap::from([(chm13, vec![(0, 50)]), (hg002, vec![(20, 30)])]);
+        let results: Vec<QueryResult> = vec![
+            result(&impg, "grch38#chr1", 0, 100, "grch38#chr1"),
+            result(&impg, "chm13#chr1", 10, 40, "grch38#chr1"),
+            result(&impg, "HG002#1#chr1", 40, 10, "grch38#chr1"),
+        ].into_iter().map(|r| (r, 60, Vec::new(), false)).collect();
+
+        let kept: Vec<_> = exclude_regions(results, &excluded).into_iter()
+            .map(|((query, _, target), mapq, _, _)| (impg.seq_index.get_name(query.metadata).unwrap(), query.first, query.last, target.first, target.last, mapq))
+            .collect();
+        // chm13 lies inside the excluded range, HG002 is split around it
+        assert_eq!(kept, vec![
+            ("grch38#chr1", 0, 100, 0, 100, 60),
+            ("HG002#1#chr1", 20, 10, 20, 30, 60),
+            ("HG002#1#chr1", 40, 30, 0, 10, 60),
+        ]);
+    }
+
+    #[test]
+    fn test_include_regions() {
+        let impg = build_impg(MIXED_PAF);
+        let chm13 = impg.seq_index.get_id("chm13#chr1").unwrap();
+        let included = HashMap::from([(chm13, vec![(0, 15), (25, 30), (35, 60)])]);
+        let results: Vec<QueryResult> = vec![
+            result(&impg, "grch38#chr1", 0, 100, "grch38#chr1"),
+            result(&impg, "chm13#chr1", 20, 40, "grch38#chr1"),
+        ].into_iter().map(|r| (r, 60, Vec::new(), false)).collect();
+
+        let kept: Vec<_> = include_regions(results, &included).into_iter()
+            .map(|((query, _, target), _, _, _)| (impg.seq_index.get_name(query.metadata).unwrap(), query.first, query.last, target.first, target.last))
+            .collect();
+        // grch38 is not in the whitelist, chm13 is cut down to the two ranges it overlaps
+        assert_eq!(kept, vec![("chm13#chr1", 25, 30, 5, 10), ("chm13#chr1", 35, 40, 15, 20)]);
+    }
+
+    #[test]
+    fn test_pad_range() {
+        assert_eq!(pad_range((1000, 2000), 500, 10000), (500, 2500));
+        assert_eq!(pad_range((100, 9900), 500, 10000), (0, 10000));
+        assert_eq!(pad_range((100, 200), 0, 10000), (100, 200));
+    }
+
+    #[test]
+    fn test_output_results_bed_name() {
+        let impg = build_impg(MIXED_PAF);
+        let results = vec![result(&impg, "HG002#1#chr1", 100, 0, "grch38#chr1")];
+
+        let mut out = Vec::new();
+        output_results_bed(&mut out, &impg, results.clone(), Some("region1".to_string())).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "HG002#1#chr1\t0\t100\tregion1\t-\n");
+
+        let mut out = Vec::new();
+        output_results_bed(&mut out, &impg, results, None).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "HG002#1#chr1\t0\t100\t.\t-\n");
+    }
+
+    #[test]
+    fn test_format_alignment() {
+        let cigar = vec![CigarOp::new(3, '='), CigarOp::new(1, 'X'), CigarOp::new(2, 'I'), CigarOp::new(1, 'D'), CigarOp::new(2, '=')];
+        let text = format_alignment(b"ACGTAGG", b"ACGAccGG", &cigar, 80);
+        assert_eq!(text, "T ACGT--AGG\n  |||.   ||\nQ ACGAcc-GG\n");
+
+        let wrapped = format_alignment(b"ACGTAGG", b"ACGAccGG", &cigar, 4);
+        assert_eq!(wrapped, "T ACGT\n  |||.\nQ ACGA\nT --AG\n     |\nQ cc-G\nT G\n  |\nQ G\n");
+    }
+
+    #[test]
+    fn test_filter_by_prefix() {
+        let impg = build_impg(MIXED_PAF);
+        let results = vec![
+            result(&impg, "grch38#chr1", 0, 100, "grch38#chr1"),
+            result(&impg, "chm13#chr1", 0, 100, "grch38#chr1"),
+            result(&impg, "HG002#1#chr1", 100, 0, "grch38#chr1"),
+            result(&impg, "chm13#chr2", 0, 100, "HG002#1#chr1"),
+        ].into_iter().map(|result| (result, 255, Vec::new(), false)).collect::<Vec<_>>();
+        let names = |results: Vec<QueryResult>| results.iter().map(|((query, _, _), _, _, _)| impg.seq_index.get_name(query.metadata).unwrap().to_string()).collect::<Vec<_>>();
+
+        assert_eq!(names(filter_by_prefix(&impg, results.clone(), &["chm13#".to_string()], None)), vec!["chm13#chr1", "chm13#chr2"]);
+        assert_eq!(names(filter_by_prefix(&impg, results, &["HG002#".to_string(), "grch38#".to_string()], None)), vec!["grch38#chr1", "HG002#1#chr1"]);
+    }
+
+    #[test]
+    fn test_filter_by_pansn_prefix() {
+        let impg = build_impg("HG002#1#chr1\t100\t0\t100\t+\tgrch38#chr1\t100\t0\t100\t100\t100\t60\n\
+                               HG002#1#chr2\t100\t0\t100\t+\tgrch38#chr1\t100\t0\t100\t100\t100\t60\n\
+                               HG002#10#chr1\t100\t0\t100\t+\tgrch38#chr1\t100\t0\t100\t100\t100\t60\n\
+                               HG0021#1#chr1\t100\t0\t100\t+\tgrch38#chr1\t100\t0\t100\t100\t100\t60\n");
+        let results: Vec<QueryResult> = ["HG002#1#chr1", "HG002#1#chr2", "HG002#10#chr1", "HG0021#1#chr1"].iter()
+            .map(|name| (result(&impg, name, 0, 100, "grch38#chr1"), 255, Vec::new(), false))
+            .collect();
+        let names = |prefix: &str, delimiter: Option<char>| filter_by_prefix(&impg, results.clone(), &[prefix.to_string()], delimiter).iter()
+            .map(|((query, _, _), _, _, _)| impg.seq_index.get_name(query.metadata).unwrap().to_string())
+            .collect::<Vec<_>>();
+
+        // A string prefix also picks up haplotype 10 and another sample
+        assert_eq!(names("HG002#1", None), vec!["HG002#1#chr1", "HG002#1#chr2", "HG002#10#chr1"]);
+        assert_eq!(names("HG002#1", Some('#')), vec!["HG002#1#chr1", "HG002#1#chr2"]);
+        assert_eq!(names("HG002#1#", Some('#')), vec!["HG002#1#chr1", "HG002#1#chr2"]);
+        assert_eq!(names("HG002", Some('#')), vec!["HG002#1#chr1", "HG002#1#chr2", "HG002#10#chr1"]);
+        assert!(matches_prefix("HG002.1.chr1", "HG002.1", Some('.')));
+    }
 }
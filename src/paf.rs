@@ -1,6 +1,11 @@
 use std::io::{BufRead, Error as IoError};
+use std::fmt;
 use std::num::ParseIntError;
+use std::str::FromStr;
 use serde::{Serialize, Deserialize};
+use rayon::prelude::*;
+use log::info;
+use crate::impg::validate_cigar_str;
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct PafRecord {
@@ -13,6 +18,10 @@ pub struct PafRecord {
     pub target_start: usize,
     pub target_end: usize,
     pub strand: Strand,
+    /// Mapping quality, 255 when missing
+    pub mapq: u8,
+    /// Byte offset of the record's line in the PAF file
+    pub record_offset: u64,
     pub cigar_offset: u64,
     pub cigar_bytes: usize,
 }
@@ -32,29 +41,28 @@ impl PafRecord {
         }
 
         let query_name = fields[0].to_string();
-        let query_length = fields[1].parse::<usize>().map_err(ParseErr::InvalidField)?;
-        let query_start = fields[2].parse::<usize>().map_err(ParseErr::InvalidField)?;
-        let query_end = fields[3].parse::<usize>().map_err(ParseErr::InvalidField)?;
+        let query_length = parse_field(&fields, 1, "query_length")?;
+        let query_start = parse_field(&fields, 2, "query_start")?;
+        let query_end = parse_field(&fields, 3, "query_end")?;
         let target_name = fields[5].to_string();
-        let target_length = fields[6].parse::<usize>().map_err(ParseErr::InvalidField)?;
-        let target_start = fields[7].parse::<usize>().map_err(ParseErr::InvalidField)?;
-        let target_end = fields[8].parse::<usize>().map_err(ParseErr::InvalidField)?;
-        let strand_char = fields[4].chars().next().ok_or_else(|| {
-            ParseErr::InvalidFormat("Expected '+' or '-' for strand".to_string())
-        })?;
-        let strand = match strand_char {
-            '+' => Strand::Forward,
-            '-' => Strand::Reverse,
-            _ => return Err(ParseErr::InvalidStrand),
+        let target_length = parse_field(&fields, 6, "target_length")?;
+        let target_start = parse_field(&fields, 7, "target_start")?;
+        let target_end = parse_field(&fields, 8, "target_end")?;
+        let strand = match fields[4] {
+            "+" => Strand::Forward,
+            "-" => Strand::Reverse,
+            value => return Err(ParseErr::InvalidFieldValue { field: "strand", expected: "'+' or '-'", value: value.to_string() }),
         };
+        let mapq = parse_field(&fields, 11, "mapq")?;
 
         let mut cigar_offset: u64 = file_pos;
         let mut cigar_bytes: usize = 0;
 
         for tag_str in fields.iter() {
-            if tag_str.starts_with("cg:Z:") {
+            if let Some(cigar) = tag_str.strip_prefix("cg:Z:") {
+                validate_cigar_str(cigar)?;
                 cigar_offset += 5;
-                cigar_bytes = tag_str.len() - 5;
+                cigar_bytes = cigar.len();
                 break;
             } else {
                 cigar_offset += (tag_str.len() + 1) as u64;
@@ -71,12 +79,27 @@ impl PafRecord {
             target_start,
             target_end,
             strand,
+            mapq,
+            record_offset: file_pos,
             cigar_offset,
             cigar_bytes,
         })
     }
 }
 
+/// Whether `line` is a record without an alignment, with `*` as the query name, target name or CIGAR.
+fn is_unmapped(line: &str) -> bool {
+    let mut fields = line.split('\t');
+    let query_name = fields.next();
+    let target_name = fields.nth(4);
+    query_name == Some("*") || target_name == Some("*") || fields.any(|tag| tag == "cg:Z:*")
+}
+
+/// Parse the integer in column `index` of a PAF line, naming the column in the error.
+fn parse_field<T: FromStr>(fields: &[&str], index: usize, field: &'static str) -> Result<T, ParseErr> {
+    fields[index].parse::<T>().map_err(|_| ParseErr::InvalidFieldValue { field, expected: "integer", value: fields[index].to_string() })
+}
+
 #[derive(Debug)]
 pub enum ParseErr {
     NotEnoughFields,
@@ -86,19 +109,112 @@ pub enum ParseErr {
     InvalidCigarFormat,
     UnsupportedCigarOperation,
     InvalidFormat(String),
+    /// A PAF column whose text is not a valid value
+    InvalidFieldValue { field: &'static str, expected: &'static str, value: String },
+    /// A record with `*` for its query name, target name or CIGAR
+    Unmapped,
+    /// A record without a `cg:Z:` CIGAR, or with an empty one
+    MissingCigar,
+    /// An error on a 1-based line of the PAF input
+    AtLine { line: usize, error: Box<ParseErr> },
+}
+
+impl fmt::Display for ParseErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseErr::NotEnoughFields => write!(f, "expected at least 12 tab-separated fields"),
+            ParseErr::IoError(e) => write!(f, "{}", e),
+            ParseErr::InvalidField(e) => write!(f, "invalid field: {}", e),
+            ParseErr::InvalidStrand => write!(f, "field 'strand': expected '+' or '-'"),
+            ParseErr::InvalidCigarFormat => write!(f, "invalid CIGAR format"),
+            ParseErr::UnsupportedCigarOperation => write!(f, "unsupported CIGAR operation"),
+            ParseErr::InvalidFormat(message) => write!(f, "{}", message),
+            ParseErr::InvalidFieldValue { field, expected, value } => write!(f, "field '{}': expected {}, found '{}'", field, expected, value),
+            ParseErr::Unmapped => write!(f, "unmapped record with '*' as query name, target name or CIGAR"),
+            ParseErr::MissingCigar => write!(f, "record without a cg:Z: CIGAR"),
+            ParseErr::AtLine { line, error } => write!(f, "PAF parse error at line {}, {}", line, error),
+        }
+    }
 }
 
+impl std::error::Error for ParseErr {}
+
+/// Parses all records, skipping unmapped ones but keeping those without a CIGAR.
 pub fn parse_paf<R: BufRead>(reader: R) -> Result<Vec<PafRecord>, ParseErr> {
-    let mut bytes_read: u64 = 0;
+    parse_paf_with_progress(reader, 0, false, false, usize::MAX, |_, _| {})
+}
+
+/// Number of lines read before a batch is handed to the thread pool for parsing.
+const PARSE_CHUNK_LINES: usize = 64 * 1024;
+
+/// Like `parse_paf` for a reader starting `start_offset` bytes into the file, calling
+/// `progress(records, bytes)` with the number of records parsed and the file offset reached
+/// every `interval` records, and once more at the end. Unmapped records, and with `require_cigar`
+/// records without a CIGAR, are skipped, or an error with `strict`.
+///
+/// Lines are read serially and parsed in parallel in chunks, keeping the records in file order.
+pub fn parse_paf_with_progress<R: BufRead, F: FnMut(usize, u64)>(reader: R, start_offset: u64, strict: bool, require_cigar: bool, interval: usize, mut progress: F) -> Result<Vec<PafRecord>, ParseErr> {
+    let mut bytes_read: u64 = start_offset;
     let mut records = Vec::new();
-    for line_result in reader.lines() {
-        let line = line_result.map_err(ParseErr::IoError)?;
-        let record = PafRecord::parse(&line, bytes_read)?;
-        records.push(record);
+    let mut chunk: Vec<(u64, String)> = Vec::with_capacity(PARSE_CHUNK_LINES);
+    let mut lines = reader.lines();
+    // Number of lines before the current chunk
+    let mut first_line = 0;
+    let mut unmapped = 0;
+    let mut missing_cigar = 0;
+    loop {
+        let line = lines.next().transpose().map_err(|error| ParseErr::AtLine { line: first_line + chunk.len() + 1, error: Box::new(ParseErr::IoError(error)) })?;
+        let at_end = line.is_none();
+        if let Some(line) = line {
+            // Size of line plus newline
+            let line_len = (line.len() + 1) as u64;
+            chunk.push((bytes_read, line));
+            bytes_read += line_len;
+        }
+        if chunk.len() < PARSE_CHUNK_LINES && !at_end {
+            continue;
+        }
 
-        // Size of line plus newline
-        bytes_read += (line.len() + 1) as u64;
+        let parsed: Vec<_> = chunk.par_iter()
+            .map(|(file_pos, line)| match is_unmapped(line) {
+                true => Err(ParseErr::Unmapped),
+                false => PafRecord::parse(line, *file_pos).and_then(|record| match record.cigar_bytes {
+                    0 if require_cigar => Err(ParseErr::MissingCigar),
+                    _ => Ok(record),
+                }),
+            })
+            .collect();
+        for (index, ((file_pos, line), record)) in chunk.iter().zip(parsed).enumerate() {
+            let record = match record {
+                Err(ParseErr::Unmapped) if !strict => {
+                    unmapped += 1;
+                    continue;
+                },
+                Err(ParseErr::MissingCigar) if !strict => {
+                    missing_cigar += 1;
+                    continue;
+                },
+                record => record,
+            };
+            let parsed_records = records.len() + 1;
+            records.push(record.map_err(|error| ParseErr::AtLine { line: first_line + index + 1, error: Box::new(error) })?);
+            if parsed_records % interval == 0 {
+                progress(parsed_records, file_pos + (line.len() + 1) as u64);
+            }
+        }
+        first_line += chunk.len();
+        chunk.clear();
+        if at_end {
+            break;
+        }
+    }
+    if unmapped > 0 {
+        info!("Skipped {} unmapped PAF records", unmapped);
+    }
+    if missing_cigar > 0 {
+        info!("Skipped {} PAF records without a cg:Z: CIGAR", missing_cigar);
     }
+    progress(records.len(), bytes_read);
     Ok(records)
 }
 
@@ -106,6 +222,58 @@ pub fn parse_paf<R: BufRead>(reader: R) -> Result<Vec<PafRecord>, ParseErr> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_parse_paf_with_progress() {
+        let line = "seq1\t100\t0\t100\t+\tseq2\t100\t0\t100\t60\t100\t255\n";
+        let mut calls = Vec::new();
+        let records = parse_paf_with_progress(line.repeat(5).as_bytes(), 0, false, false, 2, |records, bytes| calls.push((records, bytes))).unwrap();
+        assert_eq!(records.len(), 5);
+        let len = line.len() as u64;
+        assert_eq!(calls, vec![(2, 2 * len), (4, 4 * len), (5, 5 * len)]);
+    }
+
+    #[test]
+    fn test_parse_paf_unmapped() {
+        let paf_data = "q1\t100\t0\t10\t+\tt\t100\t0\t10\t10\t10\t60\tcg:Z:10=\n\
+                        q2\t100\t0\t0\t*\t*\t0\t0\t0\t0\t0\t0\n\
+                        q3\t100\t0\t10\t+\tt\t100\t0\t10\t10\t10\t60\tcg:Z:10=\n";
+        let records = parse_paf(paf_data.as_bytes()).unwrap();
+        assert_eq!(records.iter().map(|record| record.query_name.as_str()).collect::<Vec<_>>(), vec!["q1", "q3"]);
+        assert_eq!(&paf_data[records[1].cigar_offset as usize..][..records[1].cigar_bytes], "10=");
+
+        let error = parse_paf_with_progress(paf_data.as_bytes(), 0, true, true, usize::MAX, |_, _| {}).unwrap_err();
+        assert!(matches!(error, ParseErr::AtLine { line: 2, error } if matches!(*error, ParseErr::Unmapped)));
+        assert!(is_unmapped("q\t10\t0\t10\t+\tt\t10\t0\t10\t10\t10\t0\tcg:Z:*"));
+
+        // Records without a CIGAR are skipped too, unless CIGARs aren't needed
+        let paf_data = "q1\t100\t0\t10\t+\tt\t100\t0\t10\t10\t10\t60\tcg:Z:10=\n\
+                        q2\t100\t0\t10\t+\tt\t100\t0\t10\t10\t10\t60\ttp:A:P\n\
+                        q3\t100\t0\t10\t+\tt\t100\t0\t10\t10\t10\t60\tcg:Z:\n";
+        let records = parse_paf_with_progress(paf_data.as_bytes(), 0, false, true, usize::MAX, |_, _| {}).unwrap();
+        assert_eq!(records.iter().map(|record| record.query_name.as_str()).collect::<Vec<_>>(), vec!["q1"]);
+        assert_eq!(parse_paf(paf_data.as_bytes()).unwrap().len(), 3);
+        let error = parse_paf_with_progress(paf_data.as_bytes(), 0, true, true, usize::MAX, |_, _| {}).unwrap_err();
+        assert_eq!(error.to_string(), "PAF parse error at line 2, record without a cg:Z: CIGAR");
+    }
+
+    #[test]
+    fn test_parse_paf_across_chunks() {
+        let lines: String = (0..PARSE_CHUNK_LINES + 3)
+            .map(|i| format!("q{}\t100\t0\t100\t+\tt\t100\t0\t100\t100\t100\t60\tcg:Z:100=\n", i))
+            .collect();
+        let records = parse_paf(lines.as_bytes()).unwrap();
+        assert_eq!(records.len(), PARSE_CHUNK_LINES + 3);
+
+        // Records stay in file order with absolute CIGAR offsets
+        let mut offset = 0;
+        for (i, (record, line)) in records.iter().zip(lines.lines()).enumerate() {
+            assert_eq!(record.query_name, format!("q{}", i));
+            assert_eq!(&lines[record.cigar_offset as usize..record.cigar_offset as usize + record.cigar_bytes], "100=");
+            assert_eq!(record.cigar_offset, offset + line.len() as u64 - 4);
+            offset += line.len() as u64 + 1;
+        }
+    }
+
     #[test]
     fn test_parse_paf_valid() {
         let line = "seq1\t100\t0\t100\t+\tseq2\t100\t0\t100\t60\t100\t255";
@@ -122,6 +290,8 @@ mod tests {
                 target_start: 0,
                 target_end: 100,
                 strand: Strand::Forward,
+                mapq: 255,
+                record_offset: 0,
                 // If no cigar, then the offset is just the length of the line and cigar_bytes=0
                 // Should we use Option<> instead?
                 cigar_offset: (line.len() + 1) as u64,
@@ -143,10 +313,34 @@ mod tests {
         assert!(PafRecord::parse(line, 0).is_err());
     }
 
+    #[test]
+    fn test_parse_paf_error_context() {
+        let valid = "seq1\t100\t0\t100\t+\tseq2\t100\t0\t100\t60\t100\t255\n";
+        let error = |bad_line: &str| parse_paf(format!("{}{}{}\n{}", valid, valid, bad_line, valid).as_bytes()).unwrap_err().to_string();
+
+        assert_eq!(error("seq1\t100\t0\t100\t+\tseq2\t100\tfoo\t100\t60\t100\t255"),
+                   "PAF parse error at line 3, field 'target_start': expected integer, found 'foo'");
+        assert_eq!(error("seq1\t100\t0\t100\t*\tseq2\t100\t0\t100\t60\t100\t255"),
+                   "PAF parse error at line 3, field 'strand': expected '+' or '-', found '*'");
+        assert_eq!(error("seq1\t100\t0\t100\t+\tseq2\t100\t0\t100\t60\t100\t300"),
+                   "PAF parse error at line 3, field 'mapq': expected integer, found '300'");
+        assert_eq!(error("seq1\t100\t0\t100"), "PAF parse error at line 3, expected at least 12 tab-separated fields");
+        assert!(matches!(
+            parse_paf("seq1\t-5\t0\t100\t+\tseq2\t100\t0\t100\t60\t100\t255\n".as_bytes()),
+            Err(ParseErr::AtLine { line: 1, error }) if matches!(*error, ParseErr::InvalidFieldValue { field: "query_length", .. })
+        ));
+    }
+
     #[test]
     fn test_parse_paf_cigar_invalid() {
         // it's got Q in the CIGAR string
-        let line = "seq1\t100\t0\t100\t+\tseq2\t100\tz\t100\t60\t100\t255\tcg:Z:10Q";
-        assert!(PafRecord::parse(line, 0).is_err());
+        let line = "seq1\t100\t0\t100\t+\tseq2\t100\t0\t100\t60\t100\t255\tcg:Z:10Q";
+        assert!(matches!(PafRecord::parse(line, 0), Err(ParseErr::UnsupportedCigarOperation)));
+        let line = "seq1\t100\t0\t100\t+\tseq2\t100\t0\t100\t60\t100\t255\tcg:Z:10=5";
+        assert!(matches!(PafRecord::parse(line, 0), Err(ParseErr::InvalidCigarFormat)));
+
+        let valid = "seq1\t100\t0\t10\t+\tseq2\t100\t0\t10\t10\t10\t60\tcg:Z:10=\n";
+        let error = parse_paf(format!("{}{}cg:Z:10Q\n", valid, valid.trim_end_matches("cg:Z:10=\n")).as_bytes()).unwrap_err();
+        assert_eq!(error.to_string(), "PAF parse error at line 2, unsupported CIGAR operation");
     }
 }
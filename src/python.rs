@@ -0,0 +1,76 @@
+//! Python bindings for the query API, compiled with the `python` feature.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use coitrees::IntervalTree;
+use pyo3::exceptions::{PyIOError, PyKeyError, PyValueError};
+use pyo3::prelude::*;
+use crate::impg::{AdjustedInterval, Impg, SerializableImpg, cigar_to_str};
+
+/// `(query_name, query_start, query_end, cigar, target_name, target_start, target_end)`, with
+/// `query_start > query_end` for reverse strand alignments like in `AdjustedInterval`.
+type PyInterval = (String, i32, i32, String, String, i32, i32);
+
+#[pyclass(name = "Impg")]
+pub struct PyImpg {
+    impg: Impg,
+}
+
+#[pymethods]
+impl PyImpg {
+    /// Load the index at `path`, reading CIGARs from `paf_files` if given and otherwise from the
+    /// PAF files it was built from (relative paths resolve against the working directory).
+    #[staticmethod]
+    #[pyo3(signature = (path, paf_files=None))]
+    fn load(path: &str, paf_files: Option<Vec<String>>) -> PyResult<Self> {
+        let file = File::open(path).map_err(|e| PyIOError::new_err(format!("Failed to open index {}: {}", path, e)))?;
+        let serializable = SerializableImpg::deserialize_from(BufReader::new(file)).map_err(|e| PyIOError::new_err(e.to_string()))?;
+        let paf_files = paf_files.unwrap_or_else(|| serializable.paf_files.clone());
+        if paf_files.len() != serializable.paf_files.len() {
+            return Err(PyValueError::new_err(format!("Index {} was built from {} PAF files, got {}", path, serializable.paf_files.len(), paf_files.len())));
+        }
+        Ok(Self { impg: Impg::from_paf_and_serializable(&paf_files, serializable) })
+    }
+
+    /// Project `[start, end)` of `seq` through the alignments, the first result being the range itself.
+    #[pyo3(signature = (seq, start, end, transitive=false))]
+    fn query(&self, seq: &str, start: i32, end: i32, transitive: bool) -> PyResult<Vec<PyInterval>> {
+        let target_id = self.impg.seq_index.get_id(seq).ok_or_else(|| PyKeyError::new_err(format!("Target sequence '{}' not found in index", seq)))?;
+        let target_length = self.impg.seq_index.get_len_from_id(target_id).unwrap_or(0);
+        if start < 0 || start >= end || end as usize > target_length {
+            return Err(PyValueError::new_err(format!("Invalid range {}:{}-{} for a sequence of length {}", seq, start, end, target_length)));
+        }
+
+        let results = if transitive {
+            self.impg.query_transitive(target_id, start, end, None, None)
+        } else {
+            self.impg.query(target_id, start, end)
+        }.map_err(|e| PyIOError::new_err(e.to_string()))?;
+        Ok(results.iter().map(|result| self.to_tuple(result)).collect())
+    }
+
+    /// Number of sequences, number of alignment records and total sequence length.
+    fn stats(&self) -> HashMap<&'static str, usize> {
+        HashMap::from([
+            ("sequences", self.impg.seq_index.len()),
+            ("overlaps", self.impg.trees.values().map(|tree| tree.len()).sum()),
+            ("total_length", self.impg.seq_index.lengths().sum()),
+        ])
+    }
+}
+
+impl PyImpg {
+    fn to_tuple(&self, (query, cigar, target): &AdjustedInterval) -> PyInterval {
+        let name = |id| self.impg.seq_index.get_name(id).unwrap_or_default().to_string();
+        let cigar = cigar_to_str(cigar);
+        (name(query.metadata), query.first, query.last, cigar, name(target.metadata), target.first, target.last)
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "impg")]
+fn impg_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyImpg>()?;
+    Ok(())
+}
@@ -1,14 +1,27 @@
-use std::collections::HashMap;
-use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, HashMap};
+use serde::{Serialize, Serializer, Deserialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct SequenceIndex {
+    #[serde(serialize_with = "serialize_sorted")]
     name_to_id: HashMap<String, u32>,
+    #[serde(serialize_with = "serialize_sorted")]
     id_to_name: HashMap<u32, String>,
+    #[serde(serialize_with = "serialize_sorted")]
     id_to_len: HashMap<u32, usize>,
     next_id: u32,
 }
 
+/// Serialize a map in key order so identical indexes produce identical bytes.
+fn serialize_sorted<S, K, V>(map: &HashMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    K: Serialize + Ord,
+    V: Serialize,
+{
+    map.iter().collect::<BTreeMap<_, _>>().serialize(serializer)
+}
+
 impl SequenceIndex {
     pub fn new() -> Self {
         SequenceIndex {
@@ -46,6 +59,18 @@ impl SequenceIndex {
         self.id_to_len.get(&id).copied()
     }
 
+    /// Ids of all sequences, in ascending order.
+    pub fn ids(&self) -> impl Iterator<Item = u32> {
+        let mut ids: Vec<u32> = self.id_to_name.keys().copied().collect();
+        ids.sort_unstable();
+        ids.into_iter()
+    }
+
+    /// Lengths of the sequences with a known length, in no particular order.
+    pub fn lengths(&self) -> impl Iterator<Item = usize> + '_ {
+        self.id_to_len.values().copied()
+    }
+
     pub fn is_empty(&self) -> bool {
         self.name_to_id.is_empty()
     }
@@ -0,0 +1,69 @@
+//! Fixtures shared by the library and binary tests, removed from disk when dropped.
+
+use std::io::BufReader;
+use std::ops::{Deref, DerefMut};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use super::Impg;
+use super::paf::parse_paf;
+
+/// A directory of test files in the temporary directory, unique to the test that created it.
+pub struct TestDir {
+    path: PathBuf,
+}
+
+impl TestDir {
+    pub fn new(name: &str) -> Self {
+        static DIRS: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!("impg_test_{}_{}_{}", name, std::process::id(), DIRS.fetch_add(1, Ordering::Relaxed)));
+        std::fs::create_dir_all(&path).unwrap();
+        Self { path }
+    }
+
+    /// The path of `file_name` in the directory, which need not exist.
+    pub fn path(&self, file_name: &str) -> String {
+        self.path.join(file_name).to_str().unwrap().to_string()
+    }
+
+    /// Write `contents` to `file_name` in the directory and return its path.
+    pub fn write(&self, file_name: &str, contents: impl AsRef<[u8]>) -> String {
+        let path = self.path(file_name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// An index over `paf_data` written to `{name}.paf` in its own `TestDir`, so that CIGARs can be
+/// read back. Derefs to the `Impg`, whose `paf_files` holds the path.
+pub struct TestImpg {
+    impg: Impg,
+    pub dir: TestDir,
+}
+
+impl Deref for TestImpg {
+    type Target = Impg;
+
+    fn deref(&self) -> &Impg {
+        &self.impg
+    }
+}
+
+impl DerefMut for TestImpg {
+    fn deref_mut(&mut self) -> &mut Impg {
+        &mut self.impg
+    }
+}
+
+pub fn build_test_impg(name: &str, paf_data: &str) -> TestImpg {
+    let dir = TestDir::new(name);
+    let paf_file = dir.write(&format!("{}.paf", name), paf_data);
+    let records = parse_paf(BufReader::new(paf_data.as_bytes())).unwrap();
+    let impg = Impg::from_paf_records(&records, &paf_file, false, None).unwrap();
+    TestImpg { impg, dir }
+}
@@ -0,0 +1,43 @@
+"""Tests for the Python bindings, run with `maturin develop && pytest tests/python`."""
+
+import pathlib
+import subprocess
+
+import pytest
+
+import impg
+
+REPO = pathlib.Path(__file__).resolve().parents[2]
+
+PAF = (
+    "b\t100\t0\t20\t+\ta\t100\t10\t30\t20\t20\t60\tcg:Z:20=\n"
+    "c\t50\t5\t25\t-\ta\t100\t20\t40\t20\t20\t60\tcg:Z:10=1X9=\n"
+)
+
+
+@pytest.fixture
+def index(tmp_path):
+    paf_file = tmp_path / "test.paf"
+    paf_file.write_text(PAF)
+    subprocess.run(["cargo", "run", "--quiet", "--", "-p", str(paf_file), "-I"], cwd=REPO, check=True)
+    return impg.Impg.load(str(paf_file) + ".impg")
+
+
+def test_query(index):
+    assert index.query("a", 0, 100) == [
+        ("a", 0, 100, "100=", "a", 0, 100),
+        ("b", 0, 20, "20=", "a", 10, 30),
+        ("c", 25, 5, "10=1X9=", "a", 20, 40),
+    ]
+    assert index.query("a", 0, 100, transitive=True)[0] == ("a", 0, 100, "100=", "a", 0, 100)
+
+
+def test_query_errors(index):
+    with pytest.raises(KeyError):
+        index.query("missing", 0, 10)
+    with pytest.raises(ValueError):
+        index.query("a", 50, 200)
+
+
+def test_stats(index):
+    assert index.stats() == {"sequences": 3, "overlaps": 2, "total_length": 250}